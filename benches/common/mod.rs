@@ -0,0 +1,32 @@
+//! ベンチマークで使う計測。外部のクレートは使わず、中央値を表示するだけにしている。
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+/// 一回の計測にかける時間の下限
+const SAMPLE_TIME: Duration = Duration::from_millis(20);
+/// 計測の回数
+const SAMPLES: usize = 11;
+
+/// `f` を繰り返し呼び、一回あたりの時間の中央値を表示して返す
+pub fn bench<R>(name: &str, mut f: impl FnMut() -> R) -> Duration {
+    let mut iterations = 1;
+    while time(iterations, &mut f) < SAMPLE_TIME {
+        iterations *= 2;
+    }
+    let mut samples: Vec<Duration> = (0..SAMPLES)
+        .map(|_| time(iterations, &mut f) / iterations)
+        .collect();
+    samples.sort();
+    let median = samples[SAMPLES / 2];
+    println!("{:<48} {:>12.2?}", name, median);
+    median
+}
+
+fn time<R>(iterations: u32, f: &mut impl FnMut() -> R) -> Duration {
+    let start = Instant::now();
+    for _ in 0..iterations {
+        black_box(f());
+    }
+    start.elapsed()
+}
@@ -0,0 +1,63 @@
+//! 字句解析の速さを測る。`cargo bench --bench lexer` で実行する。
+//!
+//! 生成した大きなスクリプトを、ソースを借用する `Lexer<'src>` と、
+//! 入力とリテラルを所有する `OwnedLexer` で最後まで読む。
+//! `OwnedLexer` は入力の `String` を受け取るので、その複製も時間に含まれる。
+
+mod common;
+
+use common::bench;
+use learn_interpreter::lexer;
+use learn_interpreter::symbol::Interner;
+use learn_interpreter::token::{Special, TokenType};
+
+const EOF: TokenType = TokenType::Special(Special::EOF);
+
+/// 関数の定義と呼び出し、コメントを繰り返したおよそ 1.6 MB のスクリプト
+fn script() -> String {
+    let mut input = String::new();
+    for i in 0..8_000 {
+        input += &format!(
+            "// helper number {i}\n\
+             let accumulate_{i} = fn(total, value) {{\n    \
+                 if (value > {i}) {{ total + value * 2 }} else {{ total - value / 3 }}\n\
+             }};\n\
+             let result_{i} = accumulate_{i}(result_{prev}, {i}) == !false;\n",
+            prev = i.max(1) - 1,
+        );
+    }
+    input
+}
+
+fn main() {
+    let input = script();
+    println!("{} bytes", input.len());
+    let borrowed = bench("Lexer<'src>", || {
+        let mut l = lexer::borrowed(&input);
+        let mut n = 0;
+        while l.next_token().token_type != EOF {
+            n += 1;
+        }
+        n
+    });
+    bench("Lexer<'src> with_interner", || {
+        let mut l = lexer::borrowed(&input).with_interner(Interner::default());
+        let mut n = 0;
+        while l.next_token().token_type != EOF {
+            n += 1;
+        }
+        n
+    });
+    let owned = bench("OwnedLexer", || {
+        let mut l = lexer::new(input.clone());
+        let mut n = 0;
+        while l.next_token().token_type != EOF {
+            n += 1;
+        }
+        n
+    });
+    println!(
+        "OwnedLexer / Lexer<'src> = {:.2}",
+        owned.as_secs_f64() / borrowed.as_secs_f64()
+    );
+}
@@ -0,0 +1,73 @@
+//! 変数を引く速さを測る。`cargo bench --bench lookup` で実行する。
+//!
+//! `Symbol` は作るときに求めたハッシュ値で引くので、名前が長くても引く速さは変わらない。
+//! 比べるために、同じ名前を文字列のまま鍵にした表も引く。
+
+mod common;
+
+use std::collections::HashMap;
+use std::hint::black_box;
+
+use common::bench;
+use learn_interpreter::object::{Environment, Value};
+use learn_interpreter::symbol::Interner;
+use learn_interpreter::{evaluator, parser};
+
+/// 表に入れる名前の数
+const NAMES: usize = 64;
+
+fn main() {
+    for length in [4, 32, 256] {
+        let names: Vec<String> = (0..NAMES)
+            .map(|i| format!("{:_>width$}", i, width = length))
+            .collect();
+        let mut interner = Interner::default();
+        let symbols: Vec<_> = names.iter().map(|name| interner.intern(name)).collect();
+
+        let by_string: HashMap<String, i64> = names.iter().cloned().zip(0..).collect();
+        bench(&format!("HashMap<String> get, {} bytes", length), || {
+            symbols
+                .iter()
+                .map(|symbol| by_string[black_box(symbol.as_str())])
+                .sum::<i64>()
+        });
+        let by_symbol: HashMap<_, i64> = symbols.iter().cloned().zip(0..).collect();
+        bench(&format!("HashMap<Symbol> get, {} bytes", length), || {
+            symbols
+                .iter()
+                .map(|symbol| by_symbol[black_box(symbol)])
+                .sum::<i64>()
+        });
+
+        // 外側のスコープにある名前ほど多くの表を引く
+        let mut env = Environment::new();
+        for chunk in symbols.chunks(NAMES / 4) {
+            for (i, symbol) in chunk.iter().enumerate() {
+                env.borrow_mut()
+                    .set(symbol.clone(), Value::Integer(i as i64));
+            }
+            env = Environment::enclosed(env);
+        }
+        bench(&format!("Environment::get, {} bytes", length), || {
+            let env = env.borrow();
+            symbols
+                .iter()
+                .filter(|symbol| env.get(black_box(symbol)).is_some())
+                .count()
+        });
+    }
+
+    let input = "
+        let fibonacci_of_the_given_number = fn(number_to_compute) {
+            if (number_to_compute < 2) { return number_to_compute; }
+            fibonacci_of_the_given_number(number_to_compute - 1)
+                + fibonacci_of_the_given_number(number_to_compute - 2)
+        };
+        fibonacci_of_the_given_number(15)";
+    let (program, errors) = parser::parse(input);
+    assert!(errors.is_empty());
+    let mut e = evaluator::new(std::io::sink());
+    bench("evaluate fibonacci(15) with long names", || {
+        e.eval_program(&program).unwrap()
+    });
+}
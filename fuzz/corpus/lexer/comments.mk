@@ -0,0 +1,3 @@
+// head
+let a = 1 / 2; // tail  
+//
\ No newline at end of file
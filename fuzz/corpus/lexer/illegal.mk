@@ -0,0 +1,2 @@
+let café = 5abc $ 日本
+	=
\ No newline at end of file
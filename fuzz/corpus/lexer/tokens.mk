@@ -0,0 +1,6 @@
+let five = 5;
+let add = fn(x, y) { x + y; };
+!-/*5;
+5 < 10 > 5;
+if (5 < 10) { return true; } else { return false; }
+10 == 10; 10 != 9;
@@ -0,0 +1 @@
+let x =
\ No newline at end of file
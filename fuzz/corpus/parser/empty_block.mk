@@ -0,0 +1,4 @@
+let f = fn() {
+    // 空
+};
+f();
@@ -0,0 +1,2 @@
+let = ; fn(a,) { a } } let y = 99999999999999999999;
+if (x { return }
@@ -0,0 +1,12 @@
+// 足し算
+let add = fn(x, y) {
+    x + y;
+}; // 二つ足す
+
+let r = add(1, 2);
+if (r > 2) {
+    // 大きい
+    true
+} else {
+    false
+};
@@ -0,0 +1,80 @@
+use std::rc::Rc;
+
+use crate::span::Span;
+use crate::symbol::Symbol;
+use crate::token::Operator;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Program {
+    pub statements: Vec<Statement>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Statement {
+    pub kind: StatementKind,
+    pub span: Span,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum StatementKind {
+    /// let <name> = <value>;
+    Let { name: Identifier, value: Expression },
+    /// return <value>;
+    Return(Expression),
+    /// <expression>;
+    Expression(Expression),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Identifier {
+    pub name: Symbol,
+    pub span: Span,
+}
+
+/// { <statements> }
+#[derive(Debug, PartialEq, Clone)]
+pub struct Block {
+    pub statements: Vec<Statement>,
+    pub span: Span,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Expression {
+    pub kind: ExpressionKind,
+    pub span: Span,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum ExpressionKind {
+    Identifier(Symbol),
+    Integer(i64),
+    Boolean(bool),
+    /// <operator><right>。operator は `!` か `-`
+    Prefix {
+        operator: Operator,
+        right: Box<Expression>,
+    },
+    /// <left> <operator> <right>
+    Infix {
+        operator: Operator,
+        left: Box<Expression>,
+        right: Box<Expression>,
+    },
+    /// if (<condition>) <consequence> else <alternative>
+    If {
+        condition: Box<Expression>,
+        consequence: Block,
+        alternative: Option<Block>,
+    },
+    /// fn(<parameters>) <body>
+    Function {
+        parameters: Vec<Identifier>,
+        /// 評価した関数値と共有する
+        body: Rc<Block>,
+    },
+    /// <function>(<arguments>)
+    Call {
+        function: Box<Expression>,
+        arguments: Vec<Expression>,
+    },
+}
@@ -0,0 +1,191 @@
+//! 仮想機械の命令とその符号化。
+//!
+//! 命令は 1 バイトの命令コードと、命令ごとに決まった幅のオペランドを並べたもの。
+//! オペランドはビッグエンディアンで、幅は `Opcode::operand_widths` が返す。
+
+use std::fmt;
+use std::rc::Rc;
+
+use crate::span::Span;
+use crate::symbol::Symbol;
+
+pub type Instructions = Vec<u8>;
+
+macro_rules! opcodes {
+    ($($(#[$doc:meta])* $name:ident = $byte:literal [$($width:literal),*],)*) => {
+        #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+        #[repr(u8)]
+        pub enum Opcode {
+            $($(#[$doc])* $name = $byte,)*
+        }
+
+        impl Opcode {
+            pub const ALL: &'static [Opcode] = &[$(Opcode::$name,)*];
+
+            pub fn from_byte(byte: u8) -> Option<Opcode> {
+                match byte {
+                    $($byte => Some(Opcode::$name),)*
+                    _ => None,
+                }
+            }
+
+            /// オペランドのバイト数
+            pub fn operand_widths(self) -> &'static [usize] {
+                match self {
+                    $(Opcode::$name => &[$($width),*],)*
+                }
+            }
+
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Opcode::$name => stringify!($name),)*
+                }
+            }
+        }
+    };
+}
+
+opcodes! {
+    /// 定数表の値を積む
+    Constant = 0 [2],
+    Null = 1 [],
+    True = 2 [],
+    False = 3 [],
+    /// 一番上の値を捨てる
+    Pop = 4 [],
+    Add = 5 [],
+    Sub = 6 [],
+    Mul = 7 [],
+    Div = 8 [],
+    Equal = 9 [],
+    NotEqual = 10 [],
+    LessThan = 11 [],
+    GreaterThan = 12 [],
+    /// 単項の `-`
+    Minus = 13 [],
+    /// 単項の `!`
+    Bang = 14 [],
+    /// 関数の先頭からの位置へ飛ぶ
+    Jump = 15 [2],
+    /// 取り出した値が偽なら飛ぶ
+    JumpNotTruthy = 16 [2],
+    GetGlobal = 17 [2],
+    SetGlobal = 18 [2],
+    GetLocal = 19 [2],
+    SetLocal = 20 [2],
+    /// クロージャが閉じ込めた変数を積む
+    GetFree = 21 [2],
+    /// 定数表の関数から、今の関数の変数を閉じ込めたクロージャを作る
+    Closure = 22 [2],
+    /// 引数の数
+    Call = 23 [2],
+    ReturnValue = 24 [],
+    /// 今のフレームを呼び出し先のフレームで置き換える `Call`
+    TailCall = 25 [2],
+}
+
+/// 命令をひとつ符号化する
+pub fn make(op: Opcode, operands: &[usize]) -> Instructions {
+    let mut instruction = vec![op as u8];
+    for (operand, width) in operands.iter().zip(op.operand_widths()) {
+        match width {
+            1 => instruction.push(*operand as u8),
+            2 => instruction.extend_from_slice(&(*operand as u16).to_be_bytes()),
+            _ => unreachable!("unsupported operand width"),
+        }
+    }
+    instruction
+}
+
+/// `at` から幅 `width` のオペランドを読む
+pub fn read_operand(instructions: &[u8], at: usize, width: usize) -> usize {
+    match width {
+        1 => instructions[at] as usize,
+        2 => u16::from_be_bytes([instructions[at], instructions[at + 1]]) as usize,
+        _ => unreachable!("unsupported operand width"),
+    }
+}
+
+/// コンパイルした関数。定数表は関数ごとに持つ。
+#[derive(Debug, PartialEq)]
+pub struct CompiledFunction {
+    pub parameters: Vec<Symbol>,
+    pub instructions: Instructions,
+    pub constants: Vec<Constant>,
+    /// 仮引数を含む局所変数の名前。添字がスロットの番号。
+    pub locals: Vec<Symbol>,
+    /// 内側の関数に閉じ込められる局所変数のスロット。呼び出し時にセルにしておく。
+    pub cells: Vec<usize>,
+    /// 閉じ込めた変数の名前と、外側の関数のどこから閉じ込めるか
+    pub free: Vec<(Symbol, Capture)>,
+    /// まだ `let` していないかもしれない局所変数のスロットと、値がないときに代わりに読む変数
+    pub local_fallbacks: Vec<(usize, Fallback)>,
+    /// 閉じ込めた変数の番号と、値がないときに代わりに読む変数。代わりの変数の番号の方が大きい
+    pub free_fallbacks: Vec<(usize, Fallback)>,
+    /// 命令の位置と、その命令を生んだソースの範囲。位置の順に並ぶ。
+    pub spans: Vec<(usize, Span)>,
+}
+
+impl CompiledFunction {
+    pub fn local_fallback(&self, slot: usize) -> Option<Fallback> {
+        find(&self.local_fallbacks, slot)
+    }
+
+    pub fn free_fallback(&self, i: usize) -> Option<Fallback> {
+        find(&self.free_fallbacks, i)
+    }
+
+    /// `ip` の命令を生んだソースの範囲
+    pub fn span_at(&self, ip: usize) -> Span {
+        let i = self.spans.partition_point(|(offset, _)| *offset <= ip);
+        match i {
+            0 => Span { start: 0, end: 0 },
+            i => self.spans[i - 1].1,
+        }
+    }
+}
+
+/// クロージャを作るときに、外側の関数から受け取るセル
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Capture {
+    /// 外側の関数の局所変数のスロット
+    Local(usize),
+    /// 外側の関数が閉じ込めた変数の番号
+    Free(usize),
+}
+
+pub(crate) fn find(fallbacks: &[(usize, Fallback)], key: usize) -> Option<Fallback> {
+    fallbacks
+        .iter()
+        .find(|(k, _)| *k == key)
+        .map(|(_, fallback)| *fallback)
+}
+
+/// 値のない変数を読んだときに代わりに読む、外側のスコープで同じ名前が指す変数。
+/// 評価器で、まだ `let` していない名前を外側の環境から探すのと同じになる。
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Fallback {
+    /// 閉じ込めた変数の番号
+    Free(usize),
+    /// 大域変数のスロット
+    Global(usize),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Constant {
+    Integer(i64),
+    Function(Rc<CompiledFunction>),
+}
+
+impl fmt::Display for Constant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Constant::Integer(value) => write!(f, "{}", value),
+            Constant::Function(function) => {
+                let parameters: Vec<&str> =
+                    function.parameters.iter().map(|p| p.as_str()).collect();
+                write!(f, "fn({})", parameters.join(", "))
+            }
+        }
+    }
+}
@@ -0,0 +1,686 @@
+//! 構文木を仮想機械の命令に変換する。
+//!
+//! 評価器と同じ結果になるように、名前は次のように解決する。
+//!
+//! - 関数の中では、仮引数とその関数の本体で `let` する名前がすべて局所変数になる。
+//!   `if` のブロックはスコープを作らないので、ブロックの中の `let` も関数の局所変数。
+//! - 内側の関数から参照される局所変数はセルに入れ、クロージャと共有する。
+//!   評価器の環境と同じく、閉じ込めた後の代入もクロージャから見える。
+//! - どの関数の局所変数でもない名前は大域変数。定義より前に参照してもよく、
+//!   実行時に値がなければ組み込み関数を探す。
+//! - 評価器では `let` する前の名前は外側の束縛を指す。まだ `let` していないかもしれない
+//!   局所変数には、外側のスコープで同じ名前が指す変数を `Fallback` として覚えておき、
+//!   仮想機械は値がなければそちらを読む。`if` の両方の枝で `let` したものは後で必ず値がある。
+//!
+//! 関数の値になる位置での呼び出しは `TailCall` にして、フレームを積まずに呼ぶ。
+//! 関数の中で `return` する呼び出しは、どこにあってもその値が関数の値になるので同じにする。
+//!
+//! 式の入れ子が `limits::DEFAULT_MAX_NESTING` より深ければ、たどらずにエラーにする。
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::rc::Rc;
+
+use crate::ast::*;
+use crate::code::{self, Capture, CompiledFunction, Constant, Fallback, Instructions, Opcode};
+use crate::diagnostic::{self, Diagnostic};
+use crate::limits::{self, DEFAULT_MAX_NESTING};
+use crate::span::Span;
+use crate::symbol::Symbol;
+use crate::token::Operator;
+
+/// 定数、変数、引数、ジャンプ先などの数がオペランドに収まらない
+pub const E_TOO_LARGE: &str = "E0300";
+
+/// コンパイル結果。`globals` は大域変数のスロットごとの名前。
+#[derive(Debug, PartialEq)]
+pub struct Bytecode {
+    pub main: Rc<CompiledFunction>,
+    pub globals: Vec<Symbol>,
+}
+
+/// 大域変数の表を持ち、続けてコンパイルしたプログラムの間でスロットを引き継ぐ
+#[derive(Default)]
+pub struct Compiler {
+    globals: HashMap<Symbol, usize>,
+    global_names: Vec<Symbol>,
+    scopes: Vec<Scope>,
+    error: Option<Diagnostic>,
+    /// たどっている式の入れ子の深さ
+    depth: usize,
+}
+
+pub fn new() -> Compiler {
+    Compiler::default()
+}
+
+/// コンパイル中の関数。一番外側はプログラム本体で、局所変数を持たない。
+#[derive(Default)]
+struct Scope {
+    parameters: Vec<Symbol>,
+    instructions: Instructions,
+    constants: Vec<Constant>,
+    spans: Vec<(usize, Span)>,
+    locals: HashMap<Symbol, usize>,
+    local_names: Vec<Symbol>,
+    cells: BTreeSet<usize>,
+    free: Vec<(Symbol, Capture)>,
+    /// 今の位置で必ず `let` し終えている局所変数のスロット
+    assigned: HashSet<usize>,
+    /// 代わりに読むためだけに閉じ込めた変数。名前で探すときは見ない
+    hidden: HashSet<usize>,
+    local_fallbacks: Vec<(usize, Fallback)>,
+    free_fallbacks: Vec<(usize, Fallback)>,
+}
+
+enum Resolved {
+    Global(usize),
+    Local(usize),
+    Free(usize),
+}
+
+impl Compiler {
+    pub fn compile(&mut self, program: &Program) -> Result<Bytecode, Diagnostic> {
+        self.error = None;
+        self.depth = 0;
+        self.scopes = vec![Scope::default()];
+        let span = match (program.statements.first(), program.statements.last()) {
+            (Some(first), Some(last)) => Span {
+                start: first.span.start,
+                end: last.span.end,
+            },
+            _ => Span { start: 0, end: 0 },
+        };
+        self.block(&program.statements, span, false);
+        self.emit(Opcode::ReturnValue, &[], span);
+        let main = self.leave_scope();
+        match self.error.take() {
+            Some(error) => Err(error),
+            None => Ok(Bytecode {
+                main: Rc::new(main),
+                globals: self.global_names.clone(),
+            }),
+        }
+    }
+
+    /// `name` の大域変数のスロット。なければ作る。
+    pub fn global(&mut self, name: &Symbol) -> usize {
+        if let Some(&slot) = self.globals.get(name) {
+            return slot;
+        }
+        let slot = self.global_names.len();
+        self.globals.insert(name.clone(), slot);
+        self.global_names.push(name.clone());
+        slot
+    }
+
+    fn scope(&mut self) -> &mut Scope {
+        self.scopes.last_mut().expect("no scope")
+    }
+
+    fn leave_scope(&mut self) -> CompiledFunction {
+        let scope = self.scopes.pop().expect("no scope");
+        CompiledFunction {
+            parameters: scope.parameters,
+            instructions: scope.instructions,
+            constants: scope.constants,
+            locals: scope.local_names,
+            cells: scope.cells.into_iter().collect(),
+            free: scope.free,
+            local_fallbacks: scope.local_fallbacks,
+            free_fallbacks: scope.free_fallbacks,
+            spans: scope.spans,
+        }
+    }
+
+    /// 命令を書き出して、その位置を返す
+    fn emit(&mut self, op: Opcode, operands: &[usize], span: Span) -> usize {
+        for (operand, width) in operands.iter().zip(op.operand_widths()) {
+            self.check(*operand, *width, span);
+        }
+        let scope = self.scope();
+        let position = scope.instructions.len();
+        if scope.spans.last().is_none_or(|(_, last)| *last != span) {
+            scope.spans.push((position, span));
+        }
+        scope.instructions.extend(code::make(op, operands));
+        position
+    }
+
+    /// オペランドが `width` バイトに収まるか
+    fn check(&mut self, operand: usize, width: usize, span: Span) {
+        if operand >> (8 * width) != 0 && self.error.is_none() {
+            self.error = Some(diagnostic::error(
+                E_TOO_LARGE,
+                format!("operand {} does not fit in {} bytes", operand, width),
+                span,
+            ));
+        }
+    }
+
+    /// `position` のジャンプの行き先を今の位置にする
+    fn patch_jump(&mut self, position: usize, span: Span) {
+        let target = self.scope().instructions.len();
+        self.check(target, 2, span);
+        let bytes = (target as u16).to_be_bytes();
+        self.scope().instructions[position + 1..position + 3].copy_from_slice(&bytes);
+    }
+
+    fn constant(&mut self, constant: Constant) -> usize {
+        let constants = &mut self.scope().constants;
+        if let Constant::Integer(_) = constant {
+            if let Some(i) = constants.iter().position(|c| *c == constant) {
+                return i;
+            }
+        }
+        constants.push(constant);
+        constants.len() - 1
+    }
+
+    /// 文を並べたものの値をひとつ積む。`let` の値は null。
+    /// `tail` のときはこの値がそのまま関数の値になるので、末尾の呼び出しは `TailCall` にする。
+    fn block(&mut self, statements: &[Statement], span: Span, tail: bool) {
+        if statements.is_empty() {
+            self.emit(Opcode::Null, &[], span);
+            return;
+        }
+        for (i, statement) in statements.iter().enumerate() {
+            let last = i + 1 == statements.len();
+            let span = statement.span;
+            match &statement.kind {
+                StatementKind::Let { name, value } => {
+                    self.expression(value);
+                    self.assign(&name.name, span);
+                    if last {
+                        self.emit(Opcode::Null, &[], span);
+                    }
+                }
+                StatementKind::Return(value) => {
+                    // プログラム本体は関数ではないので、本体の `return` は普通に呼ぶ
+                    self.tail_expression(value, self.scopes.len() > 1);
+                    self.emit(Opcode::ReturnValue, &[], span);
+                }
+                StatementKind::Expression(expression) => {
+                    self.tail_expression(expression, last && tail);
+                    if !last {
+                        self.emit(Opcode::Pop, &[], span);
+                    }
+                }
+            }
+        }
+    }
+
+    fn assign(&mut self, name: &Symbol, span: Span) {
+        if self.scopes.len() == 1 {
+            let slot = self.global(name);
+            self.emit(Opcode::SetGlobal, &[slot], span);
+            return;
+        }
+        let slot = self.scope().locals[name];
+        self.emit(Opcode::SetLocal, &[slot], span);
+        self.scope().assigned.insert(slot);
+    }
+
+    /// 内側のスコープから順に探す。外側の関数の局所変数は閉じ込める。
+    fn resolve(&mut self, name: &Symbol, depth: usize) -> Resolved {
+        self.lookup(name, depth).0
+    }
+
+    /// `resolve` と同じだが、今の位置で値がないかもしれない変数なら代わりに読む変数も返す
+    fn lookup(&mut self, name: &Symbol, depth: usize) -> (Resolved, Option<Fallback>) {
+        if depth == 0 {
+            return (Resolved::Global(self.global(name)), None);
+        }
+        let scope = &self.scopes[depth];
+        if let Some(&slot) = scope.locals.get(name) {
+            let fallback = scope
+                .may_be_unset(slot)
+                .then(|| self.local_fallback(name, depth, slot));
+            return (Resolved::Local(slot), fallback);
+        }
+        let visible = scope
+            .free
+            .iter()
+            .enumerate()
+            .position(|(i, (free, _))| free == name && !scope.hidden.contains(&i));
+        if let Some(i) = visible {
+            return (Resolved::Free(i), code::find(&scope.free_fallbacks, i));
+        }
+        let (capture, fallback) = match self.lookup(name, depth - 1) {
+            (Resolved::Global(slot), _) => return (Resolved::Global(slot), None),
+            (Resolved::Local(slot), fallback) => {
+                self.scopes[depth - 1].cells.insert(slot);
+                (Capture::Local(slot), fallback)
+            }
+            (Resolved::Free(i), fallback) => (Capture::Free(i), fallback),
+        };
+        let i = self.capture(name, depth, capture, fallback);
+        (
+            Resolved::Free(i),
+            code::find(&self.scopes[depth].free_fallbacks, i),
+        )
+    }
+
+    /// `depth` の関数に外側の `capture` を閉じ込める。外側の変数に代わりの変数 `fallback` があれば、
+    /// それも閉じ込めて代わりにする
+    fn capture(
+        &mut self,
+        name: &Symbol,
+        depth: usize,
+        capture: Capture,
+        fallback: Option<Fallback>,
+    ) -> usize {
+        let free = &mut self.scopes[depth].free;
+        free.push((name.clone(), capture));
+        let i = free.len() - 1;
+        if let Some(fallback) = fallback {
+            let fallback = match fallback {
+                Fallback::Global(slot) => Fallback::Global(slot),
+                Fallback::Free(j) => {
+                    let outer = code::find(&self.scopes[depth - 1].free_fallbacks, j);
+                    let hidden = self.capture(name, depth, Capture::Free(j), outer);
+                    self.scopes[depth].hidden.insert(hidden);
+                    Fallback::Free(hidden)
+                }
+            };
+            self.scopes[depth].free_fallbacks.push((i, fallback));
+        }
+        i
+    }
+
+    /// `depth` の関数の局所変数 `slot` に値がないときに読む、外側のスコープで `name` が指す変数
+    fn local_fallback(&mut self, name: &Symbol, depth: usize, slot: usize) -> Fallback {
+        if let Some(fallback) = code::find(&self.scopes[depth].local_fallbacks, slot) {
+            return fallback;
+        }
+        let fallback = match self.lookup(name, depth - 1) {
+            (Resolved::Global(slot), _) => Fallback::Global(slot),
+            (Resolved::Local(outer), fallback) => {
+                self.scopes[depth - 1].cells.insert(outer);
+                let hidden = self.capture(name, depth, Capture::Local(outer), fallback);
+                self.scopes[depth].hidden.insert(hidden);
+                Fallback::Free(hidden)
+            }
+            (Resolved::Free(i), fallback) => {
+                let hidden = self.capture(name, depth, Capture::Free(i), fallback);
+                self.scopes[depth].hidden.insert(hidden);
+                Fallback::Free(hidden)
+            }
+        };
+        self.scopes[depth].local_fallbacks.push((slot, fallback));
+        fallback
+    }
+
+    /// `tail` のときは、呼び出しと `if` の枝の末尾の呼び出しを `TailCall` にする
+    fn tail_expression(&mut self, expression: &Expression, tail: bool) {
+        let span = expression.span;
+        match &expression.kind {
+            ExpressionKind::Call {
+                function,
+                arguments,
+            } if tail => self.nested(span, |c| {
+                c.chained(function);
+                for argument in arguments {
+                    c.expression(argument);
+                }
+                c.emit(Opcode::TailCall, &[arguments.len()], span);
+            }),
+            ExpressionKind::If {
+                condition,
+                consequence,
+                alternative,
+            } => self.nested(span, |c| {
+                c.if_expression(condition, consequence, alternative.as_ref(), span, tail)
+            }),
+            _ => self.expression(expression),
+        }
+    }
+
+    /// 入れ子を一段深くして `compile` を呼ぶ。上限を超えていればたどらずにエラーにする
+    fn nested(&mut self, span: Span, compile: impl FnOnce(&mut Compiler)) {
+        if self.depth >= DEFAULT_MAX_NESTING {
+            if self.error.is_none() {
+                self.error = Some(limits::nesting_too_deep(DEFAULT_MAX_NESTING, span));
+            }
+            return;
+        }
+        self.depth += 1;
+        limits::grow_stack(|| compile(self));
+        self.depth -= 1;
+    }
+
+    /// 左結合の連なりの左側の式や呼び出す関数。囲む式と同じ深さでたどる
+    fn chained(&mut self, expression: &Expression) {
+        self.depth -= 1;
+        self.expression(expression);
+        self.depth += 1;
+    }
+
+    fn if_expression(
+        &mut self,
+        condition: &Expression,
+        consequence: &Block,
+        alternative: Option<&Block>,
+        span: Span,
+        tail: bool,
+    ) {
+        self.expression(condition);
+        let jump_not_truthy = self.emit(Opcode::JumpNotTruthy, &[0], span);
+        let before = self.scope().assigned.clone();
+        self.block(&consequence.statements, consequence.span, tail);
+        let jump = self.emit(Opcode::Jump, &[0], span);
+        self.patch_jump(jump_not_truthy, span);
+        // どちらの枝を通っても `let` したものだけが、この後で必ず値を持つ
+        let consequence = std::mem::replace(&mut self.scope().assigned, before);
+        match alternative {
+            Some(alternative) => self.block(&alternative.statements, alternative.span, tail),
+            None => {
+                self.emit(Opcode::Null, &[], span);
+            }
+        }
+        self.scope()
+            .assigned
+            .retain(|slot| consequence.contains(slot));
+        self.patch_jump(jump, span);
+    }
+
+    fn expression(&mut self, expression: &Expression) {
+        self.nested(expression.span, |c| {
+            c.expression_kind(&expression.kind, expression.span)
+        });
+    }
+
+    fn expression_kind(&mut self, kind: &ExpressionKind, span: Span) {
+        match kind {
+            ExpressionKind::Identifier(name) => {
+                match self.resolve(name, self.scopes.len() - 1) {
+                    Resolved::Global(slot) => self.emit(Opcode::GetGlobal, &[slot], span),
+                    Resolved::Local(slot) => self.emit(Opcode::GetLocal, &[slot], span),
+                    Resolved::Free(i) => self.emit(Opcode::GetFree, &[i], span),
+                };
+            }
+            ExpressionKind::Integer(value) => {
+                let i = self.constant(Constant::Integer(*value));
+                self.emit(Opcode::Constant, &[i], span);
+            }
+            ExpressionKind::Boolean(true) => {
+                self.emit(Opcode::True, &[], span);
+            }
+            ExpressionKind::Boolean(false) => {
+                self.emit(Opcode::False, &[], span);
+            }
+            ExpressionKind::Prefix { operator, right } => {
+                self.expression(right);
+                let op = match operator {
+                    Operator::Minus => Opcode::Minus,
+                    Operator::Not => Opcode::Bang,
+                    _ => unreachable!("not a prefix operator"),
+                };
+                self.emit(op, &[], span);
+            }
+            ExpressionKind::Infix {
+                operator,
+                left,
+                right,
+            } => {
+                self.chained(left);
+                self.expression(right);
+                let op = match operator {
+                    Operator::Plus => Opcode::Add,
+                    Operator::Minus => Opcode::Sub,
+                    Operator::Mul => Opcode::Mul,
+                    Operator::Div => Opcode::Div,
+                    Operator::Lt => Opcode::LessThan,
+                    Operator::Gt => Opcode::GreaterThan,
+                    Operator::Eq => Opcode::Equal,
+                    Operator::NotEq => Opcode::NotEqual,
+                    Operator::Assign | Operator::Not => unreachable!("not an infix operator"),
+                };
+                self.emit(op, &[], span);
+            }
+            ExpressionKind::If {
+                condition,
+                consequence,
+                alternative,
+            } => self.if_expression(condition, consequence, alternative.as_ref(), span, false),
+            ExpressionKind::Function { parameters, body } => {
+                let mut scope = Scope::default();
+                for parameter in parameters {
+                    scope.declare(&parameter.name, true);
+                }
+                scope.parameters = scope.local_names.clone();
+                declare_lets(&body.statements, &mut scope);
+                self.scopes.push(scope);
+                self.block(&body.statements, body.span, true);
+                self.emit(Opcode::ReturnValue, &[], body.span);
+                let function = self.leave_scope();
+                let i = self.constant(Constant::Function(Rc::new(function)));
+                self.emit(Opcode::Closure, &[i], span);
+            }
+            ExpressionKind::Call {
+                function,
+                arguments,
+            } => {
+                self.chained(function);
+                for argument in arguments {
+                    self.expression(argument);
+                }
+                self.emit(Opcode::Call, &[arguments.len()], span);
+            }
+        }
+    }
+}
+
+impl Scope {
+    /// 仮引数は呼び出したときから値を持つ
+    fn may_be_unset(&self, slot: usize) -> bool {
+        slot >= self.parameters.len() && !self.assigned.contains(&slot)
+    }
+
+    /// 仮引数は同じ名前でも別のスロットを取り、後のものが名前に結びつく
+    fn declare(&mut self, name: &Symbol, parameter: bool) {
+        if !parameter && self.locals.contains_key(name) {
+            return;
+        }
+        self.locals.insert(name.clone(), self.local_names.len());
+        self.local_names.push(name.clone());
+    }
+}
+
+/// 関数の本体で `let` する名前を局所変数にする。内側の関数の中は見ない。
+fn declare_lets(statements: &[Statement], scope: &mut Scope) {
+    for statement in statements {
+        match &statement.kind {
+            StatementKind::Let { name, value } => {
+                scope.declare(&name.name, false);
+                declare_lets_in(value, scope);
+            }
+            StatementKind::Return(value) | StatementKind::Expression(value) => {
+                declare_lets_in(value, scope)
+            }
+        }
+    }
+}
+
+fn declare_lets_in(expression: &Expression, scope: &mut Scope) {
+    limits::grow_stack(|| match &expression.kind {
+        ExpressionKind::Identifier(_)
+        | ExpressionKind::Integer(_)
+        | ExpressionKind::Boolean(_)
+        | ExpressionKind::Function { .. } => {}
+        ExpressionKind::Prefix { right, .. } => declare_lets_in(right, scope),
+        ExpressionKind::Infix { left, right, .. } => {
+            declare_lets_in(left, scope);
+            declare_lets_in(right, scope);
+        }
+        ExpressionKind::If {
+            condition,
+            consequence,
+            alternative,
+        } => {
+            declare_lets_in(condition, scope);
+            declare_lets(&consequence.statements, scope);
+            if let Some(alternative) = alternative {
+                declare_lets(&alternative.statements, scope);
+            }
+        }
+        ExpressionKind::Call {
+            function,
+            arguments,
+        } => {
+            declare_lets_in(function, scope);
+            for argument in arguments {
+                declare_lets_in(argument, scope);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::code::{make, Capture, Constant, Fallback, Opcode};
+    use crate::parser;
+
+    fn compile(input: &str) -> super::Bytecode {
+        let (program, errors) = parser::parse(input);
+        assert_eq!(errors, vec![]);
+        super::new().compile(&program).unwrap()
+    }
+
+    #[test]
+    fn instructions() {
+        let bytecode = compile("let x = 1; if (x < 2) { x } else { -x }");
+        let expected: Vec<u8> = [
+            make(Opcode::Constant, &[0]),
+            make(Opcode::SetGlobal, &[0]),
+            make(Opcode::GetGlobal, &[0]),
+            make(Opcode::Constant, &[1]),
+            make(Opcode::LessThan, &[]),
+            make(Opcode::JumpNotTruthy, &[22]),
+            make(Opcode::GetGlobal, &[0]),
+            make(Opcode::Jump, &[26]),
+            make(Opcode::GetGlobal, &[0]),
+            make(Opcode::Minus, &[]),
+            make(Opcode::ReturnValue, &[]),
+        ]
+        .concat();
+        assert_eq!(bytecode.main.instructions, expected);
+        assert_eq!(
+            bytecode.main.constants,
+            vec![Constant::Integer(1), Constant::Integer(2)]
+        );
+    }
+
+    #[test]
+    fn closures() {
+        let bytecode = compile("fn(a) { let b = 1; fn() { fn() { a + b } } }");
+        let Constant::Function(outer) = &bytecode.main.constants[0] else {
+            panic!("not a function");
+        };
+        assert_eq!(outer.locals, vec!["a", "b"]);
+        assert_eq!(outer.cells, vec![0, 1]);
+        let Constant::Function(middle) = &outer.constants[1] else {
+            panic!("not a function");
+        };
+        let captures: Vec<Capture> = middle.free.iter().map(|(_, c)| *c).collect();
+        assert_eq!(captures, vec![Capture::Local(0), Capture::Local(1)]);
+        let Constant::Function(inner) = &middle.constants[0] else {
+            panic!("not a function");
+        };
+        let captures: Vec<Capture> = inner.free.iter().map(|(_, c)| *c).collect();
+        assert_eq!(captures, vec![Capture::Free(0), Capture::Free(1)]);
+    }
+
+    /// まだ代入されていないかもしれない変数は、外側で同じ名前が指す変数を控えておく
+    #[test]
+    fn fallbacks() {
+        let bytecode =
+            compile("let x = 1; fn(a) { let y = x; if (a) { let x = 2; } fn() { x + y } }");
+        let Constant::Function(outer) = &bytecode.main.constants[1] else {
+            panic!("not a function");
+        };
+        assert_eq!(outer.locals, vec!["a", "y", "x"]);
+        assert_eq!(outer.local_fallbacks, vec![(2, Fallback::Global(0))]);
+        let Constant::Function(inner) = &outer.constants[1] else {
+            panic!("not a function");
+        };
+        assert_eq!(inner.free_fallbacks, vec![(0, Fallback::Global(0))]);
+        // 分岐の両方で代入していれば控えない
+        let bytecode = compile("fn(a) { if (a) { let x = 1; } else { let x = 2; } x }");
+        let Constant::Function(function) = &bytecode.main.constants[0] else {
+            panic!("not a function");
+        };
+        assert_eq!(function.local_fallbacks, vec![]);
+    }
+
+    fn opcodes(instructions: &[u8]) -> Vec<Opcode> {
+        let mut opcodes = vec![];
+        let mut at = 0;
+        while at < instructions.len() {
+            let op = Opcode::from_byte(instructions[at]).unwrap();
+            opcodes.push(op);
+            at += 1 + op.operand_widths().iter().sum::<usize>();
+        }
+        opcodes
+    }
+
+    #[test]
+    fn tail_calls() {
+        let cases = [
+            ("fn(n) { f(n) }", true),
+            ("fn(n) { if (n) { return f(n); } else { 1 } }", true),
+            // `return` はどこにあっても関数の値になる
+            ("fn(n) { if (n) { return f(n); } 1 }", true),
+            ("fn(n) { 1 + if (n) { return f(n); } else { 2 } }", true),
+            ("fn(n) { 1 + f(n) }", false),
+            ("fn(n) { f(n); 1 }", false),
+            ("fn(n) { let x = f(n); }", false),
+        ];
+        for (input, tail) in cases {
+            let bytecode = compile(input);
+            let Constant::Function(function) = &bytecode.main.constants[0] else {
+                panic!("not a function");
+            };
+            let call = if tail { Opcode::TailCall } else { Opcode::Call };
+            assert!(opcodes(&function.instructions).contains(&call), "{}", input);
+        }
+        // プログラム本体は関数ではないので、末尾でも普通の呼び出しにする
+        assert!(!opcodes(&compile("f(1)").main.instructions).contains(&Opcode::TailCall));
+    }
+
+    /// 手で組んだ深すぎる構文木は、たどらずにエラーにする
+    #[test]
+    fn nesting_limit() {
+        use crate::ast::*;
+        use crate::limits::{DEFAULT_MAX_NESTING, E_NESTING, MAX_EXPRESSION_HEIGHT};
+        use crate::span::Span;
+        use crate::token::Operator;
+
+        let span = Span { start: 0, end: 1 };
+        let mut expression = Expression {
+            kind: ExpressionKind::Integer(1),
+            span,
+        };
+        for _ in 0..DEFAULT_MAX_NESTING * 2 {
+            expression = Expression {
+                kind: ExpressionKind::Prefix {
+                    operator: Operator::Minus,
+                    right: Box::new(expression),
+                },
+                span,
+            };
+        }
+        let program = Program {
+            statements: vec![Statement {
+                kind: StatementKind::Expression(expression),
+                span,
+            }],
+        };
+        let error = super::new().compile(&program).unwrap_err();
+        assert_eq!(error.code, E_NESTING);
+
+        // 左結合の連なりは入れ子に数えない
+        let n = MAX_EXPRESSION_HEIGHT - 1;
+        compile(&format!("1{}", " + 1".repeat(n)));
+        compile(&format!("f{}", "(1)".repeat(n)));
+    }
+}
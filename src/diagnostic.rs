@@ -0,0 +1,167 @@
+//! エラーや警告をソースの該当行とキャレット付きで表示する。
+//!
+//! ```text
+//! error[E0001]: illegal character `@`
+//!  --> main.mk:1:5
+//!   |
+//! 1 | let @ = 5;
+//!   |     ^
+//!   = help: identifiers may only contain ASCII letters, digits and `_`
+//! ```
+
+use std::fmt;
+
+use crate::span::{LineIndex, Span};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub code: &'static str,
+    pub message: String,
+    pub span: Span,
+    pub notes: Vec<String>,
+    pub help: Option<String>,
+}
+
+pub fn error(code: &'static str, message: String, span: Span) -> Diagnostic {
+    Diagnostic {
+        severity: Severity::Error,
+        code,
+        message,
+        span,
+        notes: vec![],
+        help: None,
+    }
+}
+
+pub fn warning(code: &'static str, message: String, span: Span) -> Diagnostic {
+    Diagnostic {
+        severity: Severity::Warning,
+        ..error(code, message, span)
+    }
+}
+
+impl Diagnostic {
+    pub fn with_note(mut self, note: String) -> Diagnostic {
+        self.notes.push(note);
+        self
+    }
+
+    pub fn with_help(mut self, help: String) -> Diagnostic {
+        self.help = Some(help);
+        self
+    }
+
+    /// `source` のうち `span` を含む行を切り出し、範囲の下にキャレットを引いて表示する。
+    /// 範囲が複数行にまたがる場合は最初の行の末尾までを示す。
+    pub fn render(&self, source: &str, name: &str) -> String {
+        let index = LineIndex::new(source);
+        let start = index.position(self.span.start);
+        let line = source.lines().nth(start.line - 1).unwrap_or("");
+        let line_len = line.chars().count();
+
+        let underline_start = (start.column - 1).min(line_len);
+        let end = index.position(self.span.end);
+        let underline_end = if end.line == start.line {
+            (end.column - 1).min(line_len)
+        } else {
+            line_len
+        };
+        // 空の範囲 (EOF など) でも位置が分かるように最低 1 文字は引く
+        let width = underline_end.saturating_sub(underline_start).max(1);
+
+        let gutter = start.line.to_string().len();
+        let pad = " ".repeat(gutter);
+        let mut out = format!("{}[{}]: {}\n", self.severity, self.code, self.message);
+        out += &format!("{}--> {}:{}:{}\n", pad, name, start.line, start.column);
+        out += &format!("{} |\n", pad);
+        out += &format!("{} | {}\n", start.line, line);
+        out += &format!(
+            "{} | {}{}\n",
+            pad,
+            " ".repeat(underline_start),
+            "^".repeat(width)
+        );
+        for note in &self.notes {
+            out += &format!("{} = note: {}\n", pad, note);
+        }
+        if let Some(help) = &self.help {
+            out += &format!("{} = help: {}\n", pad, help);
+        }
+        out
+    }
+}
+
+/// まとめて表示する。診断同士の間は空行で区切る。
+pub fn render_all(diagnostics: &[Diagnostic], source: &str, name: &str) -> String {
+    diagnostics
+        .iter()
+        .map(|d| d.render(source, name))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+pub fn has_errors(diagnostics: &[Diagnostic]) -> bool {
+    diagnostics.iter().any(|d| d.severity == Severity::Error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{error, warning};
+    use crate::span::Span;
+
+    #[test]
+    fn render() {
+        let source = "let a = 1;\nlet b = a + c;\n";
+        let diagnostic = error(
+            "E0000",
+            "something is wrong".to_string(),
+            Span { start: 19, end: 24 },
+        )
+        .with_note("a note".to_string())
+        .with_help("a help".to_string());
+        assert_eq!(
+            diagnostic.render(source, "main.mk"),
+            "\
+error[E0000]: something is wrong
+ --> main.mk:2:9
+  |
+2 | let b = a + c;
+  |         ^^^^^
+  = note: a note
+  = help: a help
+"
+        );
+    }
+
+    #[test]
+    fn render_empty_span_at_end() {
+        let source = "let a = 1";
+        let diagnostic = warning("W0000", "here".to_string(), Span { start: 9, end: 9 });
+        assert_eq!(
+            diagnostic.render(source, "main.mk"),
+            "\
+warning[W0000]: here
+ --> main.mk:1:10
+  |
+1 | let a = 1
+  |          ^
+"
+        );
+    }
+}
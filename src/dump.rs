@@ -0,0 +1,334 @@
+//! トークン列などの内部表現を人やツール向けに書き出す。
+//!
+//! `--format json` のトークン列は次のスキーマで出力する (`version` は互換性のない変更で上げる)。
+//!
+//! ```text
+//! {
+//!   "version": 1,
+//!   "tokens": [
+//!     {
+//!       "kind": "LET",          // TokenType の Display と同じ名前
+//!       "literal": "let",
+//!       "span": {
+//!         "start": { "offset": 0, "line": 1, "column": 1 },
+//!         "end": { "offset": 3, "line": 1, "column": 4 }
+//!       }
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! `offset` は文字単位、`line` と `column` は 1 始まりで、`end` は範囲に含まない。
+//! 最後のトークンは常に `EOF` になる。
+//!
+//! `--dump-ast` の JSON は構文木と診断を次の形で出力する。
+//! 各ノードは `type` と `span` (上と同じ形) を持つ。
+//!
+//! ```text
+//! {
+//!   "version": 1,
+//!   "program": { "type": "Program", "statements": [<Statement>...] },
+//!   "diagnostics": [
+//!     { "severity": "error", "code": "E0100", "message": "...", "span": {...} }
+//!   ]
+//! }
+//!
+//! Statement:
+//!   { "type": "Let", "name": <Identifier>, "value": <Expression> }
+//!   { "type": "Return", "value": <Expression> }
+//!   { "type": "Expression", "expression": <Expression> }
+//! Block:
+//!   { "type": "Block", "statements": [<Statement>...] }
+//! Expression:
+//!   { "type": "Identifier", "name": "x" }
+//!   { "type": "Integer", "value": 5 }
+//!   { "type": "Boolean", "value": true }
+//!   { "type": "Prefix", "operator": "!", "right": <Expression> }
+//!   { "type": "Infix", "operator": "+", "left": <Expression>, "right": <Expression> }
+//!   { "type": "If", "condition": <Expression>, "consequence": <Block>,
+//!     "alternative": <Block> | null }
+//!   { "type": "Function", "parameters": [<Identifier>...], "body": <Block> }
+//!   { "type": "Call", "function": <Expression>, "arguments": [<Expression>...] }
+//! ```
+//!
+//! 構文エラーのある文は `statements` に含まれない。
+
+use serde_json::{json, Value};
+
+use crate::ast::*;
+use crate::diagnostic::Diagnostic;
+use crate::limits;
+use crate::span::{LineIndex, Span};
+use crate::token;
+
+/// JSON スキーマのバージョン
+pub const JSON_VERSION: u32 = 1;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Format {
+    Table,
+    Json,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Option<Format> {
+        match name {
+            "table" => Some(Format::Table),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+/// `tokens` は `lexer::tokenize(input)` の結果
+pub fn tokens(input: &str, tokens: &[(token::Token, Span)], format: Format) -> String {
+    match format {
+        Format::Table => tokens_table(input, tokens),
+        Format::Json => tokens_json(input, tokens),
+    }
+}
+
+/// トークン列を `行:列 種類 リテラル` の表として書き出す。EOF も最後の行に含める。
+pub fn tokens_table(input: &str, tokens: &[(token::Token, Span)]) -> String {
+    let index = LineIndex::new(input);
+    let mut out = String::new();
+
+    for (tok, span) in tokens {
+        let pos = index.position(span.start);
+        let line = format!(
+            "{:<8}{:<12}{}",
+            format!("{}:{}", pos.line, pos.column),
+            tok.token_type.to_string(),
+            tok.literal
+        );
+        out += line.trim_end();
+        out += "\n";
+    }
+    out
+}
+
+/// トークン列をモジュール先頭に書いたスキーマの JSON として書き出す
+pub fn tokens_json(input: &str, tokens: &[(token::Token, Span)]) -> String {
+    let index = LineIndex::new(input);
+    let tokens: Vec<Value> = tokens
+        .iter()
+        .map(|(tok, span)| {
+            json!({
+                "kind": tok.token_type.to_string(),
+                "literal": tok.literal,
+                "span": span_json(&index, *span),
+            })
+        })
+        .collect();
+    let doc = json!({ "version": JSON_VERSION, "tokens": tokens });
+    serde_json::to_string_pretty(&doc).unwrap() + "\n"
+}
+
+pub fn span_json(index: &LineIndex, span: Span) -> Value {
+    let position = |offset: usize| {
+        let pos = index.position(offset);
+        json!({ "offset": offset, "line": pos.line, "column": pos.column })
+    };
+    json!({ "start": position(span.start), "end": position(span.end) })
+}
+
+/// 構文木と診断をモジュール先頭に書いたスキーマの JSON として書き出す
+pub fn ast_json(input: &str, program: &Program, diagnostics: &[Diagnostic]) -> String {
+    let index = LineIndex::new(input);
+    let diagnostics: Vec<Value> = diagnostics
+        .iter()
+        .map(|d| {
+            json!({
+                "severity": d.severity.to_string(),
+                "code": d.code,
+                "message": d.message,
+                "span": span_json(&index, d.span),
+            })
+        })
+        .collect();
+    let mut doc = json!({
+        "version": JSON_VERSION,
+        "program": { "type": "Program" },
+    });
+    doc["program"]["statements"] = statements_json(&index, &program.statements);
+    doc["diagnostics"] = Value::Array(diagnostics);
+    serde_json::to_string_pretty(&doc).unwrap() + "\n"
+}
+
+fn statements_json(index: &LineIndex, statements: &[Statement]) -> Value {
+    statements
+        .iter()
+        .map(|s| statement_json(index, s))
+        .collect()
+}
+
+fn statement_json(index: &LineIndex, statement: &Statement) -> Value {
+    let mut node = match &statement.kind {
+        StatementKind::Let { name, value } => {
+            let mut node = json!({ "type": "Let" });
+            node["name"] = identifier_json(index, name);
+            node["value"] = expression_json(index, value);
+            node
+        }
+        StatementKind::Return(value) => {
+            let mut node = json!({ "type": "Return" });
+            node["value"] = expression_json(index, value);
+            node
+        }
+        StatementKind::Expression(expression) => {
+            let mut node = json!({ "type": "Expression" });
+            node["expression"] = expression_json(index, expression);
+            node
+        }
+    };
+    node["span"] = span_json(index, statement.span);
+    node
+}
+
+fn identifier_json(index: &LineIndex, identifier: &Identifier) -> Value {
+    json!({
+        "type": "Identifier",
+        "name": identifier.name.as_str(),
+        "span": span_json(index, identifier.span),
+    })
+}
+
+fn block_json(index: &LineIndex, block: &Block) -> Value {
+    let mut node = json!({ "type": "Block" });
+    node["statements"] = statements_json(index, &block.statements);
+    node["span"] = span_json(index, block.span);
+    node
+}
+
+/// 子の `Value` は `json!` に渡すと丸ごと複製されるので、節を作ってから移す
+fn expression_json(index: &LineIndex, expression: &Expression) -> Value {
+    limits::grow_stack(|| {
+        let mut node = match &expression.kind {
+            ExpressionKind::Identifier(name) => {
+                json!({ "type": "Identifier", "name": name.as_str() })
+            }
+            ExpressionKind::Integer(value) => json!({ "type": "Integer", "value": value }),
+            ExpressionKind::Boolean(value) => json!({ "type": "Boolean", "value": value }),
+            ExpressionKind::Prefix { operator, right } => {
+                let mut node = json!({ "type": "Prefix", "operator": operator.as_str() });
+                node["right"] = expression_json(index, right);
+                node
+            }
+            ExpressionKind::Infix {
+                operator,
+                left,
+                right,
+            } => {
+                let mut node = json!({ "type": "Infix", "operator": operator.as_str() });
+                node["left"] = expression_json(index, left);
+                node["right"] = expression_json(index, right);
+                node
+            }
+            ExpressionKind::If {
+                condition,
+                consequence,
+                alternative,
+            } => {
+                let mut node = json!({ "type": "If" });
+                node["condition"] = expression_json(index, condition);
+                node["consequence"] = block_json(index, consequence);
+                node["alternative"] = match alternative {
+                    Some(block) => block_json(index, block),
+                    None => Value::Null,
+                };
+                node
+            }
+            ExpressionKind::Function { parameters, body } => {
+                let mut node = json!({ "type": "Function" });
+                node["parameters"] = parameters
+                    .iter()
+                    .map(|p| identifier_json(index, p))
+                    .collect();
+                node["body"] = block_json(index, body);
+                node
+            }
+            ExpressionKind::Call {
+                function,
+                arguments,
+            } => {
+                let mut node = json!({ "type": "Call" });
+                node["function"] = expression_json(index, function);
+                node["arguments"] = arguments
+                    .iter()
+                    .map(|a| expression_json(index, a))
+                    .collect();
+                node
+            }
+        };
+        node["span"] = span_json(index, expression.span);
+        node
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ast_json, tokens_json, tokens_table};
+    use crate::{lexer, parser};
+
+    #[test]
+    fn table() {
+        let input = "let five = 5;\nfive == 5;";
+        assert_eq!(
+            tokens_table(input, &lexer::tokenize(input).0),
+            "\
+1:1     LET         let
+1:5     IDENT       five
+1:10    ASSIGN      =
+1:12    INT         5
+1:13    SEMICOLON   ;
+2:1     IDENT       five
+2:6     EQ          ==
+2:9     INT         5
+2:10    SEMICOLON   ;
+2:11    EOF
+"
+        );
+    }
+
+    #[test]
+    fn json() {
+        let input = "let x\n;";
+        let doc: serde_json::Value =
+            serde_json::from_str(&tokens_json(input, &lexer::tokenize(input).0)).unwrap();
+        assert_eq!(doc["version"], 1);
+        let tokens = doc["tokens"].as_array().unwrap();
+        assert_eq!(tokens.len(), 4);
+        assert_eq!(tokens[1]["kind"], "IDENT");
+        assert_eq!(tokens[1]["literal"], "x");
+        assert_eq!(
+            tokens[1]["span"],
+            serde_json::json!({
+                "start": { "offset": 4, "line": 1, "column": 5 },
+                "end": { "offset": 5, "line": 1, "column": 6 },
+            })
+        );
+        assert_eq!(tokens[2]["span"]["start"]["line"], 2);
+        assert_eq!(tokens[3]["kind"], "EOF");
+    }
+
+    #[test]
+    fn ast() {
+        let input = "let f = fn(x) { -x };\nlet = 1;";
+        let (program, errors) = parser::parse(input);
+        let doc: serde_json::Value =
+            serde_json::from_str(&ast_json(input, &program, &errors)).unwrap();
+        let statements = doc["program"]["statements"].as_array().unwrap();
+        assert_eq!(statements.len(), 1);
+        let function = &statements[0]["value"];
+        assert_eq!(function["type"], "Function");
+        assert_eq!(function["parameters"][0]["name"], "x");
+        let body = &function["body"]["statements"][0]["expression"];
+        assert_eq!(body["type"], "Prefix");
+        assert_eq!(body["operator"], "-");
+        assert_eq!(body["right"]["span"]["start"]["column"], 18);
+        let diagnostics = doc["diagnostics"].as_array().unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0]["code"], "E0100");
+        assert_eq!(diagnostics[0]["span"]["start"]["line"], 2);
+    }
+}
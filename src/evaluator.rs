@@ -0,0 +1,776 @@
+//! 構文木をたどって評価する。
+//!
+//! `if` のブロックはスコープを作らず、関数の呼び出しだけが新しい環境を作る。
+//! 関数の末尾での呼び出しと `return` する呼び出しは再帰せずにループで行うので、
+//! 末尾再帰はいくら深くてもよい。
+//! それ以外の呼び出しと式の入れ子は再帰するので、スタックが足りなくなればヒープに継ぎ足す。
+//! 一つの関数の中の式の入れ子は `Limits::max_nesting` までにする。
+
+use std::io::Write;
+use std::rc::Rc;
+
+use crate::ast::*;
+use crate::diagnostic::{self, Diagnostic};
+use crate::gc::{self, Heap};
+use crate::limits::{self, Budget, Limits};
+use crate::object::{Builtin, Env, Function, HostError, HostFunction, Value};
+use crate::span::Span;
+use crate::symbol::{Interner, Symbol};
+use crate::token::Operator;
+
+/// 定義されていない名前
+pub const E_UNDEFINED: &str = "E0200";
+/// 演算子がその型の値に使えない
+pub const E_TYPE_MISMATCH: &str = "E0201";
+/// 関数でない値を呼び出した
+pub const E_NOT_CALLABLE: &str = "E0202";
+/// 引数の数が仮引数の数と合わない
+pub const E_ARITY: &str = "E0203";
+/// 0 で割った
+pub const E_DIVISION_BY_ZERO: &str = "E0204";
+/// 計算結果が i64 に収まらない
+pub const E_OVERFLOW: &str = "E0205";
+/// `puts` の出力に失敗した
+pub const E_OUTPUT: &str = "E0206";
+/// 埋め込み側の関数がエラーを返した
+pub const E_HOST: &str = "E0207";
+
+/// 大域環境を持ち、続けて評価したプログラムの間で束縛を引き継ぐ
+pub struct Evaluator<W> {
+    globals: Env,
+    out: W,
+    limits: Limits,
+    budget: Budget,
+    heap: Heap,
+    /// 評価している関数の中での式の入れ子の深さ
+    depth: usize,
+    /// 埋め込み側から渡された名前と、`interner` を通して解析したプログラムの名前
+    interner: Interner,
+}
+
+/// `puts` の出力先を `out` にした評価器
+pub fn new<W: Write>(out: W) -> Evaluator<W> {
+    let mut heap = Heap::default();
+    Evaluator {
+        globals: heap.environment(None),
+        out,
+        limits: Limits::default(),
+        budget: Budget::new(Limits::default()),
+        heap,
+        depth: 0,
+        interner: Interner::default(),
+    }
+}
+
+/// 評価を途中で打ち切る理由。再帰するたびにスタックに載るので小さく保つ。
+enum Unwind {
+    Return(Value),
+    /// `return` する呼び出し。呼び出すのは関数から抜けた後にする
+    TailCall(Box<(Value, Vec<Value>, Span)>),
+    Error(Box<Diagnostic>),
+}
+
+impl From<Diagnostic> for Unwind {
+    fn from(diagnostic: Diagnostic) -> Unwind {
+        Unwind::Error(Box::new(diagnostic))
+    }
+}
+
+type Result<T> = std::result::Result<T, Unwind>;
+
+/// 末尾の式を評価した結果。呼び出しはまだ行っていない
+enum Tail {
+    Value(Value),
+    Call(Value, Vec<Value>, Span),
+}
+
+fn error<T>(code: &'static str, message: String, span: Span) -> std::result::Result<T, Diagnostic> {
+    Err(diagnostic::error(code, message, span))
+}
+
+impl<W: Write> Evaluator<W> {
+    /// 最後の文の値を返す。`return` があればその値で終える。
+    /// 上限は呼び出しごとに数え直す
+    pub fn eval_program(&mut self, program: &Program) -> std::result::Result<Value, Diagnostic> {
+        self.budget = Budget::new(self.limits);
+        self.depth = 0;
+        let env = self.globals.clone();
+        let result = match self.statements(&program.statements, &env) {
+            Err(Unwind::TailCall(call)) => {
+                let (function, arguments, span) = *call;
+                self.call(function, arguments, span)
+            }
+            result => result,
+        };
+        match result {
+            Ok(value) | Err(Unwind::Return(value)) => Ok(value),
+            Err(Unwind::Error(e)) => Err(*e),
+            Err(Unwind::TailCall(_)) => unreachable!("calls finish tail calls"),
+        }
+    }
+
+    /// 次の `eval_program` から使う上限
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn gc_stats(&self) -> gc::Stats {
+        self.heap.stats()
+    }
+
+    /// 循環して残っている環境と関数値を今すぐ解放する
+    pub fn collect_garbage(&mut self) {
+        self.heap.collect();
+    }
+
+    /// 環境や関数値を確保するたびにごみ集めをする
+    pub fn set_gc_stress(&mut self, stress: bool) {
+        self.heap.set_stress(stress);
+    }
+
+    /// 続けて評価するプログラムをこの表で解析すると、大域変数の名前の確保を共有できる
+    pub fn interner(&mut self) -> &mut Interner {
+        &mut self.interner
+    }
+
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        let name = self.interner.intern(name);
+        self.globals.borrow_mut().set(name, value);
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        // 別の表で解析したプログラムが定義した名前は、この表になくても探す
+        let name = self
+            .interner
+            .get(name)
+            .unwrap_or_else(|| Symbol::from(name));
+        self.globals.borrow().get(&name)
+    }
+
+    pub fn output(&self) -> &W {
+        &self.out
+    }
+
+    pub fn into_output(self) -> W {
+        self.out
+    }
+
+    /// 最後の文の値。`let` の値は null。
+    fn statements(&mut self, statements: &[Statement], env: &Env) -> Result<Value> {
+        let mut value = Value::Null;
+        for statement in statements {
+            value = match &statement.kind {
+                StatementKind::Let { name, value } => {
+                    let value = self.expression(value, env)?;
+                    env.borrow_mut().set(name.name.clone(), value);
+                    Value::Null
+                }
+                StatementKind::Return(_) => {
+                    return match self.tail_statements(std::slice::from_ref(statement), env)? {
+                        Tail::Value(value) => Err(Unwind::Return(value)),
+                        Tail::Call(function, arguments, span) => {
+                            Err(Unwind::TailCall(Box::new((function, arguments, span))))
+                        }
+                    }
+                }
+                StatementKind::Expression(expression) => self.expression(expression, env)?,
+            };
+        }
+        Ok(value)
+    }
+
+    fn expression(&mut self, expression: &Expression, env: &Env) -> Result<Value> {
+        if let Some(max) = self.limits.max_nesting.filter(|&max| self.depth >= max) {
+            return Err(limits::nesting_too_deep(max, expression.span).into());
+        }
+        self.depth += 1;
+        let value =
+            limits::grow_stack(|| self.expression_kind(&expression.kind, expression.span, env));
+        self.depth -= 1;
+        value
+    }
+
+    /// 左結合の連なりの左側の式や呼び出す関数。囲む式と同じ深さで評価する
+    fn chained(&mut self, expression: &Expression, env: &Env) -> Result<Value> {
+        let depth = self.depth;
+        self.depth = depth.saturating_sub(1);
+        let value = self.expression(expression, env);
+        self.depth = depth;
+        value
+    }
+
+    fn expression_kind(&mut self, kind: &ExpressionKind, span: Span, env: &Env) -> Result<Value> {
+        self.step(span)?;
+        match kind {
+            ExpressionKind::Identifier(name) => identifier(name, env, span),
+            ExpressionKind::Integer(value) => Ok(Value::Integer(*value)),
+            ExpressionKind::Boolean(value) => Ok(Value::Boolean(*value)),
+            ExpressionKind::Prefix { operator, right } => {
+                let right = self.expression(right, env)?;
+                prefix(*operator, right, span).map_err(Unwind::from)
+            }
+            ExpressionKind::Infix {
+                operator,
+                left,
+                right,
+            } => {
+                let left = self.chained(left, env)?;
+                let right = self.expression(right, env)?;
+                infix(*operator, left, right, span).map_err(Unwind::from)
+            }
+            ExpressionKind::If {
+                condition,
+                consequence,
+                alternative,
+            } => {
+                if self.expression(condition, env)?.is_truthy() {
+                    self.statements(&consequence.statements, env)
+                } else if let Some(alternative) = alternative {
+                    self.statements(&alternative.statements, env)
+                } else {
+                    Ok(Value::Null)
+                }
+            }
+            ExpressionKind::Function { parameters, body } => {
+                self.allocate(span)?;
+                let function = function(parameters, body, env);
+                Ok(Value::Function(self.heap.function(function)))
+            }
+            ExpressionKind::Call {
+                function,
+                arguments,
+            } => {
+                let (function, arguments) = self.callee(function, arguments, env)?;
+                self.call(function, arguments, span)
+            }
+        }
+    }
+
+    fn call(&mut self, function: Value, arguments: Vec<Value>, span: Span) -> Result<Value> {
+        match function {
+            Value::Function(function) => {
+                if arguments.len() != function.parameters.len() {
+                    return Err(arity_error(
+                        function.parameters.len(),
+                        arguments.len(),
+                        span,
+                    ));
+                }
+                self.allocate(span)?;
+                self.enter(span)?;
+                // 本体の末尾で関数を呼んでいれば、再帰せずにこのループで続けて呼ぶ。
+                // 末尾呼び出しは呼び出しの深さに数えない。式の入れ子は関数ごとに数える。
+                let depth = std::mem::take(&mut self.depth);
+                let (mut function, mut arguments) = (function, arguments);
+                let result = loop {
+                    let env = self.heap.environment(Some(function.env.clone()));
+                    for (parameter, argument) in function.parameters.iter().zip(arguments) {
+                        env.borrow_mut().set(parameter.clone(), argument);
+                    }
+                    let tail = match self.tail_statements(&function.body.statements, &env) {
+                        Ok(tail) => tail,
+                        Err(Unwind::Return(value)) => Tail::Value(value),
+                        Err(Unwind::TailCall(call)) => {
+                            let (function, arguments, span) = *call;
+                            Tail::Call(function, arguments, span)
+                        }
+                        Err(e) => break Err(e),
+                    };
+                    match tail {
+                        Tail::Value(value) => break Ok(value),
+                        Tail::Call(Value::Function(next), next_arguments, span) => {
+                            if next_arguments.len() != next.parameters.len() {
+                                let expected = next.parameters.len();
+                                break Err(arity_error(expected, next_arguments.len(), span));
+                            }
+                            if let Err(e) = self.allocate(span) {
+                                break Err(e);
+                            }
+                            function = next;
+                            arguments = next_arguments;
+                        }
+                        Tail::Call(other, arguments, span) => {
+                            break self.call(other, arguments, span)
+                        }
+                    }
+                };
+                self.depth = depth;
+                self.budget.leave();
+                result
+            }
+            Value::Builtin(Builtin::Puts) => Ok(puts(&mut self.out, arguments, span)?),
+            Value::Host(host) => Ok(call_host(&host, arguments, span)?),
+            value => Err(not_callable(&value, span).into()),
+        }
+    }
+
+    // 上限の確認。`Diagnostic` を呼び出し元のフレームに置かないように、ここで `Unwind` にする
+
+    fn step(&mut self, span: Span) -> Result<()> {
+        Ok(self.budget.step(span)?)
+    }
+
+    fn allocate(&mut self, span: Span) -> Result<()> {
+        Ok(self.budget.allocate(&mut self.heap, span)?)
+    }
+
+    fn enter(&mut self, span: Span) -> Result<()> {
+        Ok(self.budget.enter(span)?)
+    }
+
+    fn callee(
+        &mut self,
+        function: &Expression,
+        arguments: &[Expression],
+        env: &Env,
+    ) -> Result<(Value, Vec<Value>)> {
+        let function = self.chained(function, env)?;
+        let mut values = Vec::with_capacity(arguments.len());
+        for argument in arguments {
+            values.push(self.expression(argument, env)?);
+        }
+        Ok((function, values))
+    }
+
+    /// 関数の本体のように、値がそのまま関数の値になる文の並び。
+    /// 末尾の呼び出しは評価せずに返す。末尾の `if` は選んだ枝をそのまま続けて見る。
+    fn tail_statements(&mut self, mut statements: &[Statement], env: &Env) -> Result<Tail> {
+        loop {
+            let Some((last, rest)) = statements.split_last() else {
+                return Ok(Tail::Value(Value::Null));
+            };
+            for statement in rest {
+                if let StatementKind::Return(_) = statement.kind {
+                    return self.tail_statements(std::slice::from_ref(statement), env);
+                }
+                self.statements(std::slice::from_ref(statement), env)?;
+            }
+            let expression = match &last.kind {
+                StatementKind::Return(value) | StatementKind::Expression(value) => value,
+                StatementKind::Let { .. } => {
+                    return self
+                        .statements(std::slice::from_ref(last), env)
+                        .map(Tail::Value)
+                }
+            };
+            match &expression.kind {
+                ExpressionKind::Call {
+                    function,
+                    arguments,
+                } => {
+                    self.step(expression.span)?;
+                    let (function, arguments) = self.callee(function, arguments, env)?;
+                    return Ok(Tail::Call(function, arguments, expression.span));
+                }
+                ExpressionKind::If {
+                    condition,
+                    consequence,
+                    alternative,
+                } => {
+                    self.step(expression.span)?;
+                    statements = if self.expression(condition, env)?.is_truthy() {
+                        &consequence.statements
+                    } else if let Some(alternative) = alternative {
+                        &alternative.statements
+                    } else {
+                        return Ok(Tail::Value(Value::Null));
+                    };
+                }
+                _ => return self.expression(expression, env).map(Tail::Value),
+            }
+        }
+    }
+}
+
+// 式の評価は再帰するので、枝ごとの一時的な値は別の関数に分けてフレームを小さく保つ
+
+fn identifier(name: &Symbol, env: &Env, span: Span) -> Result<Value> {
+    match env.borrow().get(name) {
+        Some(value) => Ok(value),
+        None => match Builtin::lookup(name.as_str()) {
+            Some(builtin) => Ok(Value::Builtin(builtin)),
+            None => Err(undefined(name, span).into()),
+        },
+    }
+}
+
+fn function(parameters: &[Identifier], body: &Rc<Block>, env: &Env) -> Function {
+    Function {
+        parameters: parameters.iter().map(|p| p.name.clone()).collect(),
+        body: body.clone(),
+        env: env.clone(),
+    }
+}
+
+/// 引数を一行にひとつずつ書き出す。仮想機械と共有する。
+pub(crate) fn puts(
+    out: &mut impl Write,
+    arguments: Vec<Value>,
+    span: Span,
+) -> std::result::Result<Value, Diagnostic> {
+    for argument in arguments {
+        if let Err(e) = writeln!(out, "{}", argument) {
+            return Err(diagnostic::error(
+                E_OUTPUT,
+                format!("failed to write output: {}", e),
+                span,
+            ));
+        }
+    }
+    Ok(Value::Null)
+}
+
+pub(crate) fn call_host(
+    host: &HostFunction,
+    arguments: Vec<Value>,
+    span: Span,
+) -> std::result::Result<Value, Diagnostic> {
+    (host.function)(arguments).map_err(|e| match e {
+        HostError::Arity { expected, found } => arity(expected, found, span),
+        HostError::Argument {
+            index,
+            expected,
+            found,
+        } => diagnostic::error(
+            E_TYPE_MISMATCH,
+            format!(
+                "argument {} of `{}` must be {}, found {}",
+                index + 1,
+                host.name,
+                expected,
+                found
+            ),
+            span,
+        ),
+        HostError::Failed(message) => {
+            diagnostic::error(E_HOST, format!("`{}` failed: {}", host.name, message), span)
+        }
+    })
+}
+
+fn arity_error(expected: usize, found: usize, span: Span) -> Unwind {
+    arity(expected, found, span).into()
+}
+
+pub(crate) fn arity(expected: usize, found: usize, span: Span) -> Diagnostic {
+    diagnostic::error(
+        E_ARITY,
+        format!(
+            "wrong number of arguments: expected {}, found {}",
+            expected, found
+        ),
+        span,
+    )
+}
+
+pub(crate) fn not_callable(value: &Value, span: Span) -> Diagnostic {
+    diagnostic::error(
+        E_NOT_CALLABLE,
+        format!("cannot call a value of type {}", value.type_name()),
+        span,
+    )
+}
+
+pub(crate) fn undefined(name: &Symbol, span: Span) -> Diagnostic {
+    diagnostic::error(E_UNDEFINED, format!("unknown identifier `{}`", name), span)
+}
+
+pub(crate) fn prefix(
+    operator: Operator,
+    right: Value,
+    span: Span,
+) -> std::result::Result<Value, Diagnostic> {
+    match (operator, right) {
+        (Operator::Not, right) => Ok(Value::Boolean(!right.is_truthy())),
+        (Operator::Minus, Value::Integer(value)) => match value.checked_neg() {
+            Some(value) => Ok(Value::Integer(value)),
+            None => error(E_OVERFLOW, "integer overflow".to_string(), span),
+        },
+        (operator, right) => error(
+            E_TYPE_MISMATCH,
+            format!(
+                "unsupported operand type for `{}`: {}",
+                operator.as_str(),
+                right.type_name()
+            ),
+            span,
+        ),
+    }
+}
+
+pub(crate) fn infix(
+    operator: Operator,
+    left: Value,
+    right: Value,
+    span: Span,
+) -> std::result::Result<Value, Diagnostic> {
+    let (l, r) = match (&left, &right) {
+        (Value::Integer(l), Value::Integer(r)) => (*l, *r),
+        _ => {
+            return match operator {
+                Operator::Eq if left.type_name() == right.type_name() => {
+                    Ok(Value::Boolean(left == right))
+                }
+                Operator::NotEq if left.type_name() == right.type_name() => {
+                    Ok(Value::Boolean(left != right))
+                }
+                _ => error(
+                    E_TYPE_MISMATCH,
+                    format!(
+                        "unsupported operand types for `{}`: {} and {}",
+                        operator.as_str(),
+                        left.type_name(),
+                        right.type_name()
+                    ),
+                    span,
+                ),
+            }
+        }
+    };
+    let value = match operator {
+        Operator::Plus => l.checked_add(r),
+        Operator::Minus => l.checked_sub(r),
+        Operator::Mul => l.checked_mul(r),
+        Operator::Div if r == 0 => {
+            return error(E_DIVISION_BY_ZERO, "division by zero".to_string(), span)
+        }
+        Operator::Div => l.checked_div(r),
+        Operator::Lt => return Ok(Value::Boolean(l < r)),
+        Operator::Gt => return Ok(Value::Boolean(l > r)),
+        Operator::Eq => return Ok(Value::Boolean(l == r)),
+        Operator::NotEq => return Ok(Value::Boolean(l != r)),
+        Operator::Assign | Operator::Not => unreachable!("not an infix operator"),
+    };
+    match value {
+        Some(value) => Ok(Value::Integer(value)),
+        None => error(E_OVERFLOW, "integer overflow".to_string(), span),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::object::Value;
+    use crate::parser;
+
+    fn eval(input: &str) -> (Result<Value, &'static str>, String) {
+        let (program, errors) = parser::parse(input);
+        assert_eq!(errors, vec![]);
+        let mut e = super::new(vec![]);
+        let value = e.eval_program(&program).map_err(|d| d.code);
+        (value, String::from_utf8(e.into_output()).unwrap())
+    }
+
+    #[test]
+    fn values() {
+        let cases = [
+            ("1 + 2 * 3 - 4 / 2", Value::Integer(5)),
+            ("-5 < 3 == !false", Value::Boolean(true)),
+            ("if (0) { 1 }", Value::Integer(1)),
+            ("if (1 > 2) { 1 }", Value::Null),
+            ("let x = 1; x", Value::Integer(1)),
+            ("return 2; 3", Value::Integer(2)),
+            ("if (true) { if (true) { return 1; } 2 }", Value::Integer(1)),
+            (
+                "let add = fn(a) { fn(b) { a + b } }; add(2)(3)",
+                Value::Integer(5),
+            ),
+            (
+                "let f = fn(n) { if (n < 2) { return n; } f(n - 1) + f(n - 2) }; f(10)",
+                Value::Integer(55),
+            ),
+            ("true == true", Value::Boolean(true)),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(eval(input).0, Ok(expected), "{}", input);
+        }
+    }
+
+    /// 関数値は本体を写さず、構文木と共有する
+    #[test]
+    fn function_body_shared() {
+        let (program, _) = parser::parse("fn(x) { x }");
+        let crate::ast::StatementKind::Expression(expression) = &program.statements[0].kind else {
+            panic!("expected an expression");
+        };
+        let crate::ast::ExpressionKind::Function { body, .. } = &expression.kind else {
+            panic!("expected a function");
+        };
+        let mut e = super::new(vec![]);
+        for _ in 0..2 {
+            let Ok(Value::Function(f)) = e.eval_program(&program) else {
+                panic!("expected a function value");
+            };
+            assert!(std::rc::Rc::ptr_eq(&f.body, body));
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let cases = [
+            ("x", super::E_UNDEFINED),
+            ("1 + true", super::E_TYPE_MISMATCH),
+            ("-true", super::E_TYPE_MISMATCH),
+            ("1 == true", super::E_TYPE_MISMATCH),
+            ("1(2)", super::E_NOT_CALLABLE),
+            ("fn(a) { a }()", super::E_ARITY),
+            ("1 / 0", super::E_DIVISION_BY_ZERO),
+            ("9223372036854775807 + 1", super::E_OVERFLOW),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(eval(input).0, Err(expected), "{}", input);
+        }
+    }
+
+    #[test]
+    fn limits() {
+        use crate::limits::*;
+        use std::time::Duration;
+
+        let run = |input: &str, limits: Limits| {
+            let (program, _) = parser::parse(input);
+            let mut e = super::new(vec![]);
+            e.set_limits(limits);
+            e.eval_program(&program).map_err(|d| d.code)
+        };
+        let forever = "let f = fn() { 1 + f() }; f()";
+        let slow = "let f = fn(n) { if (n < 2) { n } else { f(n - 1) + f(n - 2) } }; f(40)";
+
+        assert_eq!(run(forever, Limits::default()), Err(E_CALL_DEPTH));
+        // 既定の上限は普通の再帰には十分深く、ホストのスタックも溢れない
+        let sum = "let sum = fn(n) { if (n == 0) { 0 } else { n + sum(n - 1) } }; sum(5000)";
+        assert_eq!(run(sum, Limits::default()), Ok(Value::Integer(12502500)));
+        let fuel = Limits {
+            fuel: Some(1000),
+            ..Limits::default()
+        };
+        assert_eq!(run("1 + 2", fuel), Ok(Value::Integer(3)));
+        assert_eq!(run(slow, fuel), Err(E_FUEL_EXHAUSTED));
+        let objects = Limits {
+            max_objects: Some(50),
+            ..Limits::default()
+        };
+        // 呼び出しの環境はすぐにごみになるので、上限より多く確保しても数えない
+        let garbage = "let f = fn(n) { if (n == 0) { 0 } else { f(n - 1) } }; f(1000)";
+        assert_eq!(run(garbage, objects), Ok(Value::Integer(0)));
+        let kept =
+            "let f = fn(n, g) { if (n == 0) { 0 } else { f(n - 1, fn() { g }) } }; f(100, 0)";
+        assert_eq!(run(kept, objects), Err(E_OBJECT_LIMIT));
+        let timeout = Limits {
+            timeout: Some(Duration::from_millis(20)),
+            ..Limits::default()
+        };
+        assert_eq!(run(slow, timeout), Err(E_DEADLINE));
+    }
+
+    /// 解析できる深さの式は評価でき、手で組んだ深すぎる構文木はたどらずにエラーにする。
+    /// 最適化器は深すぎる部分をそのまま残す
+    #[test]
+    fn nesting_limit() {
+        use crate::ast::*;
+        use crate::limits::{Limits, DEFAULT_MAX_NESTING, E_NESTING, MAX_EXPRESSION_HEIGHT};
+        use crate::optimizer;
+        use crate::span::Span;
+        use crate::token::Operator;
+
+        let input = format!("{}1", "-".repeat(DEFAULT_MAX_NESTING - 1));
+        assert_eq!(eval(&input).0, Ok(Value::Integer(-1)));
+        // 左結合の連なりは入れ子に数えない
+        let n = MAX_EXPRESSION_HEIGHT - 1;
+        let input = format!("1{}", " + 1".repeat(n));
+        assert_eq!(eval(&input).0, Ok(Value::Integer(n as i64 + 1)));
+        let input = format!("let f = fn(x) {{ f }}; f{}; 1", "(1)".repeat(n));
+        assert_eq!(eval(&input).0, Ok(Value::Integer(1)));
+
+        let span = Span { start: 0, end: 1 };
+        let mut expression = Expression {
+            kind: ExpressionKind::Integer(1),
+            span,
+        };
+        for _ in 0..DEFAULT_MAX_NESTING * 2 {
+            expression = Expression {
+                kind: ExpressionKind::Prefix {
+                    operator: Operator::Minus,
+                    right: Box::new(expression),
+                },
+                span,
+            };
+        }
+        let program = Program {
+            statements: vec![Statement {
+                kind: StatementKind::Expression(expression),
+                span,
+            }],
+        };
+        for program in [program.clone(), optimizer::optimize(program.clone())] {
+            let error = super::new(vec![]).eval_program(&program).unwrap_err();
+            assert_eq!(error.code, E_NESTING);
+        }
+        // 上限を外せば深くても評価できる
+        let mut e = super::new(vec![]);
+        e.set_limits(Limits {
+            max_nesting: None,
+            ..Limits::default()
+        });
+        assert_eq!(e.eval_program(&program), Ok(Value::Integer(1)));
+    }
+
+    #[test]
+    fn puts() {
+        let (value, output) = eval("puts(1, true); puts(fn(a, b) { a }, puts)");
+        assert_eq!(value, Ok(Value::Null));
+        assert_eq!(output, "1\ntrue\nfn(a, b) { ... }\nbuiltin puts\n");
+    }
+
+    /// 埋め込み側の名前は評価器の表を通すが、別の表で解析したプログラムとも引き合える
+    #[test]
+    fn globals() {
+        let mut e = super::new(vec![]);
+        e.set_global("limit", Value::Integer(10));
+        let (program, _) = parser::parse_with("let x = limit + 1;", e.interner(), None);
+        e.eval_program(&program).unwrap();
+        let (program, _) = parser::parse("let y = x + limit;");
+        e.eval_program(&program).unwrap();
+        assert_eq!(e.get_global("x"), Some(Value::Integer(11)));
+        assert_eq!(e.get_global("y"), Some(Value::Integer(21)));
+        assert_eq!(e.get_global("z"), None);
+        assert!(e.interner().get("y").is_none());
+    }
+
+    /// 末尾呼び出しは呼び出しの深さの上限を超えても続けられる
+    #[test]
+    fn tail_calls() {
+        let cases = [
+            (
+                "let loop = fn(n) { if (n == 0) { return 0; } loop(n - 1) }; loop(100000)",
+                Ok(Value::Integer(0)),
+            ),
+            (
+                "let even = fn(n) { if (n == 0) { true } else { odd(n - 1) } };
+                 let odd = fn(n) { if (n == 0) { false } else { even(n - 1) } };
+                 even(10001)",
+                Ok(Value::Boolean(false)),
+            ),
+            (
+                "let sum = fn(n, acc) { if (n == 0) { return acc; } return sum(n - 1, acc + n); }; sum(10000, 0)",
+                Ok(Value::Integer(50005000)),
+            ),
+            ("let f = fn(n) { if (n == 0) { puts(n) } else { f(n - 1) } }; f(1000)", Ok(Value::Null)),
+            // 末尾にない `if` や式の中の `return` も末尾呼び出しになる
+            (
+                "let loop = fn(n) { if (n > 0) { return loop(n - 1); } 0 }; loop(100000)",
+                Ok(Value::Integer(0)),
+            ),
+            (
+                "let loop = fn(n) { 1 + if (n > 0) { return loop(n - 1); } else { -1 } }; loop(100000)",
+                Ok(Value::Integer(0)),
+            ),
+            ("let f = fn() { return puts(1); 2 }; f()", Ok(Value::Null)),
+            ("let f = fn(n) { n }; return f(2); 3", Ok(Value::Integer(2))),
+            ("let f = fn(n) { g(n, n) }; let g = fn(n) { n }; f(1)", Err(super::E_ARITY)),
+            ("let f = fn(n) { 1 + f(n + 1) }; f(0)", Err(crate::limits::E_CALL_DEPTH)),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(eval(input).0, expected, "{}", input);
+        }
+    }
+}
@@ -0,0 +1,69 @@
+//! `learn-interpreter fmt` の整形。構文木を印字し直し、コメントは元の位置の近くに戻す。
+
+use crate::diagnostic::{self, Diagnostic};
+use crate::span::LineIndex;
+use crate::symbol::Interner;
+use crate::{lexer, parser, printer};
+
+/// 整形したソースを返す。構文エラーがあると文が失われるので整形しない。
+pub fn format(input: &str) -> Result<String, Vec<Diagnostic>> {
+    let mut p = parser::new(lexer::borrowed(input).with_interner(Interner::default()));
+    let program = p.parse_program();
+    let errors = p.errors();
+    if diagnostic::has_errors(&errors) {
+        return Err(errors);
+    }
+    Ok(printer::print_program_with_comments(
+        &program,
+        p.comments(),
+        &LineIndex::new(input),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::format;
+
+    #[test]
+    fn canonical_style() {
+        let input = "
+// 足し算
+let add=fn(x,y){x+y};   // 二つ足す
+
+
+let r = add(1,2); let s = if(r>2){
+  // 大きい
+      true} else {false} // 比べる
+// おわり
+";
+        let expected = "\
+// 足し算
+let add = fn(x, y) {
+    x + y;
+}; // 二つ足す
+
+let r = add(1, 2);
+let s = if (r > 2) {
+    // 大きい
+    true;
+} else {
+    false;
+}; // 比べる
+// おわり
+";
+        assert_eq!(format(input).unwrap(), expected);
+        assert_eq!(format(expected).unwrap(), expected);
+    }
+
+    #[test]
+    fn comment_in_empty_block() {
+        let input = "let f = fn() {\n    // todo\n};\n";
+        assert_eq!(format(input).unwrap(), input);
+    }
+
+    #[test]
+    fn syntax_error() {
+        let errors = format("let = 1;").unwrap_err();
+        assert_eq!(errors.len(), 1);
+    }
+}
@@ -0,0 +1,196 @@
+//! 字句解析器と構文解析器のファジングの対象。
+//!
+//! どちらもバイト列を受け取り、性質が崩れていれば panic する。
+//! `cargo test fuzz` で `fuzz/corpus/<対象>/` の入力とランダムな入力に対して実行する。
+//! libFuzzer で探すときは `fuzz/` で `cargo fuzz run lexer` のように実行する (nightly が必要)。
+
+use std::io::Read;
+
+use crate::diagnostic::Diagnostic;
+use crate::span::Span;
+use crate::token::{Special, TokenType};
+use crate::{formatter, lexer, parser};
+
+/// 字句解析の性質を確かめる
+///
+/// - 終了し、EOF 以外のトークンは少なくとも一文字を読む
+/// - 範囲は入力の中に収まり、位置順に重ならず並ぶ
+/// - トークンのリテラルと、その間の空白・コメントをつなげると入力に戻る
+/// - 少しずつ読む `StreamLexer` も同じトークンを返す
+pub fn lexer(data: &[u8]) {
+    let input = String::from_utf8_lossy(data);
+    let chars: Vec<char> = input.chars().collect();
+    let text = |start: usize, end: usize| chars[start..end].iter().collect::<String>();
+
+    let mut l = lexer::borrowed(&input);
+    let mut tokens = vec![];
+    loop {
+        assert!(tokens.len() <= chars.len(), "lexer did not terminate");
+        let (tok, span) = l.next_token_with_span();
+        tokens.push((tok.to_token(), span));
+        if tok.token_type == TokenType::Special(Special::EOF) {
+            assert_eq!(
+                span,
+                Span {
+                    start: chars.len(),
+                    end: chars.len()
+                }
+            );
+            break;
+        }
+        assert!(
+            span.start < span.end && span.end <= chars.len(),
+            "{:?}",
+            span
+        );
+        assert_eq!(text(span.start, span.end), tok.literal);
+    }
+    check_spans(l.errors(), chars.len());
+
+    let mut rebuilt = String::new();
+    let mut offset = 0;
+    let mut comments = l.comments().iter().peekable();
+    for (tok, span) in &tokens {
+        assert!(offset <= span.start, "overlapping token at {:?}", span);
+        // トークンの間は空白とコメントだけ
+        while let Some(comment) = comments.next_if(|c| c.span.end <= span.start) {
+            let gap = text(offset, comment.span.start);
+            assert!(gap.chars().all(char::is_whitespace), "{:?}", gap);
+            assert!(text(comment.span.start, comment.span.end).starts_with(&comment.text));
+            rebuilt += &gap;
+            rebuilt += &text(comment.span.start, comment.span.end);
+            offset = comment.span.end;
+        }
+        let gap = text(offset, span.start);
+        assert!(gap.chars().all(char::is_whitespace), "{:?}", gap);
+        rebuilt += &gap;
+        rebuilt += &tok.literal;
+        offset = span.end;
+    }
+    assert!(comments.next().is_none());
+    assert_eq!(rebuilt, input);
+
+    let size = data.first().map_or(1, |b| *b as usize % 16 + 1);
+    let mut stream = lexer::from_reader(Chunked {
+        bytes: input.as_bytes(),
+        size,
+    });
+    for expected in &tokens {
+        assert_eq!(&stream.next_token_with_span().unwrap(), expected);
+    }
+    assert_eq!(stream.errors(), l.errors());
+    assert_eq!(stream.comments(), l.comments());
+}
+
+/// 構文解析の性質を確かめる
+///
+/// - 終了し、診断の範囲は入力の中に収まる
+/// - エラーがなければ整形でき、整形した結果をもう一度整形しても変わらない
+pub fn parser(data: &[u8]) {
+    let input = String::from_utf8_lossy(data);
+    let (_, errors) = parser::parse(&input);
+    check_spans(&errors, input.chars().count());
+    if let Ok(formatted) = formatter::format(&input) {
+        assert_eq!(formatter::format(&formatted), Ok(formatted));
+    }
+}
+
+fn check_spans(diagnostics: &[Diagnostic], len: usize) {
+    for d in diagnostics {
+        assert!(d.span.start <= d.span.end && d.span.end <= len, "{:?}", d);
+    }
+}
+
+/// 一度に `size` バイトまでしか返さない。字句解析器のテストでも使う
+pub(crate) struct Chunked<'a> {
+    pub(crate) bytes: &'a [u8],
+    pub(crate) size: usize,
+}
+
+impl Read for Chunked<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.size.min(buf.len()).min(self.bytes.len());
+        buf[..n].copy_from_slice(&self.bytes[..n]);
+        self.bytes = &self.bytes[n..];
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use proptest::prelude::*;
+
+    fn corpus(target: &str) -> Vec<Vec<u8>> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("fuzz/corpus")
+            .join(target);
+        let mut inputs = vec![];
+        for entry in fs::read_dir(dir).unwrap() {
+            inputs.push(fs::read(entry.unwrap().path()).unwrap());
+        }
+        assert!(!inputs.is_empty());
+        inputs
+    }
+
+    #[test]
+    fn fuzz_corpus() {
+        for input in corpus("lexer") {
+            super::lexer(&input);
+        }
+        for input in corpus("parser") {
+            super::parser(&input);
+        }
+    }
+
+    /// それらしいプログラムの断片を並べた入力
+    fn source() -> impl Strategy<Value = String> {
+        let piece = prop_oneof![
+            Just("let "),
+            Just("fn"),
+            Just("if"),
+            Just("else"),
+            Just("return "),
+            Just("true"),
+            Just("x"),
+            Just("1"),
+            Just("5abc"),
+            Just("="),
+            Just("=="),
+            Just("!"),
+            Just("+"),
+            Just("-"),
+            Just("*"),
+            Just("/"),
+            Just("<"),
+            Just(","),
+            Just(";"),
+            Just("("),
+            Just(")"),
+            Just("{"),
+            Just("}"),
+            Just(" "),
+            Just("\n"),
+            Just("// c\n"),
+            Just("é"),
+            Just("$"),
+        ];
+        prop::collection::vec(piece, 0..40).prop_map(|pieces| pieces.concat())
+    }
+
+    proptest! {
+        #[test]
+        fn fuzz_bytes(data in prop::collection::vec(any::<u8>(), 0..64)) {
+            super::lexer(&data);
+            super::parser(&data);
+        }
+
+        #[test]
+        fn fuzz_source(input in source()) {
+            super::lexer(input.as_bytes());
+            super::parser(input.as_bytes());
+        }
+    }
+}
@@ -0,0 +1,444 @@
+//! 環境と関数値のごみ集め。
+//!
+//! 値は `Rc` で持つので、循環していなければ参照がなくなった時点で解放される。
+//! `let f = fn() { f };` のように関数が自分を束縛した環境を閉じ込めると循環して残るので、
+//! 確保したものを `Heap` に覚えておき、確保が増えたら mark-sweep で集める。
+//!
+//! 根は列挙しない。強参照の数から覚えているもの同士の参照の数を引き、残りがあるものは
+//! 外 (評価中の Rust のスタックや埋め込み側) から参照されているとみなしてそこからたどる。
+//! たどれなかったものは環境とセルを空にして循環を切り、あとは `Rc` に解放させる。
+//! 今の言語にはコレクションの値がないので、集めるのは環境・関数値・クロージャ・セルだけ。
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::mem::size_of;
+use std::rc::{Rc, Weak};
+
+use crate::object::{Cell, Closure, Env, Environment, Function, Value};
+use crate::symbol::Symbol;
+
+/// 最初に集めるまでに確保する数。以降は前に集めたときに生きていた数まで確保したら集める。
+const INITIAL_THRESHOLD: usize = 1024;
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Stats {
+    /// ごみ集めをした回数
+    pub gc_collections: usize,
+    /// 生きているオブジェクトの数
+    pub heap_objects: usize,
+    /// 生きているオブジェクトが使っているおおよそのバイト数。
+    /// 関数の本体の構文木や仮想機械の関数はプログラムと共有していて値ごとには持たないので、
+    /// その参照の分だけを数える。
+    pub heap_bytes: usize,
+}
+
+/// 評価器と仮想機械がひとつずつ持つ
+pub struct Heap {
+    objects: Vec<Object>,
+    /// 前に集めてから確保した数
+    allocated: usize,
+    threshold: usize,
+    collections: usize,
+    stress: bool,
+}
+
+enum Object {
+    Env(Weak<RefCell<Environment>>),
+    Function(Weak<Function>),
+    Closure(Weak<Closure>),
+    Cell(Weak<RefCell<Option<Value>>>),
+}
+
+/// 集めている間だけ強参照で持つ
+enum Node {
+    Env(Env),
+    Function(Rc<Function>),
+    Closure(Rc<Closure>),
+    Cell(Cell),
+}
+
+impl Default for Heap {
+    fn default() -> Heap {
+        Heap {
+            objects: Vec::new(),
+            allocated: 0,
+            threshold: INITIAL_THRESHOLD,
+            collections: 0,
+            stress: false,
+        }
+    }
+}
+
+impl Heap {
+    /// `outer` がなければ大域環境
+    pub fn environment(&mut self, outer: Option<Env>) -> Env {
+        let env = match outer {
+            Some(outer) => Environment::enclosed(outer),
+            None => Environment::new(),
+        };
+        self.track(Object::Env(Rc::downgrade(&env)));
+        env
+    }
+
+    pub fn function(&mut self, function: Function) -> Rc<Function> {
+        let function = Rc::new(function);
+        self.track(Object::Function(Rc::downgrade(&function)));
+        function
+    }
+
+    pub fn closure(&mut self, closure: Closure) -> Rc<Closure> {
+        let closure = Rc::new(closure);
+        self.track(Object::Closure(Rc::downgrade(&closure)));
+        closure
+    }
+
+    pub fn cell(&mut self, value: Option<Value>) -> Cell {
+        let cell = Rc::new(RefCell::new(value));
+        self.track(Object::Cell(Rc::downgrade(&cell)));
+        cell
+    }
+
+    /// 確保するたびに集める。集め漏れや集めすぎをテストで見つけるためのもの
+    pub fn set_stress(&mut self, stress: bool) {
+        self.stress = stress;
+    }
+
+    /// 覚えているオブジェクトの数。解放されたものも次に集めるまでは数えるので、生きている数より少なくはならない
+    pub fn tracked(&self) -> usize {
+        self.objects.len()
+    }
+
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats {
+            gc_collections: self.collections,
+            ..Stats::default()
+        };
+        for node in self.objects.iter().filter_map(Object::upgrade) {
+            stats.heap_objects += 1;
+            stats.heap_bytes += node.size();
+        }
+        stats
+    }
+
+    fn track(&mut self, object: Object) {
+        self.objects.push(object);
+        self.allocated += 1;
+        if self.stress || self.allocated >= self.threshold {
+            self.collect();
+        }
+    }
+
+    /// 外から参照されているものからたどれないものを解放する
+    #[inline(never)]
+    pub fn collect(&mut self) {
+        let nodes: Vec<Node> = self.objects.iter().filter_map(Object::upgrade).collect();
+        let index: HashMap<*const (), usize> = nodes
+            .iter()
+            .enumerate()
+            .map(|(i, node)| (node.address(), i))
+            .collect();
+
+        // 外からの参照の数。`nodes` が持っているひとつは除く
+        let mut external: Vec<usize> = nodes.iter().map(|node| node.strong_count() - 1).collect();
+        let mut marked = vec![false; nodes.len()];
+        for (i, node) in nodes.iter().enumerate() {
+            let traced = node.children(|child| {
+                if let Some(&j) = index.get(&child) {
+                    external[j] -= 1;
+                }
+            });
+            // 借用中で中を見られないものは生きているとみなす。
+            // 中から参照しているものは数を引かないので、それらも生きていることになる。
+            if !traced {
+                marked[i] = true;
+            }
+        }
+
+        let mut stack = Vec::new();
+        for i in 0..nodes.len() {
+            if external[i] > 0 {
+                marked[i] = true;
+            }
+            if marked[i] {
+                stack.push(i);
+            }
+        }
+        while let Some(i) = stack.pop() {
+            nodes[i].children(|child| {
+                if let Some(&j) = index.get(&child) {
+                    if !marked[j] {
+                        marked[j] = true;
+                        stack.push(j);
+                    }
+                }
+            });
+        }
+
+        for (node, marked) in nodes.iter().zip(&marked) {
+            if !marked {
+                node.clear();
+            }
+        }
+        drop(nodes);
+        self.objects.retain(|object| object.is_alive());
+        self.collections += 1;
+        self.allocated = 0;
+        self.threshold = INITIAL_THRESHOLD.max(self.objects.len());
+    }
+}
+
+impl Object {
+    fn upgrade(&self) -> Option<Node> {
+        match self {
+            Object::Env(env) => env.upgrade().map(Node::Env),
+            Object::Function(function) => function.upgrade().map(Node::Function),
+            Object::Closure(closure) => closure.upgrade().map(Node::Closure),
+            Object::Cell(cell) => cell.upgrade().map(Node::Cell),
+        }
+    }
+
+    fn is_alive(&self) -> bool {
+        match self {
+            Object::Env(env) => env.strong_count() > 0,
+            Object::Function(function) => function.strong_count() > 0,
+            Object::Closure(closure) => closure.strong_count() > 0,
+            Object::Cell(cell) => cell.strong_count() > 0,
+        }
+    }
+}
+
+impl Node {
+    fn address(&self) -> *const () {
+        match self {
+            Node::Env(env) => address(env),
+            Node::Function(function) => address(function),
+            Node::Closure(closure) => address(closure),
+            Node::Cell(cell) => address(cell),
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Node::Env(env) => Rc::strong_count(env),
+            Node::Function(function) => Rc::strong_count(function),
+            Node::Closure(closure) => Rc::strong_count(closure),
+            Node::Cell(cell) => Rc::strong_count(cell),
+        }
+    }
+
+    /// 強参照で持っているものを `f` に渡す。借用中で中を見られなければ `false`
+    fn children(&self, mut f: impl FnMut(*const ())) -> bool {
+        match self {
+            Node::Env(env) => {
+                let Ok(env) = env.try_borrow() else {
+                    return false;
+                };
+                for value in env.values() {
+                    value_child(value, &mut f);
+                }
+                if let Some(outer) = env.outer() {
+                    f(address(outer));
+                }
+            }
+            Node::Function(function) => f(address(&function.env)),
+            Node::Closure(closure) => {
+                for cell in &closure.free {
+                    f(address(cell));
+                }
+            }
+            Node::Cell(cell) => {
+                let Ok(cell) = cell.try_borrow() else {
+                    return false;
+                };
+                if let Some(value) = &*cell {
+                    value_child(value, &mut f);
+                }
+            }
+        }
+        true
+    }
+
+    /// 循環を切る。関数値とクロージャは環境とセルを通してしか循環しないので、そのままでよい
+    fn clear(&self) {
+        match self {
+            Node::Env(env) => {
+                if let Ok(mut env) = env.try_borrow_mut() {
+                    env.clear();
+                }
+            }
+            Node::Cell(cell) => {
+                let value = cell.try_borrow_mut().ok().and_then(|mut cell| cell.take());
+                drop(value);
+            }
+            Node::Function(_) | Node::Closure(_) => {}
+        }
+    }
+
+    fn size(&self) -> usize {
+        match self {
+            Node::Env(env) => {
+                let bindings = env.try_borrow().map_or(0, |env| env.capacity());
+                size_of::<RefCell<Environment>>() + bindings * size_of::<(Symbol, Value)>()
+            }
+            // 本体は `Rc<Block>` で構文木と共有しているので `Function` の中の参照だけ
+            Node::Function(function) => {
+                size_of::<Function>() + function.parameters.capacity() * size_of::<Symbol>()
+            }
+            Node::Closure(closure) => {
+                size_of::<Closure>() + closure.free.capacity() * size_of::<Cell>()
+            }
+            Node::Cell(_) => size_of::<RefCell<Option<Value>>>(),
+        }
+    }
+}
+
+fn address<T>(rc: &Rc<T>) -> *const () {
+    Rc::as_ptr(rc) as *const ()
+}
+
+fn value_child(value: &Value, f: &mut impl FnMut(*const ())) {
+    match value {
+        Value::Function(function) => f(address(function)),
+        Value::Closure(closure) => f(address(closure)),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use crate::object::Value;
+    use crate::symbol::Symbol;
+    use crate::{compiler, evaluator, parser};
+
+    const CYCLE: &str = "let make = fn() { let g = fn() { g }; g }; make()";
+
+    /// 評価器と仮想機械で実行した値を返す
+    fn run(input: &str, stress: bool) -> [Result<Value, &'static str>; 2] {
+        let (program, errors) = parser::parse(input);
+        assert_eq!(errors, vec![]);
+        let mut e = evaluator::new(vec![]);
+        e.set_gc_stress(stress);
+        let bytecode = compiler::new().compile(&program).unwrap();
+        let mut vm = crate::vm::new(vec![]);
+        vm.set_gc_stress(stress);
+        [
+            e.eval_program(&program).map_err(|d| d.code),
+            vm.run(&bytecode).map_err(|d| d.code),
+        ]
+    }
+
+    #[test]
+    fn cycles() {
+        let (program, _) = parser::parse(CYCLE);
+        let mut e = evaluator::new(vec![]);
+        let g = match e.eval_program(&program) {
+            Ok(Value::Function(g)) => Rc::downgrade(&g),
+            other => panic!("expected a function: {:?}", other),
+        };
+        assert!(g.upgrade().is_some(), "reference counting alone leaks");
+        e.collect_garbage();
+        assert!(g.upgrade().is_none());
+        assert_eq!(e.gc_stats().gc_collections, 1);
+
+        let bytecode = compiler::new().compile(&program).unwrap();
+        let mut vm = crate::vm::new(vec![]);
+        let g = match vm.run(&bytecode) {
+            Ok(Value::Closure(g)) => Rc::downgrade(&g),
+            other => panic!("expected a closure: {:?}", other),
+        };
+        assert!(g.upgrade().is_some());
+        vm.collect_garbage();
+        assert!(g.upgrade().is_none());
+    }
+
+    /// 大域変数や評価中の値から届くものは集めない
+    #[test]
+    fn reachable() {
+        let mut e = evaluator::new(vec![]);
+        let (program, _) = parser::parse("let f = fn() { f }; let k = fn(x) { fn() { x } };");
+        e.eval_program(&program).unwrap();
+        e.collect_garbage();
+        let (program, _) = parser::parse("f() == f && k(3)() == 3");
+        assert_eq!(e.eval_program(&program), Ok(Value::Boolean(true)));
+
+        let (program, _) = parser::parse(CYCLE);
+        let kept = e.eval_program(&program).unwrap();
+        e.collect_garbage();
+        let Value::Function(g) = &kept else {
+            panic!("expected a function");
+        };
+        assert_eq!(g.env.borrow().get(&Symbol::from("g")), Some(kept.clone()));
+    }
+
+    #[test]
+    fn stats() {
+        let mut e = evaluator::new(vec![]);
+        let empty = e.gc_stats();
+        let (program, _) = parser::parse(
+            "let make = fn() { let g = fn() { g }; g };
+             let loop = fn(n) { if (n > 0) { make(); loop(n - 1) } };
+             loop(5000);",
+        );
+        e.eval_program(&program).unwrap();
+        let stats = e.gc_stats();
+        assert!(stats.gc_collections > 0);
+        e.collect_garbage();
+        let after = e.gc_stats();
+        assert!(after.heap_objects < 10, "{:?}", after);
+        assert!(after.heap_bytes > empty.heap_bytes);
+        assert!(after.heap_bytes < stats.heap_bytes);
+    }
+
+    /// 本体は構文木と共有しているので、本体が大きくても関数値ひとつの大きさは変わらない
+    #[test]
+    fn function_bodies_not_copied() {
+        let bytes = |body: &str| {
+            let (program, _) = parser::parse(&format!("let f = fn(n) {{ {} }};", body));
+            let mut e = evaluator::new(vec![]);
+            let before = e.gc_stats().heap_bytes;
+            e.eval_program(&program).unwrap();
+            e.gc_stats().heap_bytes - before
+        };
+        assert_eq!(bytes("n"), bytes(&"n + 1; ".repeat(1000)));
+    }
+
+    /// 確保のたびに集めても結果は変わらない
+    #[test]
+    fn stress() {
+        let inputs = [
+            CYCLE,
+            "let add = fn(a) { fn(b) { a + b } }; let inc = add(1); inc(inc(1))",
+            "let counter = fn(n) { let next = fn(m) { if (m < n) { next(m + 1) } else { m } }; next(0) };
+             counter(50)",
+            "let even = fn(n) { if (n == 0) { true } else { odd(n - 1) } };
+             let odd = fn(n) { if (n == 0) { false } else { even(n - 1) } };
+             even(100)",
+            "let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } }; fib(12)",
+            "let f = fn(x) { let y = x * 2; fn() { y + undefined } }; f(1)()",
+        ];
+        for input in inputs {
+            let [evaluated, executed] = run(input, false);
+            let [stressed_evaluated, stressed_executed] = run(input, true);
+            // 関数値は同じものにならないので型だけ比べる
+            let describe = |r: &Result<Value, &'static str>| match r {
+                Ok(Value::Function(_) | Value::Closure(_)) => Ok("fn".to_string()),
+                Ok(value) => Ok(value.to_string()),
+                Err(code) => Err(*code),
+            };
+            assert_eq!(
+                describe(&stressed_evaluated),
+                describe(&evaluated),
+                "{}",
+                input
+            );
+            assert_eq!(
+                describe(&stressed_executed),
+                describe(&executed),
+                "{}",
+                input
+            );
+        }
+    }
+}
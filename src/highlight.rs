@@ -0,0 +1,162 @@
+//! エディタやページャ向けに、ソースの範囲ごとに色分けの種類を決める。
+//!
+//! 識別子はトークンの種類だけでなく名前解決の結果も使って、関数・仮引数・変数に分ける。
+
+use std::collections::HashMap;
+
+use crate::resolver::{self, BindingKind};
+use crate::span::Span;
+use crate::token::{Paren, Special, TokenType, Value};
+use crate::{lexer, parser};
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Category {
+    Keyword,
+    /// 関数リテラルを束縛した名前と、呼び出されている名前
+    Function,
+    Parameter,
+    Variable,
+    Number,
+    /// まだ文字列リテラルはないので使われない
+    String,
+    Operator,
+    Comment,
+}
+
+impl Category {
+    pub const ALL: [Category; 8] = [
+        Category::Keyword,
+        Category::Function,
+        Category::Parameter,
+        Category::Variable,
+        Category::Number,
+        Category::String,
+        Category::Operator,
+        Category::Comment,
+    ];
+
+    /// CSS のクラス名などに使える名前
+    pub fn name(&self) -> &'static str {
+        match self {
+            Category::Keyword => "keyword",
+            Category::Function => "function",
+            Category::Parameter => "parameter",
+            Category::Variable => "variable",
+            Category::Number => "number",
+            Category::String => "string",
+            Category::Operator => "operator",
+            Category::Comment => "comment",
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Highlight {
+    pub span: Span,
+    pub category: Category,
+}
+
+/// 色を付ける範囲を位置順に返す。区切り記号や括弧、不正な文字は含まない。
+/// 構文エラーがあっても、解析できた部分の名前解決を使って分類する。
+pub fn highlight(input: &str) -> Vec<Highlight> {
+    let mut l = lexer::borrowed(input);
+    let mut tokens = vec![];
+    loop {
+        let (tok, span) = l.next_token_with_span();
+        if tok.token_type == TokenType::Special(Special::EOF) {
+            break;
+        }
+        tokens.push((tok.token_type, span));
+    }
+
+    let (program, _) = parser::parse(input);
+    let resolution = resolver::resolve(&program);
+    let mut bindings = HashMap::new();
+    for binding in &resolution.bindings {
+        bindings.insert(binding.span.start, binding);
+        for reference in &binding.references {
+            bindings.insert(reference.start, binding);
+        }
+    }
+
+    let mut highlights: Vec<Highlight> = l
+        .comments()
+        .iter()
+        .map(|c| Highlight {
+            span: c.span,
+            category: Category::Comment,
+        })
+        .collect();
+    for (i, (token_type, span)) in tokens.iter().enumerate() {
+        let category = match token_type {
+            TokenType::KeyWord(_) => Category::Keyword,
+            TokenType::Value(Value::Int) => Category::Number,
+            TokenType::Operator(_) => Category::Operator,
+            TokenType::Value(Value::Ident) => {
+                let called = matches!(
+                    tokens.get(i + 1),
+                    Some((TokenType::Paren(Paren::LParen), _))
+                );
+                match bindings.get(&span.start) {
+                    Some(b) if b.kind == BindingKind::Parameter => Category::Parameter,
+                    Some(b) if b.function => Category::Function,
+                    _ if called => Category::Function,
+                    _ => Category::Variable,
+                }
+            }
+            _ => continue,
+        };
+        highlights.push(Highlight {
+            span: *span,
+            category,
+        });
+    }
+    highlights.sort_by_key(|h| h.span.start);
+    highlights
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{highlight, Category};
+
+    #[test]
+    fn categories() {
+        let input = "let add = fn(a, b) { a + b }; // 足す\nlet x = 1;\nadd(x, puts(2));";
+        let found: Vec<(String, &str)> = highlight(input)
+            .iter()
+            .map(|h| {
+                let text: String = input
+                    .chars()
+                    .skip(h.span.start)
+                    .take(h.span.end - h.span.start)
+                    .collect();
+                (text, h.category.name())
+            })
+            .collect();
+        let expected = [
+            ("let", Category::Keyword),
+            ("add", Category::Function),
+            ("=", Category::Operator),
+            ("fn", Category::Keyword),
+            ("a", Category::Parameter),
+            ("b", Category::Parameter),
+            ("a", Category::Parameter),
+            ("+", Category::Operator),
+            ("b", Category::Parameter),
+            ("// 足す", Category::Comment),
+            ("let", Category::Keyword),
+            ("x", Category::Variable),
+            ("=", Category::Operator),
+            ("1", Category::Number),
+            ("add", Category::Function),
+            ("x", Category::Variable),
+            ("puts", Category::Function),
+            ("2", Category::Number),
+        ];
+        let expected: Vec<(String, &str)> = expected
+            .iter()
+            .map(|(text, category)| (text.to_string(), category.name()))
+            .collect();
+        assert_eq!(found, expected);
+    }
+}
@@ -0,0 +1,215 @@
+//! エディタ向けの差分字句解析。
+//!
+//! 編集された位置の一つ前のトークンから字句解析し直し、編集より後ろで前回と同じトークンが
+//! 同じ位置 (編集でずれた分を除く) に現れたところで打ち切る。字句解析器はトークンの境目から
+//! 先の文字だけで結果が決まるので、そこから後ろは前回の結果をずらして使える。
+
+use std::ops::Range;
+
+use crate::lexer;
+use crate::span::Span;
+use crate::token::{Special, Token, TokenType};
+
+/// `span` の範囲 (文字単位) を `text` で置き換える編集
+#[derive(Debug, PartialEq, Clone)]
+pub struct Edit {
+    pub span: Span,
+    pub text: String,
+}
+
+/// 一回の編集によるトークン列の変化。
+/// 前回のトークン列の `removed` を `inserted` で置き換え、その後ろのトークンは位置を `shift` だけずらす。
+#[derive(Debug, PartialEq, Clone)]
+pub struct TokenChange {
+    pub removed: Range<usize>,
+    pub inserted: Vec<(Token, Span)>,
+    pub shift: isize,
+}
+
+pub struct IncrementalLexer {
+    input: String,
+    tokens: Vec<(Token, Span)>,
+}
+
+impl IncrementalLexer {
+    pub fn new(input: String) -> IncrementalLexer {
+        let (tokens, _) = lexer::tokenize(&input);
+        IncrementalLexer { input, tokens }
+    }
+
+    pub fn input(&self) -> &str {
+        &self.input
+    }
+
+    /// EOF を含むトークン列
+    pub fn tokens(&self) -> &[(Token, Span)] {
+        &self.tokens
+    }
+
+    pub fn edit(&mut self, edit: &Edit) -> TokenChange {
+        let length = self.input.chars().count();
+        assert!(
+            edit.span.start <= edit.span.end && edit.span.end <= length,
+            "edit {:?} is out of the input of length {}",
+            edit.span,
+            length
+        );
+        self.input.replace_range(
+            byte_offset(&self.input, edit.span.start)..byte_offset(&self.input, edit.span.end),
+            &edit.text,
+        );
+        let shift = edit.text.chars().count() as isize - (edit.span.end - edit.span.start) as isize;
+
+        // 編集に接していないトークンのうち最後のものからやり直す
+        let first = self
+            .tokens
+            .iter()
+            .rposition(|(_, span)| span.end < edit.span.start)
+            .unwrap_or(0);
+        let restart = if first == 0 {
+            0
+        } else {
+            self.tokens[first].1.start
+        };
+
+        let mut l = lexer::new_at(self.input.clone(), restart);
+        let mut inserted = vec![];
+        // 前回のトークン列のうち、次に一致するか調べる位置
+        let mut old = first;
+        let end = loop {
+            let (tok, span) = l.next_token_with_span();
+            while old < self.tokens.len() && shifted(self.tokens[old].1, shift).start < span.start {
+                old += 1;
+            }
+            if let Some((old_tok, old_span)) = self.tokens.get(old) {
+                if old_span.start >= edit.span.end
+                    && *old_tok == tok
+                    && shifted(*old_span, shift) == span
+                {
+                    break old;
+                }
+            }
+            let eof = tok.token_type == TokenType::Special(Special::EOF);
+            inserted.push((tok, span));
+            if eof {
+                break self.tokens.len();
+            }
+        };
+
+        let change = TokenChange {
+            removed: first..end,
+            inserted,
+            shift,
+        };
+        self.tokens
+            .splice(change.removed.clone(), change.inserted.iter().cloned());
+        for (_, span) in &mut self.tokens[first + change.inserted.len()..] {
+            *span = shifted(*span, shift);
+        }
+        change
+    }
+}
+
+fn shifted(span: Span, shift: isize) -> Span {
+    Span {
+        start: (span.start as isize + shift) as usize,
+        end: (span.end as isize + shift) as usize,
+    }
+}
+
+fn byte_offset(input: &str, offset: usize) -> usize {
+    input
+        .char_indices()
+        .nth(offset)
+        .map(|(i, _)| i)
+        .unwrap_or(input.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::{Edit, IncrementalLexer};
+    use crate::lexer;
+    use crate::span::Span;
+
+    #[test]
+    fn relex_only_affected_tokens() {
+        let mut l = IncrementalLexer::new("let a = 1;\nlet b = a + 2;\n".to_string());
+        let change = l.edit(&Edit {
+            span: Span { start: 4, end: 5 },
+            text: "abc".to_string(),
+        });
+        assert_eq!(l.input(), "let abc = 1;\nlet b = a + 2;\n");
+        assert_eq!(change.removed, 0..2);
+        let literals: Vec<&str> = change
+            .inserted
+            .iter()
+            .map(|(t, _)| t.literal.as_str())
+            .collect();
+        assert_eq!(literals, vec!["let", "abc"]);
+        assert_eq!(change.shift, 2);
+        assert_eq!(l.tokens(), lexer::tokenize(l.input()).0.as_slice());
+
+        // `=` の後ろに `=` を足すと一つのトークンになる
+        let change = l.edit(&Edit {
+            span: Span { start: 9, end: 9 },
+            text: "=".to_string(),
+        });
+        let literals: Vec<&str> = change
+            .inserted
+            .iter()
+            .map(|(t, _)| t.literal.as_str())
+            .collect();
+        assert_eq!(literals, vec!["abc", "=="]);
+        assert_eq!(l.tokens(), lexer::tokenize(l.input()).0.as_slice());
+
+        // コメントにすると行末までのトークンが消える
+        l.edit(&Edit {
+            span: Span { start: 0, end: 0 },
+            text: "//".to_string(),
+        });
+        assert_eq!(l.tokens(), lexer::tokenize(l.input()).0.as_slice());
+    }
+
+    fn source() -> impl Strategy<Value = String> {
+        prop::collection::vec(
+            prop_oneof![
+                Just("let"),
+                Just("x"),
+                Just("10"),
+                Just("="),
+                Just("=="),
+                Just("!"),
+                Just("/"),
+                Just("//"),
+                Just(";"),
+                Just("("),
+                Just("}"),
+                Just(" "),
+                Just("\n"),
+                Just("あ"),
+            ],
+            0..30,
+        )
+        .prop_map(|parts| parts.concat())
+    }
+
+    proptest! {
+        #[test]
+        fn matches_full_relex(
+            input in source(),
+            edits in prop::collection::vec((any::<prop::sample::Index>(), 0..4usize, source()), 1..5),
+        ) {
+            let mut l = IncrementalLexer::new(input);
+            for (at, removed, text) in edits {
+                let length = l.input().chars().count();
+                let start = at.index(length + 1);
+                let end = (start + removed).min(length);
+                l.edit(&Edit { span: Span { start, end }, text });
+                let (expected, _) = lexer::tokenize(l.input());
+                prop_assert_eq!(l.tokens(), expected.as_slice());
+            }
+        }
+    }
+}
@@ -0,0 +1,331 @@
+//! プログラムに組み込んで使うための入口。
+//!
+//! ```text
+//! let mut interpreter = Interpreter::new();
+//! interpreter.register_fn("double", |x: i64| x * 2);
+//! interpreter.set_global("limit", 10);
+//! let value = interpreter.eval("double(limit)")?; // Value::Integer(20)
+//! ```
+
+use std::fmt;
+use std::io::{self, Write};
+use std::rc::Rc;
+
+use crate::diagnostic::{self, Diagnostic};
+use crate::evaluator::{self, Evaluator};
+use crate::gc::Stats;
+use crate::limits::{Limit, Limits};
+use crate::object::{FromValue, HostError, HostFunction, IntoValue, Value};
+use crate::{optimizer, parser};
+
+/// 評価を続けても大域の束縛と登録した関数は残る
+pub struct Interpreter {
+    evaluator: Evaluator<Box<dyn Write>>,
+    optimize: bool,
+    /// 評価器に渡した上限。式の入れ子の上限は構文解析にもかける
+    limits: Limits,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Error {
+    /// 字句・構文のエラー。ひとつでもあれば評価しない。
+    Syntax(Vec<Diagnostic>),
+    Runtime(Diagnostic),
+}
+
+impl Error {
+    pub fn diagnostics(&self) -> &[Diagnostic] {
+        match self {
+            Error::Syntax(diagnostics) => diagnostics,
+            Error::Runtime(diagnostic) => std::slice::from_ref(diagnostic),
+        }
+    }
+
+    /// 上限に達して打ち切ったときはその上限
+    pub fn limit(&self) -> Option<Limit> {
+        match self {
+            Error::Runtime(diagnostic) => Limit::from_code(diagnostic.code),
+            Error::Syntax(_) => None,
+        }
+    }
+
+    /// `diagnostic::render_all` と同じ形で表示する
+    pub fn render(&self, source: &str, name: &str) -> String {
+        diagnostic::render_all(self.diagnostics(), source, name)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let messages: Vec<String> = self
+            .diagnostics()
+            .iter()
+            .map(|d| format!("error[{}]: {}", d.code, d.message))
+            .collect();
+        write!(f, "{}", messages.join("\n"))
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl Default for Interpreter {
+    fn default() -> Interpreter {
+        Interpreter::new()
+    }
+}
+
+impl Interpreter {
+    /// `puts` は標準出力に書く
+    pub fn new() -> Interpreter {
+        Interpreter::with_output(io::stdout())
+    }
+
+    pub fn with_output(out: impl Write + 'static) -> Interpreter {
+        Interpreter {
+            evaluator: evaluator::new(Box::new(out)),
+            optimize: true,
+            limits: Limits::default(),
+        }
+    }
+
+    /// 最後の文の値を返す
+    pub fn eval(&mut self, input: &str) -> Result<Value, Error> {
+        let (program, errors) =
+            parser::parse_with(input, self.evaluator.interner(), self.limits.max_nesting);
+        if diagnostic::has_errors(&errors) {
+            return Err(Error::Syntax(errors));
+        }
+        let program = if self.optimize {
+            optimizer::optimize(program)
+        } else {
+            program
+        };
+        self.evaluator
+            .eval_program(&program)
+            .map_err(Error::Runtime)
+    }
+
+    /// 評価する前に `optimizer` をかけるか。既定ではかける
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.optimize = optimize;
+    }
+
+    /// 以降の `eval` にかける上限
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+        self.evaluator.set_limits(limits);
+    }
+
+    /// ごみ集めの回数と、生きている環境と関数値の量
+    pub fn gc_stats(&self) -> Stats {
+        self.evaluator.gc_stats()
+    }
+
+    /// 循環して残っている環境と関数値を今すぐ解放する。放っておいても確保が増えれば集める
+    pub fn collect_garbage(&mut self) {
+        self.evaluator.collect_garbage();
+    }
+
+    /// 環境や関数値を確保するたびにごみ集めをする。遅くなるのでテストで使う
+    pub fn set_gc_stress(&mut self, stress: bool) {
+        self.evaluator.set_gc_stress(stress);
+    }
+
+    pub fn set_global(&mut self, name: &str, value: impl IntoValue) {
+        self.evaluator.set_global(name, value.into_value());
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        self.evaluator.get_global(name)
+    }
+
+    /// 大域の束縛を `T` に変換して返す。ないか型が合わなければ `None`。
+    pub fn get<T: FromValue>(&self, name: &str) -> Option<T> {
+        T::from_value(self.get_global(name)?)
+    }
+
+    /// Rust の関数を大域の組み込み関数として登録する。
+    /// 引数は `FromValue` で変換し、数か型が合わなければ実行時エラーにする。
+    /// 戻り値は `IntoValue` か、失敗を表す `Result<T, E>` にできる。
+    pub fn register_fn<Args, F: HostFn<Args>>(&mut self, name: &str, function: F) {
+        let host = HostFunction {
+            name: name.to_string(),
+            function: Box::new(move |arguments| function.call(arguments)),
+        };
+        self.evaluator.set_global(name, Value::Host(Rc::new(host)));
+    }
+}
+
+/// `register_fn` に渡せる関数。引数が 4 個までのクロージャに実装している。
+pub trait HostFn<Args>: 'static {
+    fn call(&self, arguments: Vec<Value>) -> Result<Value, HostError>;
+}
+
+/// 埋め込み側の関数の戻り値
+pub trait HostResult {
+    fn into_result(self) -> Result<Value, HostError>;
+}
+
+impl<T: IntoValue> HostResult for T {
+    fn into_result(self) -> Result<Value, HostError> {
+        Ok(self.into_value())
+    }
+}
+
+impl<T: IntoValue, E: fmt::Display> HostResult for Result<T, E> {
+    fn into_result(self) -> Result<Value, HostError> {
+        self.map(T::into_value)
+            .map_err(|e| HostError::Failed(e.to_string()))
+    }
+}
+
+fn argument<T: FromValue>(index: usize, value: Value) -> Result<T, HostError> {
+    let found = value.type_name();
+    T::from_value(value).ok_or(HostError::Argument {
+        index,
+        expected: T::TYPE_NAME,
+        found,
+    })
+}
+
+macro_rules! host_fn {
+    ($len:expr; $($arg:ident),*) => {
+        impl<F, R, $($arg),*> HostFn<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R + 'static,
+            R: HostResult,
+            $($arg: FromValue,)*
+        {
+            #[allow(non_snake_case, unused_variables, unused_mut)]
+            fn call(&self, arguments: Vec<Value>) -> Result<Value, HostError> {
+                if arguments.len() != $len {
+                    return Err(HostError::Arity {
+                        expected: $len,
+                        found: arguments.len(),
+                    });
+                }
+                let mut arguments = arguments.into_iter().enumerate();
+                $(
+                    let (index, value) = arguments.next().unwrap();
+                    let $arg: $arg = argument(index, value)?;
+                )*
+                self($($arg),*).into_result()
+            }
+        }
+    };
+}
+
+host_fn!(0;);
+host_fn!(1; A);
+host_fn!(2; A, B);
+host_fn!(3; A, B, C);
+host_fn!(4; A, B, C, D);
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::{self, Write};
+    use std::rc::Rc;
+
+    use super::{Error, Interpreter};
+    use crate::evaluator::{E_ARITY, E_HOST, E_TYPE_MISMATCH, E_UNDEFINED};
+    use crate::limits::{Limit, Limits};
+    use crate::object::Value;
+
+    #[derive(Clone, Default)]
+    struct Output(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn code(result: Result<Value, Error>) -> &'static str {
+        match result {
+            Err(Error::Runtime(d)) => d.code,
+            other => panic!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn embedding() {
+        let output = Output::default();
+        let mut i = Interpreter::with_output(output.clone());
+        i.register_fn("double", |x: i64| x * 2);
+        i.register_fn("clamp", |x: i64, max: Option<i64>| x.min(max.unwrap_or(x)));
+        i.register_fn("checked_div", |a: i64, b: i64| {
+            a.checked_div(b).ok_or("division by zero")
+        });
+        i.register_fn("log", move |v: Value| {
+            let _ = v;
+        });
+        i.set_global("limit", 10);
+
+        assert_eq!(i.eval("double(limit)"), Ok(Value::Integer(20)));
+        assert_eq!(
+            i.eval("let x = clamp(50, limit) + clamp(3, if (false) { 1 }); x"),
+            Ok(Value::Integer(13))
+        );
+        assert_eq!(i.get::<i64>("x"), Some(13));
+        assert_eq!(i.get::<bool>("x"), None);
+        assert_eq!(i.eval("puts(double, log(1))"), Ok(Value::Null));
+        assert_eq!(*output.0.borrow(), b"builtin double\nnull\n");
+
+        assert_eq!(code(i.eval("double(true)")), E_TYPE_MISMATCH);
+        assert_eq!(code(i.eval("double(1, 2)")), E_ARITY);
+        assert_eq!(code(i.eval("checked_div(1, 0)")), E_HOST);
+        assert_eq!(code(i.eval("y")), E_UNDEFINED);
+        assert_eq!(i.eval("y").unwrap_err().limit(), None);
+        i.set_limits(Limits {
+            fuel: Some(10),
+            ..Limits::default()
+        });
+        assert_eq!(
+            i.eval("double(double(double(double(double(1)))))")
+                .unwrap_err()
+                .limit(),
+            Some(Limit::Fuel)
+        );
+        assert!(matches!(i.eval("let = 1;"), Err(Error::Syntax(_))));
+        i.set_limits(Limits {
+            max_nesting: Some(2),
+            ..Limits::default()
+        });
+        assert_eq!(i.eval("1 + 2 + 3"), Ok(Value::Integer(6)));
+        assert!(matches!(i.eval("--1"), Err(Error::Syntax(_))));
+        let error = i.eval("double(true)").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "error[E0201]: argument 1 of `double` must be int, found bool"
+        );
+    }
+
+    #[test]
+    fn garbage_collection() {
+        let mut i = Interpreter::with_output(io::sink());
+        i.eval("let make = fn(n) { let g = fn() { g }; n };")
+            .unwrap();
+        let before = i.gc_stats();
+        assert_eq!(i.eval("make(1) + make(2)"), Ok(Value::Integer(3)));
+        let leaked = i.gc_stats();
+        assert!(leaked.heap_objects > before.heap_objects);
+        assert!(leaked.heap_bytes > before.heap_bytes);
+        i.collect_garbage();
+        let after = i.gc_stats();
+        assert_eq!(after.gc_collections, before.gc_collections + 1);
+        assert_eq!(after.heap_objects, before.heap_objects);
+        assert_eq!(after.heap_bytes, before.heap_bytes);
+
+        // 確保のたびに集めるので、残るのは最後の呼び出しの環境と関数値だけ
+        i.set_gc_stress(true);
+        assert_eq!(i.eval("make(1) + make(2) + make(3)"), Ok(Value::Integer(6)));
+        let stressed = i.gc_stats();
+        assert!(stressed.gc_collections > after.gc_collections);
+        assert_eq!(stressed.heap_objects, before.heap_objects + 2);
+    }
+}
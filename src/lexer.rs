@@ -1,164 +1,806 @@
-use std::char;
+//! 字句解析器。
+//!
+//! `Lexer<'src>` はソースを借用し、リテラルをソースの部分文字列として返すのでトークンごとの確保がない。
+//! `with_interner` で intern 表を渡すと識別子に `Symbol` も付け、同じ名前は一度だけ確保する。
+//! 所有する `String` を受け取って `token::Token` を返す `OwnedLexer` も残している。
+//! `io::Read` から少しずつ読む `StreamLexer` もある。
+//! どれも同じ `Cursor` で読み進めるので、返すトークンと範囲は変わらない。
+//! 予約語の表は `with_keywords` で差し替えられる。
 
-use regex::Regex;
+use std::io::{self, Read};
+use std::ops::Range;
+use std::str;
+use std::sync::Arc;
 
-use crate::token;
+use crate::diagnostic::{self, Diagnostic};
+use crate::span::Span;
+use crate::symbol::Interner;
+use crate::token::{self, KeyWord, Keywords, TokenType};
 
-pub struct Lexer {
+/// 字句解析できない文字
+pub const E_ILLEGAL_CHARACTER: &str = "E0001";
+/// `5abc` のように数字で始まり英字を含む語
+pub const E_INVALID_NUMBER: &str = "E0002";
+
+/// ソースを借用する字句解析器
+pub struct Lexer<'src> {
+    input: &'src str,
+    cursor: Cursor,
+    /// あれば識別子をこの表で `Symbol` にする
+    interner: Option<Interner>,
+}
+
+/// 入力を所有する字句解析器。トークンのリテラルも所有する。
+pub struct OwnedLexer {
     input: String,
-    position: usize,
-    read_position: usize,
+    cursor: Cursor,
 }
 
-pub fn new(input: String) -> Lexer {
-    return Lexer {
+/// `io::Read` から読みながら字句解析する。トークンのリテラルは所有する。
+pub struct StreamLexer<R> {
+    reader: R,
+    /// 読み込んだテキストのうち、まだ捨てていない部分
+    buffer: String,
+    /// 末尾で途切れた UTF-8 の文字のバイト
+    pending: Vec<u8>,
+    done: bool,
+    cursor: Cursor,
+}
+
+/// 一度に読み込む大きさ
+const CHUNK_SIZE: usize = 8 * 1024;
+
+/// `//` から行末までのコメント。トークンにはならず、空白と同じく読み飛ばされる。
+#[derive(Debug, PartialEq, Clone)]
+pub struct Comment {
+    /// 先頭の `//` を含み、行末の空白を含まない
+    pub text: String,
+    pub span: Span,
+}
+
+pub fn new(input: String) -> OwnedLexer {
+    OwnedLexer {
         input,
-        position: 0,
-        read_position: 0,
-    };
+        cursor: Cursor::default(),
+    }
+}
+
+/// `offset` 文字目から字句解析を始める。`offset` はトークンの境目でなければならない。
+pub fn new_at(input: String, offset: usize) -> OwnedLexer {
+    let cursor = Cursor::at(&input, offset);
+    OwnedLexer { input, cursor }
 }
 
-impl Lexer {
+pub fn borrowed(input: &str) -> Lexer<'_> {
+    Lexer {
+        input,
+        cursor: Cursor::default(),
+        interner: None,
+    }
+}
+
+pub fn from_reader<R: Read>(reader: R) -> StreamLexer<R> {
+    StreamLexer {
+        reader,
+        buffer: String::new(),
+        pending: vec![],
+        done: false,
+        cursor: Cursor::default(),
+    }
+}
+
+/// 入力の終わりまで字句解析し、EOF を含むトークン列と見つかったエラーを返す
+pub fn tokenize(input: &str) -> (Vec<(token::Token, Span)>, Vec<Diagnostic>) {
+    let mut l = borrowed(input);
+    let mut tokens = vec![];
+    loop {
+        let (tok, span) = l.next_token_with_span();
+        tokens.push((tok.to_token(), span));
+        if tok.token_type == TokenType::Special(token::Special::EOF) {
+            return (tokens, l.cursor.errors);
+        }
+    }
+}
+
+impl<'src> Lexer<'src> {
+    pub fn with_keywords(mut self, keywords: Keywords) -> Lexer<'src> {
+        self.cursor.keywords = Arc::new(keywords);
+        self
+    }
+
+    /// 識別子のトークンに、`interner` でまとめた `Symbol` を付ける
+    pub fn with_interner(mut self, interner: Interner) -> Lexer<'src> {
+        self.interner = Some(interner);
+        self
+    }
+
+    /// `with_interner` で渡した表を、読んだ識別子を加えて返す
+    pub fn take_interner(&mut self) -> Interner {
+        self.interner.take().unwrap_or_default()
+    }
+
+    pub fn next_token(&mut self) -> token::TokenRef<'src> {
+        self.next_token_with_span().0
+    }
+
+    /// 次のトークンと、そのソース上の範囲を返す
+    pub fn next_token_with_span(&mut self) -> (token::TokenRef<'src>, Span) {
+        let (token_type, bytes, span) = self.cursor.scan(self.input);
+        let literal = &self.input[bytes];
+        let symbol = match (&mut self.interner, token_type) {
+            (Some(interner), TokenType::Value(token::Value::Ident)) => {
+                Some(interner.intern(literal))
+            }
+            _ => None,
+        };
+        let tok = token::TokenRef {
+            token_type,
+            literal,
+            symbol,
+        };
+        (tok, span)
+    }
+
+    /// これまでに見つかった字句解析のエラー
+    pub fn errors(&self) -> &[Diagnostic] {
+        &self.cursor.errors
+    }
+
+    /// これまでに読み飛ばしたコメント
+    pub fn comments(&self) -> &[Comment] {
+        &self.cursor.comments
+    }
+}
+
+impl OwnedLexer {
+    pub fn with_keywords(mut self, keywords: Keywords) -> OwnedLexer {
+        self.cursor.keywords = Arc::new(keywords);
+        self
+    }
+
     pub fn next_token(&mut self) -> token::Token {
-        use token::*;
-        let char = match self.input.chars().nth(self.read_position) {
-            Some(c) => c,
-            None => '\0',
+        self.next_token_with_span().0
+    }
+
+    /// 次のトークンと、そのソース上の範囲を返す
+    pub fn next_token_with_span(&mut self) -> (token::Token, Span) {
+        let (token_type, bytes, span) = self.cursor.scan(&self.input);
+        let tok = token::Token {
+            token_type,
+            literal: self.input[bytes].to_string(),
         };
+        (tok, span)
+    }
 
-        let tok = match char {
-            '=' => match self.input.chars().nth(self.read_position + 1).unwrap() {
-                '=' => {
-                    self.position = self.read_position;
-                    self.read_position += 1;
-                    Token {
-                        token_type: TokenType::Operator(Operator::Eq),
-                        literal: "==".to_string(),
-                    }
+    /// これまでに見つかった字句解析のエラー
+    pub fn errors(&self) -> &[Diagnostic] {
+        &self.cursor.errors
+    }
+
+    /// これまでに読み飛ばしたコメント
+    pub fn comments(&self) -> &[Comment] {
+        &self.cursor.comments
+    }
+}
+
+impl<R: Read> StreamLexer<R> {
+    pub fn with_keywords(mut self, keywords: Keywords) -> StreamLexer<R> {
+        self.cursor.keywords = Arc::new(keywords);
+        self
+    }
+
+    pub fn next_token(&mut self) -> io::Result<token::Token> {
+        Ok(self.next_token_with_span()?.0)
+    }
+
+    /// 次のトークンと、そのソース上の範囲を返す。入力が UTF-8 でなければエラーになる。
+    pub fn next_token_with_span(&mut self) -> io::Result<(token::Token, Span)> {
+        loop {
+            let (byte, char) = (self.cursor.byte, self.cursor.char);
+            let (errors, comments) = (self.cursor.errors.len(), self.cursor.comments.len());
+            let (token_type, bytes, span) = self.cursor.scan(&self.buffer);
+            // バッファの末尾で終わったトークンには続きがあるかもしれないので、読み足してからやり直す
+            if (self.cursor.byte == self.buffer.len() || self.cursor.incomplete) && !self.done {
+                self.cursor.byte = byte;
+                self.cursor.char = char;
+                self.cursor.errors.truncate(errors);
+                self.cursor.comments.truncate(comments);
+                self.fill()?;
+                continue;
+            }
+            let tok = token::Token {
+                token_type,
+                literal: self.buffer[bytes].to_string(),
+            };
+            if self.cursor.byte >= CHUNK_SIZE {
+                self.buffer.drain(..self.cursor.byte);
+                self.cursor.byte = 0;
+            }
+            return Ok((tok, span));
+        }
+    }
+
+    /// これまでに見つかった字句解析のエラー
+    pub fn errors(&self) -> &[Diagnostic] {
+        &self.cursor.errors
+    }
+
+    /// これまでに読み飛ばしたコメント
+    pub fn comments(&self) -> &[Comment] {
+        &self.cursor.comments
+    }
+
+    /// 一度読み込み、文字として完結している部分をバッファに足す
+    fn fill(&mut self) -> io::Result<()> {
+        let mut chunk = [0; CHUNK_SIZE];
+        let n = loop {
+            match self.reader.read(&mut chunk) {
+                Ok(n) => break n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        };
+        if n == 0 {
+            self.done = true;
+            if !self.pending.is_empty() {
+                return Err(invalid_utf8());
+            }
+            return Ok(());
+        }
+        self.pending.extend_from_slice(&chunk[..n]);
+        let valid = match str::from_utf8(&self.pending) {
+            Ok(text) => text.len(),
+            // 文字の途中で途切れているだけなら、残りは次に読んだときに足す
+            Err(e) if e.error_len().is_none() => e.valid_up_to(),
+            Err(_) => return Err(invalid_utf8()),
+        };
+        self.buffer
+            .push_str(str::from_utf8(&self.pending[..valid]).unwrap());
+        self.pending.drain(..valid);
+        Ok(())
+    }
+}
+
+fn invalid_utf8() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "stream did not contain valid UTF-8",
+    )
+}
+
+/// 読んでいる位置。スライスにはバイト単位、`Span` には文字単位の位置を使うので両方を持つ。
+/// `StreamLexer` ではバイト単位の位置はバッファの先頭からの位置になる。
+struct Cursor {
+    byte: usize,
+    char: usize,
+    errors: Vec<Diagnostic>,
+    comments: Vec<Comment>,
+    keywords: Arc<Keywords>,
+    /// 直前の `scan` が入力の続きを見ないと決められなかったか
+    incomplete: bool,
+}
+
+impl Default for Cursor {
+    fn default() -> Cursor {
+        Cursor {
+            byte: 0,
+            char: 0,
+            errors: vec![],
+            comments: vec![],
+            keywords: Keywords::shared_default(),
+            incomplete: false,
+        }
+    }
+}
+
+impl Cursor {
+    fn at(input: &str, offset: usize) -> Cursor {
+        let byte = input
+            .char_indices()
+            .nth(offset)
+            .map_or(input.len(), |(i, _)| i);
+        Cursor {
+            byte,
+            char: offset,
+            ..Cursor::default()
+        }
+    }
+
+    fn peek(&self, input: &str) -> Option<char> {
+        input[self.byte..].chars().next()
+    }
+
+    fn peek_second(&self, input: &str) -> Option<char> {
+        input[self.byte..].chars().nth(1)
+    }
+
+    fn bump(&mut self, input: &str) -> Option<char> {
+        let c = self.peek(input)?;
+        self.byte += c.len_utf8();
+        self.char += 1;
+        Some(c)
+    }
+
+    /// 次のトークンの種類と、そのバイト単位の範囲と文字単位の範囲を返す
+    fn scan(&mut self, input: &str) -> (TokenType, Range<usize>, Span) {
+        use token::*;
+        self.skip_whitespace(input);
+        self.incomplete = false;
+        let (start_byte, start) = (self.byte, self.char);
+
+        let token_type = match self.bump(input) {
+            Some('=') => match self.peek(input) {
+                Some('=') => {
+                    self.bump(input);
+                    TokenType::Operator(Operator::Eq)
                 }
-                _ => Token {
-                    token_type: TokenType::Operator(Operator::Assign),
-                    literal: char.to_string(),
-                },
+                _ => TokenType::Operator(Operator::Assign),
             },
-            '!' => match self.input.chars().nth(self.read_position + 1).unwrap() {
-                '=' => {
-                    self.position = self.read_position;
-                    self.read_position += 1;
-                    Token {
-                        token_type: TokenType::Operator(Operator::NotEq),
-                        literal: "!=".to_string(),
-                    }
+            Some('!') => match self.peek(input) {
+                Some('=') => {
+                    self.bump(input);
+                    TokenType::Operator(Operator::NotEq)
                 }
-                _ => Token {
-                    token_type: TokenType::Operator(Operator::Not),
-                    literal: char.to_string(),
-                },
-            },
-            '+' => Token {
-                token_type: TokenType::Operator(Operator::Plus),
-                literal: char.to_string(),
-            },
-            '-' => Token {
-                token_type: TokenType::Operator(Operator::Minus),
-                literal: char.to_string(),
-            },
-            '/' => Token {
-                token_type: TokenType::Operator(Operator::Div),
-                literal: char.to_string(),
-            },
-            '*' => Token {
-                token_type: TokenType::Operator(Operator::Mul),
-                literal: char.to_string(),
-            },
-            '<' => Token {
-                token_type: TokenType::Operator(Operator::Lt),
-                literal: char.to_string(),
-            },
-            '>' => Token {
-                token_type: TokenType::Operator(Operator::Gt),
-                literal: char.to_string(),
-            },
-            ';' => Token {
-                token_type: TokenType::Delimiter(Delimiter::SemiColon),
-                literal: char.to_string(),
-            },
-            ',' => Token {
-                token_type: TokenType::Delimiter(Delimiter::Comma),
-                literal: char.to_string(),
-            },
-            '(' => Token {
-                token_type: TokenType::Paren(Paren::LParen),
-                literal: char.to_string(),
-            },
-            ')' => Token {
-                token_type: TokenType::Paren(Paren::RParen),
-                literal: char.to_string(),
-            },
-            '{' => Token {
-                token_type: TokenType::Paren(Paren::LBrace),
-                literal: char.to_string(),
-            },
-            '}' => Token {
-                token_type: TokenType::Paren(Paren::RBrace),
-                literal: char.to_string(),
+                _ => TokenType::Operator(Operator::Not),
             },
-            '\0' => Token {
-                token_type: TokenType::Special(Special::EOF),
-                literal: "".to_string(),
-            },
-            ' ' | '\n' | '\t' | '\r' => {
-                // 飛ばして次のトークンへ
-                self.position = self.read_position;
-                self.read_position += 1;
-                let tok = self.next_token();
-                // TODO: もうちょっといい感じにしたい（無駄にインクリメントした分を戻している）
-                self.read_position -= 1;
-                self.position = self.read_position;
-
-                tok
-            }
-            _ => {
-                if self.is_letter(char) {
-                    let mut literal = "".to_string();
-                    // skip(n) するとインデックスが n の値からイテレーションが開始する
-                    for (i, c) in self.input.chars().enumerate().skip(self.read_position) {
-                        if self.is_letter(c) {
-                            self.position = self.read_position;
-                            self.read_position = i;
-                            literal += &c.to_string();
-                        } else {
-                            break;
-                        }
-                    }
-                    Token {
-                        token_type: get_token_type(&literal),
-                        literal,
-                    }
+            Some('+') => TokenType::Operator(Operator::Plus),
+            Some('-') => TokenType::Operator(Operator::Minus),
+            Some('/') => TokenType::Operator(Operator::Div),
+            Some('*') => TokenType::Operator(Operator::Mul),
+            Some('<') => TokenType::Operator(Operator::Lt),
+            Some('>') => TokenType::Operator(Operator::Gt),
+            Some(';') => TokenType::Delimiter(Delimiter::SemiColon),
+            Some(',') => TokenType::Delimiter(Delimiter::Comma),
+            Some('(') => TokenType::Paren(Paren::LParen),
+            Some(')') => TokenType::Paren(Paren::RParen),
+            Some('{') => TokenType::Paren(Paren::LBrace),
+            Some('}') => TokenType::Paren(Paren::RBrace),
+            None => TokenType::Special(Special::EOF),
+            Some(c) if is_letter(c) => {
+                while self.peek(input).is_some_and(is_letter) {
+                    self.bump(input);
+                }
+                let word = &input[start_byte..self.byte];
+                if c.is_ascii_digit() && !word.bytes().all(|b| b.is_ascii_digit()) {
+                    self.errors.push(
+                        diagnostic::error(
+                            E_INVALID_NUMBER,
+                            format!("invalid number literal `{}`", word),
+                            Span {
+                                start,
+                                end: self.char,
+                            },
+                        )
+                        .with_help("identifiers cannot start with a digit".to_string()),
+                    );
+                    TokenType::Special(Special::Illegal)
                 } else {
-                    Token {
-                        token_type: TokenType::Special(Special::Illegal),
-                        literal: "".to_string(),
-                    }
+                    self.keywords.token_type(word)
                 }
             }
+            Some(c) => match self.non_ascii_keyword(input, start_byte) {
+                Some((keyword, len)) => {
+                    while self.byte < start_byte + len {
+                        self.bump(input);
+                    }
+                    TokenType::KeyWord(keyword)
+                }
+                None => {
+                    self.errors.push(
+                        diagnostic::error(
+                            E_ILLEGAL_CHARACTER,
+                            format!("illegal character `{}`", c),
+                            Span {
+                                start,
+                                end: start + 1,
+                            },
+                        )
+                        .with_help(
+                            "identifiers may only contain ASCII letters, digits and `_`"
+                                .to_string(),
+                        ),
+                    );
+                    TokenType::Special(Special::Illegal)
+                }
+            },
+        };
+        let span = Span {
+            start,
+            end: self.char,
         };
-        self.position = self.read_position;
-        self.read_position += 1;
-        return tok;
+        (token_type, start_byte..self.byte, span)
     }
 
-    fn is_letter(&self, c: char) -> bool {
-        let re = Regex::new(r"[0-9a-zA-Z_]").unwrap();
-        return re.is_match(&c.to_string());
+    /// `start` から始まる ASCII 以外の予約語とそのバイト数。
+    /// 予約語の直後に英数字が続くときは予約語とみなさない。
+    fn non_ascii_keyword(&mut self, input: &str, start: usize) -> Option<(KeyWord, usize)> {
+        let rest = &input[start..];
+        let mut found: Option<(KeyWord, usize)> = None;
+        for (word, keyword) in self.keywords.non_ascii() {
+            if word.starts_with(rest) && word.len() > rest.len() {
+                // 入力の続き次第で予約語になるかもしれない
+                self.incomplete = true;
+            }
+            if !rest.starts_with(word) || found.is_some_and(|(_, len)| len >= word.len()) {
+                continue;
+            }
+            match rest[word.len()..].chars().next() {
+                Some(c) if c.is_alphanumeric() || c == '_' => {}
+                None => {
+                    self.incomplete = true;
+                    found = Some((keyword, word.len()));
+                }
+                Some(_) => found = Some((keyword, word.len())),
+            }
+        }
+        found
+    }
+
+    /// 空白とコメントを読み飛ばす
+    fn skip_whitespace(&mut self, input: &str) {
+        loop {
+            match self.peek(input) {
+                Some(' ' | '\n' | '\t' | '\r') => {
+                    self.bump(input);
+                }
+                Some('/') if self.peek_second(input) == Some('/') => self.skip_comment(input),
+                _ => return,
+            }
+        }
+    }
+
+    fn skip_comment(&mut self, input: &str) {
+        let (start_byte, start) = (self.byte, self.char);
+        while self.peek(input).is_some_and(|c| c != '\n') {
+            self.bump(input);
+        }
+        self.comments.push(Comment {
+            text: input[start_byte..self.byte].trim_end().to_string(),
+            span: Span {
+                start,
+                end: self.char,
+            },
+        });
     }
 }
 
+fn is_letter(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
 #[cfg(test)]
 mod tests {
-    use super::new;
+    use std::io;
+
+    use proptest::prelude::*;
+
+    use super::{borrowed, from_reader, new, tokenize, Comment};
+    use crate::alloc_count;
+    use crate::fuzz::Chunked;
+    use crate::span::Span;
+    use crate::symbol::{Interner, Symbol};
     use crate::token;
 
+    fn large_input() -> String {
+        "let add = fn(x, y) { x + y; }; // sum\nlet ten = add(4, 6) == 10;\n".repeat(2000)
+    }
+
+    fn count_borrowed(input: &str) -> usize {
+        let mut l = borrowed(input);
+        let mut n = 0;
+        while l.next_token().token_type != token::TokenType::Special(token::Special::EOF) {
+            n += 1;
+        }
+        n
+    }
+
+    fn count_owned(input: &str) -> usize {
+        let mut l = new(input.to_string());
+        let mut n = 0;
+        while l.next_token().token_type != token::TokenType::Special(token::Special::EOF) {
+            n += 1;
+        }
+        n
+    }
+
+    #[test]
+    fn borrowed_tokens() {
+        let input = "let x = 1; ?";
+        let mut l = borrowed(input);
+        let mut literals = vec![];
+        loop {
+            let (tok, span) = l.next_token_with_span();
+            if tok.token_type == token::TokenType::Special(token::Special::EOF) {
+                assert_eq!(span, Span { start: 12, end: 12 });
+                break;
+            }
+            literals.push(tok.literal);
+        }
+        assert_eq!(literals, vec!["let", "x", "=", "1", ";", "?"]);
+        assert_eq!(l.errors().len(), 1);
+
+        // コメントのない入力ではトークンを何個読んでも確保しない
+        let input = large_input().replace("// sum", "");
+        let (allocations, tokens) = alloc_count::count(|| count_borrowed(&input));
+        assert_eq!(allocations, 0);
+        let (allocations, _) = alloc_count::count(|| count_owned(&input));
+        assert!(allocations >= tokens);
+    }
+
+    /// 表を渡すと識別子に `Symbol` が付き、同じ名前は一度しか確保しない
+    #[test]
+    fn interned_identifiers() {
+        let mut l = borrowed("let x = x + y;").with_interner(Interner::default());
+        let mut symbols = vec![];
+        loop {
+            let tok = l.next_token();
+            if tok.token_type == token::TokenType::Special(token::Special::EOF) {
+                break;
+            }
+            symbols.push(tok.symbol.map(|s| s.to_string()));
+        }
+        let x = Some("x".to_string());
+        let y = Some("y".to_string());
+        assert_eq!(symbols, vec![None, x.clone(), None, x, None, y, None]);
+        assert_eq!(l.take_interner().get("y"), Some(Symbol::from("y")));
+
+        let input = large_input().replace("// sum", "");
+        let (allocations, tokens) = alloc_count::count(|| {
+            let mut l = borrowed(&input).with_interner(Interner::default());
+            let mut n = 0;
+            while l.next_token().token_type != token::TokenType::Special(token::Special::EOF) {
+                n += 1;
+            }
+            n
+        });
+        assert!(allocations < tokens / 100, "{} allocations", allocations);
+    }
+
+    fn stream(input: &str, size: usize) -> io::Result<Vec<(token::Token, Span)>> {
+        let mut l = from_reader(Chunked {
+            bytes: input.as_bytes(),
+            size,
+        });
+        let mut tokens = vec![];
+        loop {
+            let (tok, span) = l.next_token_with_span()?;
+            let eof = tok.token_type == token::TokenType::Special(token::Special::EOF);
+            tokens.push((tok, span));
+            if eof {
+                return Ok(tokens);
+            }
+        }
+    }
+
+    #[test]
+    fn stream_across_chunks() {
+        let input = "let café = 10 != 日本; // コメント\n!= == //\n$ x";
+        for size in 1..=8 {
+            assert_eq!(stream(input, size).unwrap(), tokenize(input).0);
+        }
+        let long = large_input();
+        assert_eq!(stream(&long, 4096).unwrap(), tokenize(&long).0);
+
+        // 不正なバイト列も、途中で途切れた文字もエラーになる
+        let truncated = "x é".as_bytes().split_last().unwrap().1;
+        for invalid in [&b"let x = \xff;"[..], truncated] {
+            let mut l = from_reader(invalid);
+            let error = (0..5).find_map(|_| l.next_token().err()).unwrap();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn keywords() {
+        use token::{KeyWord, Keywords};
+        let kinds = |mut l: super::Lexer| {
+            let mut kinds = vec![];
+            loop {
+                let tok = l.next_token();
+                if tok.token_type == token::TokenType::Special(token::Special::EOF) {
+                    return kinds;
+                }
+                kinds.push(format!("{} {}", tok.token_type, tok.literal));
+            }
+        };
+
+        let input = "定義 x = もし (真) { 返す 1 } でなければ { return もしx }; 偽";
+        assert_eq!(
+            kinds(borrowed(input).with_keywords(Keywords::japanese())).join(", "),
+            "LET 定義, IDENT x, ASSIGN =, IF もし, LPAREN (, TRUE 真, RPAREN ), LBRACE {, \
+             RETURN 返す, INT 1, RBRACE }, ELSE でなければ, LBRACE {, RETURN return, \
+             ILLEGAL も, ILLEGAL し, IDENT x, RBRACE }, SEMICOLON ;, FALSE 偽"
+        );
+        for size in 1..=4 {
+            let mut l = from_reader(Chunked {
+                bytes: input.as_bytes(),
+                size,
+            })
+            .with_keywords(Keywords::japanese());
+            let mut expected = borrowed(input).with_keywords(Keywords::japanese());
+            loop {
+                let (tok, span) = l.next_token_with_span().unwrap();
+                let (want, want_span) = expected.next_token_with_span();
+                assert_eq!((tok, span), (want.to_token(), want_span));
+                if want.token_type == token::TokenType::Special(token::Special::EOF) {
+                    break;
+                }
+            }
+        }
+
+        let mut keywords = Keywords::default();
+        keywords
+            .disable(KeyWord::Return)
+            .insert("fun", KeyWord::Function);
+        assert_eq!(
+            kinds(borrowed("fun return fn").with_keywords(keywords)),
+            vec!["FUNCTION fun", "IDENT return", "FUNCTION fn"]
+        );
+    }
+
+    #[test]
+    fn invalid_number() {
+        let (tokens, errors) = tokenize("5abc 99999999999999999999");
+        let kinds: Vec<String> = tokens
+            .iter()
+            .map(|(tok, span)| format!("{} {} {}", tok, span.start, span.end))
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                "ILLEGAL \"5abc\" 0 4",
+                "INT \"99999999999999999999\" 5 25",
+                "EOF \"\" 25 25"
+            ]
+        );
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].code, super::E_INVALID_NUMBER);
+    }
+
+    /// 種類とリテラルの組。識別子と整数以外のリテラルは種類で決まる。
+    fn token() -> impl Strategy<Value = (token::TokenType, String)> {
+        use token::*;
+        let fixed = [
+            (TokenType::Operator(Operator::Assign), "="),
+            (TokenType::Operator(Operator::Plus), "+"),
+            (TokenType::Operator(Operator::Minus), "-"),
+            (TokenType::Operator(Operator::Mul), "*"),
+            (TokenType::Operator(Operator::Div), "/"),
+            (TokenType::Operator(Operator::Lt), "<"),
+            (TokenType::Operator(Operator::Gt), ">"),
+            (TokenType::Operator(Operator::Eq), "=="),
+            (TokenType::Operator(Operator::NotEq), "!="),
+            (TokenType::Operator(Operator::Not), "!"),
+            (TokenType::Delimiter(Delimiter::Comma), ","),
+            (TokenType::Delimiter(Delimiter::SemiColon), ";"),
+            (TokenType::Paren(Paren::LParen), "("),
+            (TokenType::Paren(Paren::RParen), ")"),
+            (TokenType::Paren(Paren::LBrace), "{"),
+            (TokenType::Paren(Paren::RBrace), "}"),
+            (TokenType::KeyWord(KeyWord::Function), "fn"),
+            (TokenType::KeyWord(KeyWord::Let), "let"),
+            (TokenType::KeyWord(KeyWord::True), "true"),
+            (TokenType::KeyWord(KeyWord::False), "false"),
+            (TokenType::KeyWord(KeyWord::If), "if"),
+            (TokenType::KeyWord(KeyWord::Else), "else"),
+            (TokenType::KeyWord(KeyWord::Return), "return"),
+        ]
+        .map(|(kind, literal)| (kind, literal.to_string()));
+        prop_oneof![
+            prop::sample::select(fixed.to_vec()),
+            "[a-zA-Z_][a-zA-Z0-9_]{0,8}"
+                .prop_filter("keyword", |s| Keywords::default().get(s).is_none())
+                .prop_map(|s| (TokenType::Value(Value::Ident), s)),
+            "[0-9]{1,25}".prop_map(|s| (TokenType::Value(Value::Int), s)),
+        ]
+    }
+
+    /// 続けて書くとひとつのトークンやコメントになってしまうか
+    fn joins(left: &str, right: &str) -> bool {
+        let (l, r) = (left.chars().last().unwrap(), right.chars().next().unwrap());
+        (super::is_letter(l) && super::is_letter(r))
+            || (matches!(left, "=" | "!") && r == '=')
+            || (l == '/' && r == '/')
+    }
+
+    proptest! {
+        #[test]
+        fn token_sequences(
+            tokens in prop::collection::vec((token(), "[ \t\r\n]{0,3}"), 0..30),
+            leading in "[ \t\r\n]{0,3}",
+        ) {
+            let mut input = leading;
+            for (i, ((_, literal), space)) in tokens.iter().enumerate() {
+                input += literal;
+                input += space;
+                let next = tokens.get(i + 1).map(|((_, next), _)| next);
+                if space.is_empty() && next.is_some_and(|next| joins(literal, next)) {
+                    input += " ";
+                }
+            }
+            let (lexed, errors) = tokenize(&input);
+            prop_assert!(errors.is_empty());
+            let lexed: Vec<(token::TokenType, String)> = lexed
+                .into_iter()
+                .map(|(tok, _)| (tok.token_type, tok.literal))
+                .filter(|(kind, _)| *kind != token::TokenType::Special(token::Special::EOF))
+                .collect();
+            let expected: Vec<(token::TokenType, String)> =
+                tokens.into_iter().map(|(token, _)| token).collect();
+            prop_assert_eq!(lexed, expected);
+        }
+
+        #[test]
+        fn arbitrary_unicode(input in "\\PC*") {
+            let (tokens, _) = tokenize(&input);
+            let len = input.chars().count();
+            prop_assert!(tokens.iter().all(|(_, span)| span.start <= span.end && span.end <= len));
+        }
+
+        #[test]
+        fn stream_matches_tokenize(input in "[a-z0-9 =!/;\n\té日$]{0,40}", size in 1usize..6) {
+            prop_assert_eq!(stream(&input, size).unwrap(), tokenize(&input).0);
+        }
+    }
+
+    /// 不正なトークンのリテラルは空文字列ではなく、読めなかった文字そのものになる
+    #[test]
+    fn illegal_literal() {
+        let (tokens, errors) = tokenize("a ? 日 $");
+        let illegal: Vec<_> = tokens
+            .iter()
+            .filter(|(tok, _)| tok.token_type == token::TokenType::Special(token::Special::Illegal))
+            .map(|(tok, _)| tok.literal.as_str())
+            .collect();
+        assert_eq!(illegal, vec!["?", "日", "$"]);
+        assert_eq!(errors.len(), 3);
+    }
+
+    /// 入力の最後の `=` や `!` は、次の文字がなくても一文字の演算子になる
+    #[test]
+    fn trailing_operator() {
+        for input in ["let x =", "!"] {
+            let (tokens, _) = tokenize(input);
+            let last = &tokens[tokens.len() - 2].0;
+            assert_eq!(last.literal, input.chars().last().unwrap().to_string());
+        }
+    }
+
+    #[test]
+    fn comments() {
+        let mut lexer = new("// head\nlet a = 1 / 2; // tail  \n//".to_string());
+        let mut kinds = vec![];
+        loop {
+            let tok = lexer.next_token();
+            if tok.token_type == token::TokenType::Special(token::Special::EOF) {
+                break;
+            }
+            kinds.push(tok.token_type.to_string());
+        }
+        assert_eq!(
+            kinds,
+            vec!["LET", "IDENT", "ASSIGN", "INT", "DIV", "INT", "SEMICOLON"]
+        );
+        assert_eq!(
+            lexer.comments(),
+            &[
+                Comment {
+                    text: "// head".to_string(),
+                    span: Span { start: 0, end: 7 },
+                },
+                Comment {
+                    text: "// tail".to_string(),
+                    span: Span { start: 23, end: 32 },
+                },
+                Comment {
+                    text: "//".to_string(),
+                    span: Span { start: 33, end: 35 },
+                },
+            ]
+        );
+        assert_eq!(tokenize("1 // x").0.len(), 2);
+    }
+
     #[test]
     fn next_token() {
         use token::*;
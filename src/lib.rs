@@ -0,0 +1,44 @@
+//! Monkey 言語の字句解析器・構文解析器・評価器と、その上に作ったツール群。
+//!
+//! ```text
+//! let (program, errors) = parser::parse("let x = 1 + 2; x");
+//! let value = evaluator::new(io::stdout()).eval_program(&program);
+//! ```
+//!
+//! `compiler` でバイトコードにすれば、`vm` で同じ結果をより速く得られる。
+//!
+//! 組み込んで使うときは `interpreter::Interpreter` が構文解析から評価までまとめて行う。
+
+#[cfg(test)]
+mod alloc_count;
+pub mod ast;
+pub mod code;
+pub mod compiler;
+pub mod diagnostic;
+pub mod dump;
+pub mod evaluator;
+pub mod formatter;
+#[cfg(any(test, feature = "fuzz"))]
+pub mod fuzz;
+pub mod gc;
+pub mod highlight;
+pub mod incremental;
+pub mod interpreter;
+pub mod lexer;
+pub mod limits;
+pub mod lint;
+pub mod lsp;
+pub mod mkc;
+pub mod object;
+pub mod optimizer;
+pub mod parser;
+pub mod printer;
+pub mod resolver;
+pub mod span;
+pub mod symbol;
+pub mod token;
+pub mod vm;
+
+#[cfg(test)]
+#[global_allocator]
+static ALLOCATOR: alloc_count::Counter = alloc_count::Counter;
@@ -0,0 +1,212 @@
+//! 信頼できないプログラムを評価するときの上限。
+//!
+//! 上限に達すると評価を打ち切り、上限ごとに異なるコードの実行時エラーを返す。
+
+use std::time::{Duration, Instant};
+
+use crate::diagnostic::{self, Diagnostic};
+use crate::gc::Heap;
+use crate::span::Span;
+
+/// 評価の手数を使い切った
+pub const E_FUEL_EXHAUSTED: &str = "E0210";
+/// 関数呼び出しが深すぎる
+pub const E_CALL_DEPTH: &str = "E0211";
+/// 生きているオブジェクトが多すぎる
+pub const E_OBJECT_LIMIT: &str = "E0212";
+/// 制限時間を過ぎた
+pub const E_DEADLINE: &str = "E0213";
+/// 式の入れ子が深すぎる
+pub const E_NESTING: &str = "E0214";
+
+/// 呼び出しの深さの既定の上限。止まらない再帰を打ち切るために、設定しなくてもかける。
+/// 評価器は足りなくなるとスタックを継ぎ足すので、この深さまでホストのスタックは溢れない。
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 10_000;
+
+/// 式の入れ子の既定の上限。構文解析器と、最適化器・コンパイラ・評価器が同じ数え方でかける。
+///
+/// 数えるのは本当に再帰する入れ子だけで、`1 + 2 + 3` や `f(1)(2)` のような左結合の連なりは
+/// 何段続いても一段と数える。連なりの構文木は深くなるが、構文木をたどる処理はどれも
+/// スタックが足りなくなればヒープに継ぎ足すので溢れない。木の高さは `MAX_EXPRESSION_HEIGHT` で抑える。
+/// 解析器は括弧も一段と数えるので、解析できたプログラムはたどる側の上限には達しない。
+pub const DEFAULT_MAX_NESTING: usize = 200;
+
+/// 構文解析器が受け付ける式の木の高さ。連なりも一段ずつ数える。
+/// 構文木の `Drop` や `Clone` は導出したものでスタックを継ぎ足せないので、入れ子の上限とは別に
+/// 設定によらずかける。
+pub const MAX_EXPRESSION_HEIGHT: usize = 1000;
+
+/// 残りのスタックがこれより少なくなったら、継ぎ足したスタックで続ける
+const STACK_RED_ZONE: usize = 64 * 1024;
+/// 一度に継ぎ足すスタックの大きさ
+const STACK_SEGMENT: usize = 1024 * 1024;
+
+/// 構文木をたどる再帰の一段ごとに通す。スタックが足りなければヒープに継ぎ足して `f` を呼ぶ
+pub fn grow_stack<R>(f: impl FnOnce() -> R) -> R {
+    stacker::maybe_grow(STACK_RED_ZONE, STACK_SEGMENT, f)
+}
+
+/// `None` は上限なし
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Limits {
+    /// 評価できる式の数
+    pub fuel: Option<u64>,
+    pub max_call_depth: Option<usize>,
+    /// 式の入れ子の深さ。`Interpreter` は構文解析にも同じ上限をかける
+    pub max_nesting: Option<usize>,
+    /// 同時に生きていられるオブジェクトの数。`gc::Stats::heap_objects` と同じく、ごみ集めが
+    /// 覚えている環境・関数値・クロージャ・セルを数える。達したらごみを集めて数え直すので、
+    /// ごみは数えない。今の言語にはコレクションの値がないので、これがメモリの上限になる。
+    pub max_objects: Option<usize>,
+    /// 評価を始めてからの制限時間
+    pub timeout: Option<Duration>,
+}
+
+impl Default for Limits {
+    fn default() -> Limits {
+        Limits {
+            fuel: None,
+            max_call_depth: Some(DEFAULT_MAX_CALL_DEPTH),
+            max_nesting: Some(DEFAULT_MAX_NESTING),
+            max_objects: None,
+            timeout: None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Limit {
+    Fuel,
+    CallDepth,
+    Objects,
+    Deadline,
+}
+
+impl Limit {
+    pub fn code(&self) -> &'static str {
+        match self {
+            Limit::Fuel => E_FUEL_EXHAUSTED,
+            Limit::CallDepth => E_CALL_DEPTH,
+            Limit::Objects => E_OBJECT_LIMIT,
+            Limit::Deadline => E_DEADLINE,
+        }
+    }
+
+    pub fn from_code(code: &str) -> Option<Limit> {
+        [
+            Limit::Fuel,
+            Limit::CallDepth,
+            Limit::Objects,
+            Limit::Deadline,
+        ]
+        .into_iter()
+        .find(|limit| limit.code() == code)
+    }
+
+    pub fn diagnostic(&self, limits: &Limits, span: Span) -> Diagnostic {
+        let message = match self {
+            Limit::Fuel => format!("ran out of fuel after {} steps", limits.fuel.unwrap_or(0)),
+            Limit::CallDepth => format!(
+                "call depth exceeded the limit of {}",
+                limits.max_call_depth.unwrap_or(0)
+            ),
+            Limit::Objects => format!(
+                "cannot keep more than {} objects alive",
+                limits.max_objects.unwrap_or(0)
+            ),
+            Limit::Deadline => format!(
+                "evaluation took longer than {:?}",
+                limits.timeout.unwrap_or_default()
+            ),
+        };
+        diagnostic::error(self.code(), message, span)
+    }
+}
+
+/// 式の入れ子が上限 `max_nesting` を超えたときのエラー
+pub fn nesting_too_deep(max_nesting: usize, span: Span) -> Diagnostic {
+    diagnostic::error(
+        E_NESTING,
+        format!(
+            "expressions are nested more than {} levels deep",
+            max_nesting
+        ),
+        span,
+    )
+}
+
+/// 時刻を調べる間隔。毎回 `Instant::now` を呼ぶと遅いので、この手数ごとに調べる。
+const CLOCK_INTERVAL: u64 = 1024;
+
+/// ひとつの評価で使った量
+#[derive(Debug, Clone)]
+pub struct Budget {
+    limits: Limits,
+    steps: u64,
+    depth: usize,
+    deadline: Option<Instant>,
+}
+
+impl Budget {
+    /// 制限時間はここから数える
+    pub fn new(limits: Limits) -> Budget {
+        Budget {
+            limits,
+            steps: 0,
+            depth: 0,
+            deadline: limits.timeout.map(|timeout| Instant::now() + timeout),
+        }
+    }
+
+    fn exceeded(&self, limit: Limit, span: Span) -> Result<(), Diagnostic> {
+        Err(limit.diagnostic(&self.limits, span))
+    }
+
+    /// 式をひとつ評価する前に呼ぶ。`span` は上限に達したときのエラーの位置。
+    pub fn step(&mut self, span: Span) -> Result<(), Diagnostic> {
+        self.steps += 1;
+        if self.limits.fuel.is_some_and(|fuel| self.steps > fuel) {
+            return self.exceeded(Limit::Fuel, span);
+        }
+        if self.steps.is_multiple_of(CLOCK_INTERVAL)
+            && self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+        {
+            return self.exceeded(Limit::Deadline, span);
+        }
+        Ok(())
+    }
+
+    /// 関数を呼び出す前に呼ぶ。成功したら戻るときに `leave` を呼ぶ。
+    pub fn enter(&mut self, span: Span) -> Result<(), Diagnostic> {
+        if self
+            .limits
+            .max_call_depth
+            .is_some_and(|max| self.depth >= max)
+        {
+            return self.exceeded(Limit::CallDepth, span);
+        }
+        self.depth += 1;
+        Ok(())
+    }
+
+    pub fn leave(&mut self) {
+        self.depth -= 1;
+    }
+
+    /// `heap` に確保する前に呼ぶ。覚えている数が上限に達していたら、ごみを集めて数え直す。
+    /// 生きている数が上限近くにとどまると確保のたびに集めることになる
+    pub fn allocate(&mut self, heap: &mut Heap, span: Span) -> Result<(), Diagnostic> {
+        let Some(max) = self.limits.max_objects else {
+            return Ok(());
+        };
+        if heap.tracked() >= max {
+            heap.collect();
+            if heap.tracked() >= max {
+                return self.exceeded(Limit::Objects, span);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,388 @@
+//! `learn-interpreter lint` の規則。
+//!
+//! 規則はプロジェクトのディレクトリに置いた `monkeylint.json` で個別に無効にできる。
+//!
+//! ```text
+//! { "rules": { "shadowed-binding": false } }
+//! ```
+
+use std::path::Path;
+use std::{fs, io};
+
+use crate::ast::*;
+use crate::diagnostic::{self, Diagnostic};
+use crate::limits;
+use crate::resolver::{self, BindingKind};
+use crate::span::{LineIndex, Span};
+use crate::token::Operator;
+
+pub const CONFIG_FILE: &str = "monkeylint.json";
+
+pub const UNUSED_BINDING: &str = "unused-binding";
+pub const SHADOWED_BINDING: &str = "shadowed-binding";
+pub const UNREACHABLE_CODE: &str = "unreachable-code";
+pub const CONSTANT_CONDITION: &str = "constant-condition";
+pub const SELF_COMPARISON: &str = "self-comparison";
+pub const MISSING_RETURN: &str = "missing-return";
+
+pub const RULES: [&str; 6] = [
+    UNUSED_BINDING,
+    SHADOWED_BINDING,
+    UNREACHABLE_CODE,
+    CONSTANT_CONDITION,
+    SELF_COMPARISON,
+    MISSING_RETURN,
+];
+
+/// 有効な規則の一覧。既定ではすべて有効。
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Config {
+    disabled: Vec<&'static str>,
+}
+
+impl Config {
+    pub fn is_enabled(&self, rule: &str) -> bool {
+        !self.disabled.contains(&rule)
+    }
+
+    pub fn parse(json: &str) -> Result<Config, String> {
+        let value: serde_json::Value = serde_json::from_str(json).map_err(|e| e.to_string())?;
+        let mut config = Config::default();
+        let rules = match value.get("rules") {
+            Some(rules) => rules
+                .as_object()
+                .ok_or_else(|| "`rules` must be an object".to_string())?,
+            None => return Ok(config),
+        };
+        for (name, enabled) in rules {
+            let rule = RULES
+                .iter()
+                .find(|r| *r == name)
+                .ok_or_else(|| format!("unknown rule `{}`", name))?;
+            match enabled.as_bool() {
+                Some(true) => {}
+                Some(false) => config.disabled.push(rule),
+                None => return Err(format!("rule `{}` must be true or false", name)),
+            }
+        }
+        Ok(config)
+    }
+
+    /// `dir` から親へたどって最初に見つかった設定ファイルを読む。なければ既定の設定。
+    pub fn find(dir: &Path) -> Result<Config, String> {
+        for dir in dir.ancestors() {
+            let path = dir.join(CONFIG_FILE);
+            match fs::read_to_string(&path) {
+                Ok(json) => {
+                    return Config::parse(&json).map_err(|e| format!("{}: {}", path.display(), e))
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(format!("{}: {}", path.display(), e)),
+            }
+        }
+        Ok(Config::default())
+    }
+}
+
+/// 警告をソース上の位置順に返す
+pub fn lint(program: &Program, source: &str, config: &Config) -> Vec<Diagnostic> {
+    let mut l = Linter {
+        warnings: vec![],
+        index: LineIndex::new(source),
+    };
+    l.bindings(program);
+    l.statements(&program.statements);
+    let mut warnings: Vec<Diagnostic> = l
+        .warnings
+        .into_iter()
+        .filter(|w| config.is_enabled(w.code))
+        .collect();
+    warnings.sort_by_key(|w| w.span.start);
+    warnings
+}
+
+struct Linter {
+    warnings: Vec<Diagnostic>,
+    index: LineIndex,
+}
+
+impl Linter {
+    fn warn(&mut self, rule: &'static str, message: String, span: Span) -> &mut Diagnostic {
+        self.warnings.push(diagnostic::warning(rule, message, span));
+        self.warnings.last_mut().unwrap()
+    }
+
+    fn bindings(&mut self, program: &Program) {
+        let resolution = resolver::resolve(program);
+        for binding in &resolution.bindings {
+            if binding.kind == BindingKind::Let
+                && binding.references.is_empty()
+                && !binding.name.as_str().starts_with('_')
+            {
+                self.warn(
+                    UNUSED_BINDING,
+                    format!("unused binding `{}`", binding.name),
+                    binding.span,
+                )
+                .help = Some(format!(
+                    "remove it, or rename it to `_{}` if it is intentional",
+                    binding.name
+                ));
+            }
+            if let Some(shadowed) = binding.shadows {
+                let pos = self
+                    .index
+                    .position(resolution.bindings[shadowed].span.start);
+                self.warn(
+                    SHADOWED_BINDING,
+                    format!("`{}` shadows an earlier binding", binding.name),
+                    binding.span,
+                )
+                .notes
+                .push(format!("previous binding at {}:{}", pos.line, pos.column));
+            }
+        }
+    }
+
+    fn statements(&mut self, statements: &[Statement]) {
+        let mut returned = false;
+        for (i, statement) in statements.iter().enumerate() {
+            if returned {
+                let end = statements.last().unwrap().span.end;
+                self.warn(
+                    UNREACHABLE_CODE,
+                    "unreachable code after `return`".to_string(),
+                    Span {
+                        start: statement.span.start,
+                        end,
+                    },
+                );
+                // 到達しない文の中も他の規則では調べる
+                for statement in &statements[i..] {
+                    self.statement(statement);
+                }
+                return;
+            }
+            self.statement(statement);
+            returned = matches!(statement.kind, StatementKind::Return(_));
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        match &statement.kind {
+            StatementKind::Let { value, .. } => self.expression(value),
+            StatementKind::Return(value) | StatementKind::Expression(value) => {
+                self.expression(value)
+            }
+        }
+    }
+
+    fn expression(&mut self, expression: &Expression) {
+        limits::grow_stack(|| match &expression.kind {
+            ExpressionKind::Identifier(_)
+            | ExpressionKind::Integer(_)
+            | ExpressionKind::Boolean(_) => {}
+            ExpressionKind::Prefix { right, .. } => self.expression(right),
+            ExpressionKind::Infix {
+                operator,
+                left,
+                right,
+            } => {
+                if let (ExpressionKind::Identifier(l), ExpressionKind::Identifier(r)) =
+                    (&left.kind, &right.kind)
+                {
+                    if l == r
+                        && matches!(
+                            operator,
+                            Operator::Eq | Operator::NotEq | Operator::Lt | Operator::Gt
+                        )
+                    {
+                        self.warn(
+                            SELF_COMPARISON,
+                            format!("`{}` is compared with itself", l),
+                            expression.span,
+                        );
+                    }
+                }
+                self.expression(left);
+                self.expression(right);
+            }
+            ExpressionKind::If {
+                condition,
+                consequence,
+                alternative,
+            } => {
+                if let Some(value) = constant_truthiness(condition) {
+                    self.warn(
+                        CONSTANT_CONDITION,
+                        format!("condition is always {}", value),
+                        condition.span,
+                    );
+                }
+                self.expression(condition);
+                self.statements(&consequence.statements);
+                if let Some(alternative) = alternative {
+                    self.statements(&alternative.statements);
+                }
+            }
+            ExpressionKind::Function { body, .. } => {
+                if !yields_value(&body.statements) {
+                    self.warn(
+                        MISSING_RETURN,
+                        "function does not return a value on every path".to_string(),
+                        expression.span,
+                    )
+                    .notes
+                    .push("a path that ends without a value returns null".to_string());
+                }
+                self.statements(&body.statements);
+            }
+            ExpressionKind::Call {
+                function,
+                arguments,
+            } => {
+                self.expression(function);
+                for argument in arguments {
+                    self.expression(argument);
+                }
+            }
+        })
+    }
+}
+
+/// リテラルだけでできた条件の真偽
+fn constant_truthiness(condition: &Expression) -> Option<bool> {
+    match &condition.kind {
+        ExpressionKind::Boolean(value) => Some(*value),
+        // 整数は 0 も含めてすべて真
+        ExpressionKind::Integer(_) => Some(true),
+        ExpressionKind::Prefix {
+            operator: Operator::Not,
+            right,
+        } => constant_truthiness(right).map(|value| !value),
+        _ => None,
+    }
+}
+
+/// どの経路でも `return` か最後の式で値を返すか
+fn yields_value(statements: &[Statement]) -> bool {
+    for (i, statement) in statements.iter().enumerate() {
+        let last = i == statements.len() - 1;
+        match &statement.kind {
+            StatementKind::Return(_) => return true,
+            StatementKind::Expression(Expression {
+                kind:
+                    ExpressionKind::If {
+                        consequence,
+                        alternative: Some(alternative),
+                        ..
+                    },
+                ..
+            }) if always_returns(&consequence.statements)
+                && always_returns(&alternative.statements) =>
+            {
+                return true
+            }
+            StatementKind::Expression(Expression {
+                kind:
+                    ExpressionKind::If {
+                        consequence,
+                        alternative,
+                        ..
+                    },
+                ..
+            }) if last => {
+                return match alternative {
+                    Some(alternative) => {
+                        yields_value(&consequence.statements)
+                            && yields_value(&alternative.statements)
+                    }
+                    None => false,
+                }
+            }
+            StatementKind::Expression(_) if last => return true,
+            _ => {}
+        }
+    }
+    false
+}
+
+/// どの経路でも `return` に行き着くか
+fn always_returns(statements: &[Statement]) -> bool {
+    statements.iter().any(|statement| match &statement.kind {
+        StatementKind::Return(_) => true,
+        StatementKind::Expression(Expression {
+            kind:
+                ExpressionKind::If {
+                    consequence,
+                    alternative: Some(alternative),
+                    ..
+                },
+            ..
+        }) => always_returns(&consequence.statements) && always_returns(&alternative.statements),
+        _ => false,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser;
+
+    fn rules(input: &str, config: &Config) -> Vec<(&'static str, String)> {
+        let (program, errors) = parser::parse(input);
+        assert_eq!(errors, vec![]);
+        lint(&program, input, config)
+            .into_iter()
+            .map(|w| {
+                let text: String = input
+                    .chars()
+                    .skip(w.span.start)
+                    .take(w.span.end - w.span.start)
+                    .collect();
+                (w.code, text)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn every_rule() {
+        let input = "
+let unused = 1;
+let _ignored = 2;
+let x = 3;
+let f = fn(x) {
+    if (true) { return x; }
+    return 0;
+    x;
+};
+let g = fn(y) { if (y == y) { 1 } };
+let h = fn(n) { if (n < 1) { return 0; } h(n - 1) };
+f(g(h(x)));
+";
+        assert_eq!(
+            rules(input, &Config::default()),
+            vec![
+                (UNUSED_BINDING, "unused".to_string()),
+                (SHADOWED_BINDING, "x".to_string()),
+                (CONSTANT_CONDITION, "true".to_string()),
+                (UNREACHABLE_CODE, "x;".to_string()),
+                (MISSING_RETURN, "fn(y) { if (y == y) { 1 } }".to_string()),
+                (SELF_COMPARISON, "y == y".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn config() {
+        let config = Config::parse(r#"{ "rules": { "unused-binding": false } }"#).unwrap();
+        assert!(!config.is_enabled(UNUSED_BINDING));
+        assert!(config.is_enabled(SHADOWED_BINDING));
+        assert_eq!(rules("let a = 1;", &config), vec![]);
+
+        assert_eq!(
+            Config::parse(r#"{ "rules": { "no-such-rule": false } }"#),
+            Err("unknown rule `no-such-rule`".to_string())
+        );
+    }
+}
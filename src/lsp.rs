@@ -0,0 +1,677 @@
+//! 標準入出力で JSON-RPC を話す Language Server。`learn-interpreter lsp` で起動する。
+//!
+//! 文書は変更のたびに全文を受け取り (`TextDocumentSyncKind.Full`)、そのつど解析し直す。
+//! 位置は LSP の決まりどおり 0 始まりの行と UTF-16 単位の列でやりとりする。
+
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use serde_json::{json, Value};
+
+use crate::ast::*;
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::highlight::{self, Category};
+use crate::limits;
+use crate::resolver::{self, BindingKind, Resolution};
+use crate::span::{LineIndex, Span};
+use crate::token;
+use crate::{formatter, parser, printer};
+
+const METHOD_NOT_FOUND: i64 = -32601;
+const PARSE_ERROR: i64 = -32700;
+
+/// `reader` からメッセージを読み、応答を `writer` に書く。`exit` を受け取るか入力が終わると戻る。
+pub fn run(mut reader: impl BufRead, mut writer: impl Write) -> io::Result<()> {
+    let mut server = Server::default();
+    while let Some(body) = read_message(&mut reader)? {
+        let replies = match serde_json::from_str(&body) {
+            Ok(message) => server.handle(message),
+            Err(e) => vec![json!({
+                "jsonrpc": "2.0",
+                "id": null,
+                "error": { "code": PARSE_ERROR, "message": e.to_string() },
+            })],
+        };
+        for reply in replies {
+            write_message(&mut writer, &reply)?;
+        }
+        if server.exited {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// `Content-Length` ヘッダ付きのメッセージを一つ読む。入力が終わっていれば `None`。
+fn read_message(reader: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut length = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok();
+        }
+    }
+    let length = length.ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length header")
+    })?;
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    String::from_utf8(body)
+        .map(Some)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn write_message(writer: &mut impl Write, message: &Value) -> io::Result<()> {
+    let body = message.to_string();
+    write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    writer.flush()
+}
+
+#[derive(Default)]
+pub struct Server {
+    documents: HashMap<String, String>,
+    exited: bool,
+}
+
+impl Server {
+    /// メッセージを一つ処理し、返すべき応答と通知を返す
+    pub fn handle(&mut self, message: Value) -> Vec<Value> {
+        let method = message["method"].as_str().unwrap_or("").to_string();
+        let params = &message["params"];
+        let id = match message.get("id") {
+            Some(id) => id.clone(),
+            None => return self.notification(&method, params),
+        };
+        let result = match method.as_str() {
+            "initialize" => {
+                let token_types: Vec<&str> = Category::ALL.iter().map(Category::name).collect();
+                json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "hoverProvider": true,
+                    "documentFormattingProvider": true,
+                    "semanticTokensProvider": {
+                        "legend": { "tokenTypes": token_types, "tokenModifiers": [] },
+                        "full": true,
+                    },
+                },
+                "serverInfo": { "name": "learn-interpreter" },
+                })
+            }
+            "shutdown" => Value::Null,
+            "textDocument/definition" => self.definition(params),
+            "textDocument/references" => self.references(params),
+            "textDocument/hover" => self.hover(params),
+            "textDocument/semanticTokens/full" => self.semantic_tokens(params),
+            "textDocument/formatting" => self.formatting(params),
+            _ => {
+                return vec![json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "error": {
+                        "code": METHOD_NOT_FOUND,
+                        "message": format!("unknown method `{}`", method),
+                    },
+                })]
+            }
+        };
+        vec![json!({ "jsonrpc": "2.0", "id": id, "result": result })]
+    }
+
+    fn notification(&mut self, method: &str, params: &Value) -> Vec<Value> {
+        let uri = params["textDocument"]["uri"]
+            .as_str()
+            .unwrap_or("")
+            .to_string();
+        match method {
+            "exit" => {
+                self.exited = true;
+                vec![]
+            }
+            "textDocument/didOpen" => {
+                let text = params["textDocument"]["text"].as_str().unwrap_or("");
+                self.documents.insert(uri.clone(), text.to_string());
+                vec![self.publish_diagnostics(&uri)]
+            }
+            "textDocument/didChange" => {
+                // 全文同期なので最後の変更が文書全体になる
+                if let Some(text) = params["contentChanges"]
+                    .as_array()
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change["text"].as_str())
+                {
+                    self.documents.insert(uri.clone(), text.to_string());
+                }
+                vec![self.publish_diagnostics(&uri)]
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                vec![json!({
+                    "jsonrpc": "2.0",
+                    "method": "textDocument/publishDiagnostics",
+                    "params": { "uri": uri, "diagnostics": [] },
+                })]
+            }
+            _ => vec![],
+        }
+    }
+
+    fn document<'a>(&'a self, params: &'a Value) -> Option<(&'a str, Document<'a>)> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        let text = self.documents.get(uri)?;
+        Some((uri, Document::new(text)))
+    }
+
+    fn publish_diagnostics(&self, uri: &str) -> Value {
+        let text = self.documents.get(uri).map(String::as_str).unwrap_or("");
+        let document = Document::new(text);
+        let diagnostics: Vec<Value> = document
+            .errors
+            .iter()
+            .map(|d| diagnostic_json(&document, d))
+            .collect();
+        json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/publishDiagnostics",
+            "params": { "uri": uri, "diagnostics": diagnostics },
+        })
+    }
+
+    fn definition(&self, params: &Value) -> Value {
+        let (uri, document) = match self.document(params) {
+            Some(document) => document,
+            None => return Value::Null,
+        };
+        let binding = match document.binding_at(&params["position"]) {
+            Some(binding) => &document.resolution.bindings[binding],
+            None => return Value::Null,
+        };
+        json!({ "uri": uri, "range": document.range(binding.span) })
+    }
+
+    fn references(&self, params: &Value) -> Value {
+        let (uri, document) = match self.document(params) {
+            Some(document) => document,
+            None => return Value::Null,
+        };
+        let binding = match document.binding_at(&params["position"]) {
+            Some(binding) => &document.resolution.bindings[binding],
+            None => return json!([]),
+        };
+        let mut spans = vec![];
+        if params["context"]["includeDeclaration"]
+            .as_bool()
+            .unwrap_or(true)
+        {
+            spans.push(binding.span);
+        }
+        spans.extend(&binding.references);
+        let locations: Vec<Value> = spans
+            .into_iter()
+            .map(|span| json!({ "uri": uri, "range": document.range(span) }))
+            .collect();
+        json!(locations)
+    }
+
+    fn hover(&self, params: &Value) -> Value {
+        let (_, document) = match self.document(params) {
+            Some(document) => document,
+            None => return Value::Null,
+        };
+        let id = match document.binding_at(&params["position"]) {
+            Some(binding) => binding,
+            None => return Value::Null,
+        };
+        let binding = &document.resolution.bindings[id];
+        let text = match binding.kind {
+            BindingKind::Parameter => format!("(parameter) {}", binding.name),
+            BindingKind::Let => {
+                let value = document.values.get(&binding.span.start);
+                let ty = value
+                    .map(|value| document.infer(value, 0))
+                    .unwrap_or(Type::Unknown);
+                match value {
+                    Some(value) if is_literal(value) => format!(
+                        "let {}: {} = {}",
+                        binding.name,
+                        ty,
+                        printer::print_expression(value, printer::Mode::Normal)
+                    ),
+                    _ => format!("let {}: {}", binding.name, ty),
+                }
+            }
+        };
+        json!({
+            "contents": { "kind": "markdown", "value": format!("```monkey\n{}\n```", text) },
+            "range": document.range(binding.span),
+        })
+    }
+
+    fn semantic_tokens(&self, params: &Value) -> Value {
+        let (_, document) = match self.document(params) {
+            Some(document) => document,
+            None => return Value::Null,
+        };
+        let ranges = highlight::highlight(document.text).into_iter().map(|h| {
+            // 凡例は Category::ALL の並び
+            let kind = Category::ALL.iter().position(|c| *c == h.category).unwrap();
+            (h.span, kind)
+        });
+
+        let mut data = vec![];
+        let (mut last_line, mut last_character) = (0, 0);
+        for (span, kind) in ranges {
+            let start = document.position(span.start);
+            let end = document.position(span.end);
+            if start.0 != end.0 {
+                continue;
+            }
+            let delta_line = start.0 - last_line;
+            let delta_character = if delta_line == 0 {
+                start.1 - last_character
+            } else {
+                start.1
+            };
+            data.extend([delta_line, delta_character, end.1 - start.1, kind, 0]);
+            (last_line, last_character) = start;
+        }
+        json!({ "data": data })
+    }
+
+    fn formatting(&self, params: &Value) -> Value {
+        let (_, document) = match self.document(params) {
+            Some(document) => document,
+            None => return Value::Null,
+        };
+        match formatter::format(document.text) {
+            Ok(formatted) if formatted != document.text => {
+                let end = document.text.chars().count();
+                json!([{
+                    "range": document.range(Span { start: 0, end }),
+                    "newText": formatted,
+                }])
+            }
+            Ok(_) => json!([]),
+            // 構文エラーがあるうちは整形しない
+            Err(_) => Value::Null,
+        }
+    }
+}
+
+fn diagnostic_json(document: &Document, diagnostic: &Diagnostic) -> Value {
+    let mut message = diagnostic.message.clone();
+    for note in &diagnostic.notes {
+        message += &format!("\nnote: {}", note);
+    }
+    if let Some(help) = &diagnostic.help {
+        message += &format!("\nhelp: {}", help);
+    }
+    json!({
+        "range": document.range(diagnostic.span),
+        "severity": match diagnostic.severity {
+            Severity::Error => 1,
+            Severity::Warning => 2,
+        },
+        "code": diagnostic.code,
+        "source": "learn-interpreter",
+        "message": message,
+    })
+}
+
+/// 解析済みの文書
+struct Document<'a> {
+    text: &'a str,
+    lines: Vec<&'a str>,
+    index: LineIndex,
+    errors: Vec<Diagnostic>,
+    resolution: Resolution,
+    /// `let` で束縛する識別子の開始位置から、束縛する値への対応
+    values: HashMap<usize, Expression>,
+}
+
+impl<'a> Document<'a> {
+    fn new(text: &'a str) -> Document<'a> {
+        let (program, errors) = parser::parse(text);
+        let mut values = HashMap::new();
+        collect_values(&program.statements, &mut values);
+        Document {
+            text,
+            lines: text.split('\n').collect(),
+            index: LineIndex::new(text),
+            errors,
+            resolution: resolver::resolve(&program),
+            values,
+        }
+    }
+
+    /// 文字単位のオフセットを 0 始まりの行と UTF-16 単位の列にする
+    fn position(&self, offset: usize) -> (usize, usize) {
+        let pos = self.index.position(offset);
+        let line = self.lines.get(pos.line - 1).copied().unwrap_or("");
+        let character = line.chars().take(pos.column - 1).map(char::len_utf16).sum();
+        (pos.line - 1, character)
+    }
+
+    /// LSP の位置を文字単位のオフセットにする
+    fn offset(&self, position: &Value) -> Option<usize> {
+        let line = position["line"].as_u64()? as usize;
+        let character = position["character"].as_u64()? as usize;
+        let text = self.lines.get(line)?;
+        let start: usize = self.lines[..line]
+            .iter()
+            .map(|l| l.chars().count() + 1)
+            .sum();
+        let mut units = 0;
+        let mut column = 0;
+        for c in text.chars() {
+            if units >= character {
+                break;
+            }
+            units += c.len_utf16();
+            column += 1;
+        }
+        Some(start + column)
+    }
+
+    fn range(&self, span: Span) -> Value {
+        let (start_line, start_character) = self.position(span.start);
+        let (end_line, end_character) = self.position(span.end);
+        json!({
+            "start": { "line": start_line, "character": start_character },
+            "end": { "line": end_line, "character": end_character },
+        })
+    }
+
+    fn binding_at(&self, position: &Value) -> Option<usize> {
+        self.resolution.binding_at(self.offset(position)?)
+    }
+
+    /// 式の型を推論する。`depth` は束縛をたどった回数で、循環した定義で止まらなくならないように制限する。
+    fn infer(&self, expression: &Expression, depth: usize) -> Type {
+        match &expression.kind {
+            ExpressionKind::Integer(_) => Type::Int,
+            ExpressionKind::Boolean(_) => Type::Bool,
+            ExpressionKind::Prefix { operator, .. } => match operator {
+                token::Operator::Not => Type::Bool,
+                _ => Type::Int,
+            },
+            ExpressionKind::Infix { operator, .. } => match operator {
+                token::Operator::Plus
+                | token::Operator::Minus
+                | token::Operator::Mul
+                | token::Operator::Div => Type::Int,
+                _ => Type::Bool,
+            },
+            ExpressionKind::Function { parameters, .. } => {
+                Type::Function(parameters.iter().map(|p| p.name.to_string()).collect())
+            }
+            ExpressionKind::Identifier(_) if depth < 16 => {
+                let value = self
+                    .resolution
+                    .binding_at(expression.span.start)
+                    .map(|binding| &self.resolution.bindings[binding])
+                    .filter(|binding| binding.kind == BindingKind::Let)
+                    .and_then(|binding| self.values.get(&binding.span.start));
+                match value {
+                    Some(value) => self.infer(value, depth + 1),
+                    None => Type::Unknown,
+                }
+            }
+            _ => Type::Unknown,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Type {
+    Int,
+    Bool,
+    Function(Vec<String>),
+    Unknown,
+}
+
+impl std::fmt::Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Type::Int => write!(f, "int"),
+            Type::Bool => write!(f, "bool"),
+            Type::Function(parameters) => write!(f, "fn({})", parameters.join(", ")),
+            Type::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+fn is_literal(expression: &Expression) -> bool {
+    matches!(
+        expression.kind,
+        ExpressionKind::Integer(_) | ExpressionKind::Boolean(_)
+    )
+}
+
+fn collect_values(statements: &[Statement], values: &mut HashMap<usize, Expression>) {
+    for statement in statements {
+        match &statement.kind {
+            StatementKind::Let { name, value } => {
+                values.insert(name.span.start, value.clone());
+                collect_expression_values(value, values);
+            }
+            StatementKind::Return(value) | StatementKind::Expression(value) => {
+                collect_expression_values(value, values)
+            }
+        }
+    }
+}
+
+fn collect_expression_values(expression: &Expression, values: &mut HashMap<usize, Expression>) {
+    limits::grow_stack(|| match &expression.kind {
+        ExpressionKind::Prefix { right, .. } => collect_expression_values(right, values),
+        ExpressionKind::Infix { left, right, .. } => {
+            collect_expression_values(left, values);
+            collect_expression_values(right, values);
+        }
+        ExpressionKind::If {
+            condition,
+            consequence,
+            alternative,
+        } => {
+            collect_expression_values(condition, values);
+            collect_values(&consequence.statements, values);
+            if let Some(alternative) = alternative {
+                collect_values(&alternative.statements, values);
+            }
+        }
+        ExpressionKind::Function { body, .. } => collect_values(&body.statements, values),
+        ExpressionKind::Call {
+            function,
+            arguments,
+        } => {
+            collect_expression_values(function, values);
+            for argument in arguments {
+                collect_expression_values(argument, values);
+            }
+        }
+        ExpressionKind::Identifier(_) | ExpressionKind::Integer(_) | ExpressionKind::Boolean(_) => {
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::{run, Server};
+
+    const URI: &str = "file:///main.mk";
+
+    fn open(server: &mut Server, text: &str) -> Vec<Value> {
+        server.handle(json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didOpen",
+            "params": {
+                "textDocument": { "uri": URI, "languageId": "monkey", "version": 1, "text": text },
+            },
+        }))
+    }
+
+    fn request(server: &mut Server, method: &str, params: Value) -> Value {
+        let mut replies = server.handle(json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": params,
+        }));
+        assert_eq!(replies.len(), 1);
+        replies.remove(0)["result"].take()
+    }
+
+    fn at(line: usize, character: usize) -> Value {
+        json!({
+            "textDocument": { "uri": URI },
+            "position": { "line": line, "character": character },
+        })
+    }
+
+    fn range(line: usize, start: usize, end: usize) -> Value {
+        json!({
+            "start": { "line": line, "character": start },
+            "end": { "line": line, "character": end },
+        })
+    }
+
+    #[test]
+    fn diagnostics() {
+        let mut server = Server::default();
+        let replies = open(&mut server, "let x = 1;\nlet = 2;");
+        assert_eq!(replies[0]["method"], "textDocument/publishDiagnostics");
+        let diagnostics = &replies[0]["params"]["diagnostics"];
+        assert_eq!(diagnostics.as_array().unwrap().len(), 1);
+        assert_eq!(diagnostics[0]["range"], range(1, 4, 5));
+        assert_eq!(diagnostics[0]["code"], "E0100");
+        assert_eq!(diagnostics[0]["severity"], 1);
+
+        let replies = server.handle(json!({
+            "jsonrpc": "2.0",
+            "method": "textDocument/didChange",
+            "params": {
+                "textDocument": { "uri": URI, "version": 2 },
+                "contentChanges": [{ "text": "let x = 1;" }],
+            },
+        }));
+        assert_eq!(replies[0]["params"]["diagnostics"], json!([]));
+    }
+
+    #[test]
+    fn navigation() {
+        let mut server = Server::default();
+        open(
+            &mut server,
+            "let x = 5;\nlet add = fn(a, b) { a + b };\nadd(x, x);",
+        );
+
+        assert_eq!(
+            request(&mut server, "textDocument/definition", at(2, 4)),
+            json!({ "uri": URI, "range": range(0, 4, 5) })
+        );
+        let mut params = at(0, 4);
+        params["context"] = json!({ "includeDeclaration": false });
+        assert_eq!(
+            request(&mut server, "textDocument/references", params),
+            json!([
+                { "uri": URI, "range": range(2, 4, 5) },
+                { "uri": URI, "range": range(2, 7, 8) },
+            ])
+        );
+
+        let hover = request(&mut server, "textDocument/hover", at(2, 4));
+        assert_eq!(hover["contents"]["value"], "```monkey\nlet x: int = 5\n```");
+        let hover = request(&mut server, "textDocument/hover", at(2, 0));
+        assert_eq!(
+            hover["contents"]["value"],
+            "```monkey\nlet add: fn(a, b)\n```"
+        );
+        let hover = request(&mut server, "textDocument/hover", at(1, 21));
+        assert_eq!(hover["contents"]["value"], "```monkey\n(parameter) a\n```");
+        assert_eq!(
+            request(&mut server, "textDocument/hover", at(0, 9)),
+            Value::Null
+        );
+    }
+
+    #[test]
+    fn semantic_tokens_and_formatting() {
+        let mut server = Server::default();
+        open(&mut server, "// あ\nlet  x=1;");
+        let tokens = request(
+            &mut server,
+            "textDocument/semanticTokens/full",
+            json!({ "textDocument": { "uri": URI } }),
+        );
+        assert_eq!(
+            tokens["data"],
+            json!([0, 0, 4, 7, 0, 1, 0, 3, 0, 0, 0, 5, 1, 3, 0, 0, 1, 1, 6, 0, 0, 1, 1, 4, 0])
+        );
+
+        let edits = request(
+            &mut server,
+            "textDocument/formatting",
+            json!({ "textDocument": { "uri": URI }, "options": { "tabSize": 4 } }),
+        );
+        assert_eq!(
+            edits,
+            json!([{
+                "range": {
+                    "start": { "line": 0, "character": 0 },
+                    "end": { "line": 1, "character": 9 },
+                },
+                "newText": "// あ\nlet x = 1;\n",
+            }])
+        );
+    }
+
+    #[test]
+    fn unknown_method() {
+        let mut server = Server::default();
+        let replies = server.handle(json!({ "jsonrpc": "2.0", "id": 7, "method": "foo/bar" }));
+        assert_eq!(replies[0]["id"], 7);
+        assert_eq!(replies[0]["error"]["code"], -32601);
+    }
+
+    #[test]
+    fn stdio_session() {
+        let messages = [
+            json!({ "jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {} }),
+            json!({ "jsonrpc": "2.0", "method": "initialized", "params": {} }),
+            json!({ "jsonrpc": "2.0", "id": 2, "method": "shutdown" }),
+            json!({ "jsonrpc": "2.0", "method": "exit" }),
+        ];
+        let mut input = String::new();
+        for message in messages {
+            let body = message.to_string();
+            input += &format!("Content-Length: {}\r\n\r\n{}", body.len(), body);
+        }
+        let mut output = vec![];
+        run(input.as_bytes(), &mut output).unwrap();
+
+        let output = String::from_utf8(output).unwrap();
+        let bodies: Vec<Value> = output
+            .split("Content-Length: ")
+            .skip(1)
+            .map(|m| serde_json::from_str(m.split_once("\r\n\r\n").unwrap().1).unwrap())
+            .collect();
+        assert_eq!(bodies.len(), 2);
+        assert_eq!(bodies[0]["result"]["capabilities"]["hoverProvider"], true);
+        assert_eq!(
+            bodies[1],
+            json!({ "jsonrpc": "2.0", "id": 2, "result": null })
+        );
+    }
+}
@@ -1,7 +1,280 @@
-mod lexer;
+use std::path::Path;
+use std::{env, fs, io, process};
+
+use learn_interpreter::{
+    ast, compiler, diagnostic, dump, evaluator, formatter, lexer, lint, lsp, mkc, optimizer,
+    parser, printer, span, vm,
+};
+
 mod repl;
-mod token;
 
-fn main() -> ! {
-    repl::start();
+const USAGE: &str = "\
+usage: learn-interpreter
+       learn-interpreter run [--vm] [--no-optimize] <file>
+       learn-interpreter run <file.mkc>
+       learn-interpreter compile [--no-optimize] <file> -o <file.mkc>
+       learn-interpreter disasm <file.mkc>
+       learn-interpreter --dump-tokens <file> [--format table|json]
+       learn-interpreter --dump-ast <file> [--format source|parens|json]
+       learn-interpreter fmt [--check] <file>...
+       learn-interpreter lint <file>...
+       learn-interpreter lsp";
+
+fn main() {
+    let mut args: Vec<String> = env::args().skip(1).collect();
+    // 最適化を切るのはデバッグ用なので、実行とコンパイルのどこに置いてもよい
+    let mut optimize = true;
+    if matches!(args.first().map(String::as_str), Some("run" | "compile")) {
+        let count = args.len();
+        args.retain(|arg| arg != "--no-optimize");
+        optimize = args.len() == count;
+    }
+    match args
+        .iter()
+        .map(String::as_str)
+        .collect::<Vec<_>>()
+        .as_slice()
+    {
+        [] => repl::start(),
+        ["run", path] if path.ends_with(".mkc") => run_bytecode(path),
+        ["run", path] => run(path, false, optimize),
+        // `.mkc` はもともと仮想機械で実行する
+        ["run", "--vm", path] if path.ends_with(".mkc") => run_bytecode(path),
+        ["run", "--vm", path] => run(path, true, optimize),
+        ["compile", path, "-o", output] => compile(path, output, optimize),
+        ["disasm", path] => print!("{}", mkc::disassemble(&load(path))),
+        ["--dump-tokens", path] => dump_tokens(path, dump::Format::Table),
+        ["--dump-tokens", path, "--format", format] => dump_tokens(path, parse_format(format)),
+        ["--dump-ast", path] => dump_ast(path, "source"),
+        ["--dump-ast", path, "--format", format] => dump_ast(path, format),
+        ["fmt", "--check", paths @ ..] if !paths.is_empty() => fmt(paths, true),
+        ["fmt", paths @ ..] if !paths.is_empty() => fmt(paths, false),
+        ["lint", paths @ ..] if !paths.is_empty() => lint(paths),
+        ["lsp"] => {
+            if let Err(e) = lsp::run(io::stdin().lock(), io::stdout().lock()) {
+                eprintln!("lsp: {}", e);
+                process::exit(1);
+            }
+        }
+        _ => usage(),
+    }
+}
+
+/// プログラムを実行する。`puts` は標準出力に書く。`use_vm` のときはコンパイルして仮想機械で実行する。
+fn run(path: &str, use_vm: bool, optimize: bool) {
+    let input = read_source(path);
+    let program = parse(&input, path, optimize);
+    let result = if use_vm {
+        compiler::new()
+            .compile(&program)
+            .and_then(|bytecode| vm::new(io::stdout().lock()).run(&bytecode))
+    } else {
+        evaluator::new(io::stdout().lock()).eval_program(&program)
+    };
+    if let Err(error) = result {
+        report(&[error], &input, path);
+    }
+}
+
+/// 構文エラーがあれば表示して終える。`optimize` のときは最適化してから返す
+fn parse(input: &str, path: &str, optimize: bool) -> ast::Program {
+    let (program, errors) = parser::parse(input);
+    report(&errors, input, path);
+    if optimize {
+        optimizer::optimize(program)
+    } else {
+        program
+    }
+}
+
+/// `.mkc` を読み込んで仮想機械で実行する。
+/// エラーはコンパイルしたソースが読めて、コンパイルしたときと同じならそれを使って表示する。
+/// 読めないか書き換わっていれば、埋め込んだ行の表で位置だけ示す。
+fn run_bytecode(path: &str) {
+    let file = load(path);
+    let result = vm::new(io::stdout().lock()).run(&file.bytecode);
+    let Err(error) = result else {
+        return;
+    };
+    match fs::read_to_string(&file.source) {
+        Ok(input) if file.matches(&input) => report(&[error], &input, &file.source),
+        _ => {
+            let position = file.lines.position(error.span.start);
+            eprintln!(
+                "{}[{}]: {}\n --> {}:{}:{}",
+                error.severity,
+                error.code,
+                error.message,
+                file.source,
+                position.line,
+                position.column
+            );
+            process::exit(1);
+        }
+    }
+}
+
+/// コンパイルして `.mkc` に書き出す
+fn compile(path: &str, output: &str, optimize: bool) {
+    let input = read_source(path);
+    let program = parse(&input, path, optimize);
+    let bytecode = match compiler::new().compile(&program) {
+        Ok(bytecode) => bytecode,
+        Err(error) => {
+            report(&[error], &input, path);
+            return;
+        }
+    };
+    let file = mkc::File {
+        source: path.to_string(),
+        source_hash: mkc::hash_source(&input),
+        lines: span::LineIndex::new(&input),
+        bytecode,
+    };
+    if let Err(e) = fs::write(output, mkc::write(&file)) {
+        eprintln!("{}: {}", output, e);
+        process::exit(1);
+    }
+}
+
+/// `.mkc` を読み込む。検証に通らなければ終了コード 1 で終える
+fn load(path: &str) -> mkc::File {
+    let bytes = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        }
+    };
+    match mkc::read(&bytes) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        }
+    }
+}
+
+/// 警告を表示し、ひとつでもあれば終了コード 1 で終える
+fn lint(paths: &[&str]) {
+    let mut warned = false;
+    for path in paths {
+        let input = read_source(path);
+        let dir = Path::new(path).parent().unwrap_or(Path::new("."));
+        let config = match lint::Config::find(dir) {
+            Ok(config) => config,
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(2);
+            }
+        };
+        let (program, errors) = parser::parse(&input);
+        report(&errors, &input, path);
+        let warnings = lint::lint(&program, &input, &config);
+        if !warnings.is_empty() {
+            eprint!("{}", diagnostic::render_all(&warnings, &input, path));
+            warned = true;
+        }
+    }
+    if warned {
+        process::exit(1);
+    }
+}
+
+/// ファイルを整形して書き戻す。`check` のときは書き換えず、整形されていないファイルがあれば終了コード 1 で終える。
+fn fmt(paths: &[&str], check: bool) {
+    let mut unformatted = false;
+    for path in paths {
+        let input = read_source(path);
+        let formatted = match formatter::format(&input) {
+            Ok(formatted) => formatted,
+            Err(errors) => {
+                report(&errors, &input, path);
+                continue;
+            }
+        };
+        if formatted == input {
+            continue;
+        }
+        if check {
+            println!("would reformat {}", path);
+            unformatted = true;
+        } else if let Err(e) = fs::write(path, formatted) {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        }
+    }
+    if unformatted {
+        process::exit(1);
+    }
+}
+
+fn dump_tokens(path: &str, format: dump::Format) {
+    let input = read_source(path);
+    let (tokens, errors) = lexer::tokenize(&input);
+    print!("{}", dump::tokens(&input, &tokens, format));
+    report(&errors, &input, path);
+}
+
+fn dump_ast(path: &str, format: &str) {
+    let mode = match format {
+        "source" => Some(printer::Mode::Normal),
+        "parens" => Some(printer::Mode::Parenthesized),
+        "json" => None,
+        _ => {
+            eprintln!("unknown format: {}", format);
+            usage();
+        }
+    };
+    let input = read_source(path);
+    let (program, errors) = parser::parse(&input);
+    match mode {
+        Some(mode) => {
+            print!("{}", printer::print_program(&program, mode));
+            report(&errors, &input, path);
+        }
+        None => {
+            // JSON には診断も含める
+            print!("{}", dump::ast_json(&input, &program, &errors));
+            if diagnostic::has_errors(&errors) {
+                process::exit(1);
+            }
+        }
+    }
+}
+
+/// 診断を標準エラーに表示し、エラーが含まれていれば終了コード 1 で終える
+fn report(diagnostics: &[diagnostic::Diagnostic], source: &str, name: &str) {
+    if diagnostics.is_empty() {
+        return;
+    }
+    eprint!("{}", diagnostic::render_all(diagnostics, source, name));
+    if diagnostic::has_errors(diagnostics) {
+        process::exit(1);
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(2);
+}
+
+fn parse_format(name: &str) -> dump::Format {
+    match dump::Format::parse(name) {
+        Some(format) => format,
+        None => {
+            eprintln!("unknown format: {}", name);
+            usage();
+        }
+    }
+}
+
+fn read_source(path: &str) -> String {
+    match fs::read_to_string(path) {
+        Ok(input) => input,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            process::exit(1);
+        }
+    }
 }
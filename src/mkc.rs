@@ -0,0 +1,840 @@
+//! コンパイルしたプログラムを保存する `.mkc` 形式。
+//!
+//! 数値はすべてビッグエンディアン。文字列は u32 の長さと UTF-8 のバイト列。
+//!
+//! ```text
+//! magic     "MKC\0"
+//! version   u16
+//! source    文字列。コンパイルしたソースの名前
+//! hash      u64 コンパイルしたソースの FNV-1a ハッシュ
+//! lines     u32 個数, u32 の行頭オフセット
+//! globals   u32 個数, 大域変数の名前
+//! functions u32 個数, 関数。最後がプログラム本体
+//! ```
+//!
+//! 関数は次の順に並ぶ。定数の関数は、関数表でそれより前にある関数の番号で指す。
+//! プログラム本体以外の関数はちょうど一度ずつ指され、入れ子は `limits::DEFAULT_MAX_NESTING` 段まで。
+//!
+//! ```text
+//! parameters u16 仮引数の数。局所変数の先頭から
+//! locals     u32 個数, 名前
+//! cells      u32 個数, u16 スロット
+//! free       u32 個数, 名前と u8 種類 (0 局所変数, 1 閉じ込めた変数) と u16 番号
+//! fallbacks  u32 個数, u8 種類 (0 局所変数, 1 閉じ込めた変数) と u16 番号,
+//!            代わりに読む変数の u8 種類 (0 閉じ込めた変数, 1 大域変数) と u16 番号
+//! constants  u32 個数, u8 種類 (0 整数 i64, 1 関数 u32 番号)
+//! code       u32 長さ, 命令列
+//! debug      u32 個数, u32 命令の位置と u32 範囲の始まり・終わり
+//! ```
+//!
+//! 読み込むときに、命令とオペランドが正しく、スタックが足りなくならないことまで確かめる。
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::rc::Rc;
+
+use crate::code::{self, Capture, CompiledFunction, Constant, Fallback, Opcode};
+use crate::compiler::Bytecode;
+use crate::limits::DEFAULT_MAX_NESTING;
+use crate::span::{LineIndex, Span};
+use crate::symbol::Interner;
+
+pub const MAGIC: &[u8; 4] = b"MKC\0";
+pub const VERSION: u16 = 4;
+
+/// `.mkc` ファイルの中身
+pub struct File {
+    /// ソースの名前。診断や逆アセンブルの表示に使う
+    pub source: String,
+    /// コンパイルしたソースの `hash_source`。今そこにあるファイルが同じソースか確かめる
+    pub source_hash: u64,
+    /// ソースがなくても範囲を行・列にできるように持っておく
+    pub lines: LineIndex,
+    pub bytecode: Bytecode,
+}
+
+/// 読み込めなかった理由
+#[derive(Debug, PartialEq, Clone)]
+pub enum LoadError {
+    BadMagic,
+    UnsupportedVersion(u16),
+    /// ファイルが途中で終わっている
+    Truncated,
+    /// 形式は読めたが中身が正しくない
+    Invalid(String),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::BadMagic => write!(f, "not a bytecode file"),
+            LoadError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported bytecode version {} (expected {})",
+                version, VERSION
+            ),
+            LoadError::Truncated => write!(f, "bytecode file is truncated"),
+            LoadError::Invalid(message) => write!(f, "invalid bytecode file: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+type Result<T> = std::result::Result<T, LoadError>;
+
+fn invalid<T>(message: String) -> Result<T> {
+    Err(LoadError::Invalid(message))
+}
+
+/// ソースの FNV-1a ハッシュ。実行する環境によらず同じ値になる
+pub fn hash_source(input: &str) -> u64 {
+    input.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+impl File {
+    /// `input` がコンパイルしたソースと同じか
+    pub fn matches(&self, input: &str) -> bool {
+        hash_source(input) == self.source_hash
+    }
+}
+
+/// 関数表。内側の関数が先に来るように並べる。
+/// 読み込んだ関数の入れ子は `read` で制限しているが、ここでは再帰せず、同じ関数は一度だけ載せる。
+fn functions(main: &Rc<CompiledFunction>) -> Vec<Rc<CompiledFunction>> {
+    let mut table = vec![];
+    let mut seen = HashSet::from([Rc::as_ptr(main)]);
+    // 関数と、次に見る定数の番号
+    let mut stack = vec![(main.clone(), 0)];
+    while let Some((function, next)) = stack.last_mut() {
+        let Some(constant) = function.constants.get(*next) else {
+            let (function, _) = stack.pop().unwrap();
+            table.push(function);
+            continue;
+        };
+        *next += 1;
+        if let Constant::Function(inner) = constant {
+            if seen.insert(Rc::as_ptr(inner)) {
+                let inner = inner.clone();
+                stack.push((inner, 0));
+            }
+        }
+    }
+    table
+}
+
+pub fn write(file: &File) -> Vec<u8> {
+    let mut w = Writer(MAGIC.to_vec());
+    w.u16(VERSION as usize);
+    w.string(&file.source);
+    w.0.extend_from_slice(&file.source_hash.to_be_bytes());
+    w.u32(file.lines.line_starts().len());
+    for &start in file.lines.line_starts() {
+        w.u32(start);
+    }
+    w.u32(file.bytecode.globals.len());
+    for name in &file.bytecode.globals {
+        w.string(name.as_str());
+    }
+    let table = functions(&file.bytecode.main);
+    let index: HashMap<*const CompiledFunction, usize> = table
+        .iter()
+        .enumerate()
+        .map(|(i, f)| (Rc::as_ptr(f), i))
+        .collect();
+    w.u32(table.len());
+    for function in &table {
+        w.u16(function.parameters.len());
+        w.u32(function.locals.len());
+        for name in &function.locals {
+            w.string(name.as_str());
+        }
+        w.u32(function.cells.len());
+        for &slot in &function.cells {
+            w.u16(slot);
+        }
+        w.u32(function.free.len());
+        for (name, capture) in &function.free {
+            w.string(name.as_str());
+            match capture {
+                Capture::Local(slot) => {
+                    w.0.push(0);
+                    w.u16(*slot);
+                }
+                Capture::Free(i) => {
+                    w.0.push(1);
+                    w.u16(*i);
+                }
+            }
+        }
+        w.u32(function.local_fallbacks.len() + function.free_fallbacks.len());
+        for (kind, fallbacks) in [
+            (0, &function.local_fallbacks),
+            (1, &function.free_fallbacks),
+        ] {
+            for (from, fallback) in fallbacks {
+                w.0.push(kind);
+                w.u16(*from);
+                match fallback {
+                    Fallback::Free(i) => {
+                        w.0.push(0);
+                        w.u16(*i);
+                    }
+                    Fallback::Global(slot) => {
+                        w.0.push(1);
+                        w.u16(*slot);
+                    }
+                }
+            }
+        }
+        w.u32(function.constants.len());
+        for constant in &function.constants {
+            match constant {
+                Constant::Integer(value) => {
+                    w.0.push(0);
+                    w.0.extend_from_slice(&value.to_be_bytes());
+                }
+                Constant::Function(inner) => {
+                    w.0.push(1);
+                    w.u32(index[&Rc::as_ptr(inner)]);
+                }
+            }
+        }
+        w.u32(function.instructions.len());
+        w.0.extend_from_slice(&function.instructions);
+        w.u32(function.spans.len());
+        for (offset, span) in &function.spans {
+            w.u32(*offset);
+            w.u32(span.start);
+            w.u32(span.end);
+        }
+    }
+    w.0
+}
+
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u16(&mut self, value: usize) {
+        self.0.extend_from_slice(&(value as u16).to_be_bytes());
+    }
+
+    fn u32(&mut self, value: usize) {
+        self.0.extend_from_slice(&(value as u32).to_be_bytes());
+    }
+
+    fn string(&mut self, value: &str) {
+        self.u32(value.len());
+        self.0.extend_from_slice(value.as_bytes());
+    }
+}
+
+/// 読み込んで検証する。検証を通ったものは仮想機械で実行してよい。
+pub fn read(bytes: &[u8]) -> Result<File> {
+    let mut r = Reader {
+        bytes,
+        at: 0,
+        interner: Interner::default(),
+        referenced: vec![],
+        depths: vec![],
+    };
+    if r.take(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+        return Err(LoadError::BadMagic);
+    }
+    let version = r.u16()? as u16;
+    if version != VERSION {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+    let source = r.string()?.to_string();
+    let source_hash = u64::from_be_bytes(r.take(8)?.try_into().unwrap());
+    let mut line_starts = vec![];
+    for _ in 0..r.count()? {
+        line_starts.push(r.u32()?);
+    }
+    let Some(lines) = LineIndex::from_line_starts(line_starts) else {
+        return invalid("line table is not increasing".to_string());
+    };
+    let mut globals = vec![];
+    for _ in 0..r.count()? {
+        let name = r.string()?;
+        globals.push(r.interner.intern(name));
+    }
+    let mut table: Vec<Rc<CompiledFunction>> = vec![];
+    for _ in 0..r.count()? {
+        let function = r.function(&table)?;
+        verify(&function, table.len(), globals.len())?;
+        table.push(Rc::new(function));
+    }
+    if r.at != bytes.len() {
+        return invalid("trailing bytes after the last function".to_string());
+    }
+    let Some(main) = table.pop() else {
+        return invalid("no functions".to_string());
+    };
+    if let Some(i) = r.referenced[..table.len()].iter().position(|used| !used) {
+        return invalid(format!("function {} is never used", i));
+    }
+    if !main.parameters.is_empty() || !main.free.is_empty() {
+        return invalid("the main function cannot take parameters or captures".to_string());
+    }
+    Ok(File {
+        source,
+        source_hash,
+        lines,
+        bytecode: Bytecode { main, globals },
+    })
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    at: usize,
+    /// 読んだ名前は読み込んだプログラムと一緒に解放する
+    interner: Interner,
+    /// 読んだ関数ごとに、定数から参照されたか。コンパイラの出力では関数はどれも一度だけ参照される
+    referenced: Vec<bool>,
+    /// 読んだ関数ごとに、その中で入れ子になっている関数の深さ
+    depths: Vec<usize>,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self.at.checked_add(len).ok_or(LoadError::Truncated)?;
+        let bytes = self.bytes.get(self.at..end).ok_or(LoadError::Truncated)?;
+        self.at = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<usize> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]) as usize)
+    }
+
+    fn u32(&mut self) -> Result<usize> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes(bytes.try_into().unwrap()) as usize)
+    }
+
+    /// 要素の数。残りのバイト数より多ければ途中で終わっている
+    fn count(&mut self) -> Result<usize> {
+        let count = self.u32()?;
+        if count > self.bytes.len() - self.at {
+            return Err(LoadError::Truncated);
+        }
+        Ok(count)
+    }
+
+    fn string(&mut self) -> Result<&'a str> {
+        let len = self.u32()?;
+        match std::str::from_utf8(self.take(len)?) {
+            Ok(s) => Ok(s),
+            Err(_) => invalid("name is not UTF-8".to_string()),
+        }
+    }
+
+    /// `table` はそれまでに読んだ関数
+    fn function(&mut self, table: &[Rc<CompiledFunction>]) -> Result<CompiledFunction> {
+        let parameters = self.u16()?;
+        let mut locals = vec![];
+        for _ in 0..self.count()? {
+            let name = self.string()?;
+            locals.push(self.interner.intern(name));
+        }
+        if parameters > locals.len() {
+            return invalid("more parameters than locals".to_string());
+        }
+        let mut cells = vec![];
+        for _ in 0..self.count()? {
+            let slot = self.u16()?;
+            if slot >= locals.len() {
+                return invalid(format!("cell slot {} out of range", slot));
+            }
+            cells.push(slot);
+        }
+        let mut free = vec![];
+        for _ in 0..self.count()? {
+            let name = self.string()?;
+            let name = self.interner.intern(name);
+            let capture = match (self.u8()?, self.u16()?) {
+                (0, slot) => Capture::Local(slot),
+                (1, i) => Capture::Free(i),
+                (kind, _) => return invalid(format!("unknown capture kind {}", kind)),
+            };
+            free.push((name, capture));
+        }
+        let (mut local_fallbacks, mut free_fallbacks) = (vec![], vec![]);
+        for _ in 0..self.count()? {
+            let (kind, from) = (self.u8()?, self.u16()?);
+            let fallback = match (self.u8()?, self.u16()?) {
+                (0, i) => Fallback::Free(i),
+                (1, slot) => Fallback::Global(slot),
+                (kind, _) => return invalid(format!("unknown fallback kind {}", kind)),
+            };
+            match kind {
+                0 => local_fallbacks.push((from, fallback)),
+                1 => free_fallbacks.push((from, fallback)),
+                kind => return invalid(format!("unknown fallback source kind {}", kind)),
+            }
+        }
+        // 同じ関数を何度も参照させたり深く入れ子にさせたりして、
+        // 関数表をたどる処理や解放を指数時間やスタックあふれにさせない
+        let mut constants = vec![];
+        let mut depth = 0;
+        for _ in 0..self.count()? {
+            let constant = match self.u8()? {
+                0 => Constant::Integer(i64::from_be_bytes(self.take(8)?.try_into().unwrap())),
+                1 => {
+                    let i = self.u32()?;
+                    let Some(function) = table.get(i) else {
+                        return invalid("function constant out of range".to_string());
+                    };
+                    if std::mem::replace(&mut self.referenced[i], true) {
+                        return invalid(format!("function {} is referenced more than once", i));
+                    }
+                    depth = depth.max(self.depths[i] + 1);
+                    Constant::Function(function.clone())
+                }
+                kind => return invalid(format!("unknown constant kind {}", kind)),
+            };
+            constants.push(constant);
+        }
+        if depth > DEFAULT_MAX_NESTING {
+            return invalid(format!(
+                "functions nested more than {} deep",
+                DEFAULT_MAX_NESTING
+            ));
+        }
+        self.referenced.push(false);
+        self.depths.push(depth);
+        let len = self.u32()?;
+        let instructions = self.take(len)?.to_vec();
+        let mut spans = vec![];
+        for _ in 0..self.count()? {
+            let offset = self.u32()?;
+            let span = Span {
+                start: self.u32()?,
+                end: self.u32()?,
+            };
+            spans.push((offset, span));
+        }
+        Ok(CompiledFunction {
+            parameters: locals[..parameters].to_vec(),
+            instructions,
+            constants,
+            locals,
+            cells,
+            free,
+            local_fallbacks,
+            free_fallbacks,
+            spans,
+        })
+    }
+}
+
+/// 命令列を確かめる。`index` は関数表での番号で、エラーメッセージに使う
+fn verify(function: &CompiledFunction, index: usize, globals: usize) -> Result<()> {
+    let fail = |offset: usize, message: String| -> Result<()> {
+        invalid(format!("function {} at {:04}: {}", index, offset, message))
+    };
+    let code = &function.instructions;
+
+    // 命令の境目と、各命令のスタックの増減
+    let mut starts = vec![false; code.len()];
+    let mut decoded = vec![];
+    let mut at = 0;
+    while at < code.len() {
+        let Some(op) = Opcode::from_byte(code[at]) else {
+            return fail(at, format!("unknown opcode {}", code[at]));
+        };
+        let width: usize = op.operand_widths().iter().sum();
+        if at + 1 + width > code.len() {
+            return fail(at, format!("{} is cut off", op.name()));
+        }
+        let operand = match op.operand_widths().first() {
+            Some(&width) => code::read_operand(code, at + 1, width),
+            None => 0,
+        };
+        starts[at] = true;
+        decoded.push((at, op, operand));
+        at += 1 + width;
+    }
+    match decoded.last() {
+        Some((_, Opcode::ReturnValue, _)) => {}
+        _ => return fail(code.len(), "must end with ReturnValue".to_string()),
+    }
+
+    let out_of_range =
+        |op: Opcode, operand: usize| format!("{} {} out of range", op.name(), operand);
+    for &(at, op, operand) in &decoded {
+        let ok = match op {
+            Opcode::Constant => {
+                matches!(function.constants.get(operand), Some(Constant::Integer(_)))
+            }
+            Opcode::Closure => match function.constants.get(operand) {
+                Some(Constant::Function(inner)) => {
+                    inner.free.iter().all(|(_, capture)| match capture {
+                        Capture::Local(slot) => function.cells.contains(slot),
+                        Capture::Free(i) => *i < function.free.len(),
+                    })
+                }
+                _ => false,
+            },
+            // コンパイラは前にしか飛ばないので、ループは作らせない
+            Opcode::Jump | Opcode::JumpNotTruthy => {
+                operand > at && operand < code.len() && starts[operand]
+            }
+            Opcode::GetGlobal | Opcode::SetGlobal => operand < globals,
+            Opcode::GetLocal | Opcode::SetLocal => operand < function.locals.len(),
+            Opcode::GetFree => operand < function.free.len(),
+            _ => true,
+        };
+        if !ok {
+            return fail(at, out_of_range(op, operand));
+        }
+    }
+    // 閉じ込めた変数の代わりはそれより後ろの変数にして、代わりをたどると必ず終わるようにする
+    let fallback_ok = |fallback: &Fallback, after: Option<usize>| match *fallback {
+        Fallback::Free(i) => i < function.free.len() && after.is_none_or(|after| i > after),
+        Fallback::Global(slot) => slot < globals,
+    };
+    for (slot, fallback) in &function.local_fallbacks {
+        if *slot >= function.locals.len() || !fallback_ok(fallback, None) {
+            return fail(0, format!("bad fallback for local {}", slot));
+        }
+    }
+    for (i, fallback) in &function.free_fallbacks {
+        if *i >= function.free.len() || !fallback_ok(fallback, Some(*i)) {
+            return fail(0, format!("bad fallback for free {}", i));
+        }
+    }
+    for (offset, span) in &function.spans {
+        if *offset >= code.len() || span.start > span.end {
+            return fail(*offset, "bad debug entry".to_string());
+        }
+    }
+    if !function.spans.windows(2).all(|w| w[0].0 < w[1].0) {
+        return fail(0, "debug entries are not in order".to_string());
+    }
+
+    // どの経路でもスタックが足りることを、各命令に来るときの最小の高さで確かめる
+    let position: HashMap<usize, usize> = decoded
+        .iter()
+        .enumerate()
+        .map(|(i, (at, ..))| (*at, i))
+        .collect();
+    let mut heights: Vec<Option<usize>> = vec![None; decoded.len()];
+    let mut work = vec![(0, 0)];
+    while let Some((i, height)) = work.pop() {
+        if heights[i].is_some_and(|known| known <= height) {
+            continue;
+        }
+        heights[i] = Some(height);
+        let (at, op, operand) = decoded[i];
+        let (needs, pushes) = match op {
+            Opcode::Constant
+            | Opcode::Null
+            | Opcode::True
+            | Opcode::False
+            | Opcode::GetGlobal
+            | Opcode::GetLocal
+            | Opcode::GetFree
+            | Opcode::Closure => (0, 1),
+            Opcode::Pop | Opcode::SetGlobal | Opcode::SetLocal | Opcode::JumpNotTruthy => (1, 0),
+            Opcode::Add
+            | Opcode::Sub
+            | Opcode::Mul
+            | Opcode::Div
+            | Opcode::Equal
+            | Opcode::NotEqual
+            | Opcode::LessThan
+            | Opcode::GreaterThan => (2, 1),
+            Opcode::Minus | Opcode::Bang => (1, 1),
+            Opcode::Jump => (0, 0),
+            Opcode::Call | Opcode::TailCall => (operand + 1, 1),
+            Opcode::ReturnValue => (1, 0),
+        };
+        if height < needs {
+            return fail(
+                at,
+                format!("{} needs {} values on the stack", op.name(), needs),
+            );
+        }
+        let height = height - needs + pushes;
+        match op {
+            Opcode::ReturnValue => {}
+            Opcode::Jump => work.push((position[&operand], height)),
+            Opcode::JumpNotTruthy => {
+                work.push((position[&operand], height));
+                work.push((i + 1, height));
+            }
+            _ => work.push((i + 1, height)),
+        }
+    }
+    Ok(())
+}
+
+/// 関数ごとに、命令と行・列、オペランドが指すものを表示する
+pub fn disassemble(file: &File) -> String {
+    let table = functions(&file.bytecode.main);
+    let index: HashMap<*const CompiledFunction, usize> = table
+        .iter()
+        .enumerate()
+        .map(|(i, f)| (Rc::as_ptr(f), i))
+        .collect();
+    let mut out = String::new();
+    for (i, function) in table.iter().enumerate().rev() {
+        if !out.is_empty() {
+            out.push('\n');
+        }
+        if i + 1 == table.len() {
+            out += &format!("main ({})\n", file.source);
+        } else {
+            let parameters: Vec<&str> = function.parameters.iter().map(|p| p.as_str()).collect();
+            out += &format!("function {} fn({})\n", i, parameters.join(", "));
+        }
+        if !function.free.is_empty() {
+            let free: Vec<String> = function
+                .free
+                .iter()
+                .map(|(name, capture)| match capture {
+                    Capture::Local(slot) => format!("{} (local {})", name, slot),
+                    Capture::Free(i) => format!("{} (free {})", name, i),
+                })
+                .collect();
+            out += &format!("  free: {}\n", free.join(", "));
+        }
+        let fallbacks: Vec<String> = function
+            .local_fallbacks
+            .iter()
+            .map(|(slot, fallback)| (format!("local {}", slot), fallback))
+            .chain(
+                function
+                    .free_fallbacks
+                    .iter()
+                    .map(|(i, fallback)| (format!("free {}", i), fallback)),
+            )
+            .map(|(from, fallback)| match fallback {
+                Fallback::Free(i) => format!("{} -> free {}", from, i),
+                Fallback::Global(slot) => format!("{} -> global {}", from, slot),
+            })
+            .collect();
+        if !fallbacks.is_empty() {
+            out += &format!("  fallbacks: {}\n", fallbacks.join(", "));
+        }
+        let mut at = 0;
+        let mut last = None;
+        while at < function.instructions.len() {
+            let op = Opcode::from_byte(function.instructions[at]).expect("verified opcode");
+            let mut operands = vec![];
+            let mut next = at + 1;
+            for &width in op.operand_widths() {
+                operands.push(code::read_operand(&function.instructions, next, width));
+                next += width;
+            }
+            let position = file.lines.position(function.span_at(at).start);
+            let line = if last == Some(position) {
+                String::new()
+            } else {
+                format!("{}:{}", position.line, position.column)
+            };
+            last = Some(position);
+            let operand = operands.first().copied().unwrap_or(0);
+            let comment = match op {
+                Opcode::Constant => function.constants[operand].to_string(),
+                Opcode::Closure => match &function.constants[operand] {
+                    Constant::Function(inner) => format!("function {}", index[&Rc::as_ptr(inner)]),
+                    constant => constant.to_string(),
+                },
+                Opcode::GetGlobal | Opcode::SetGlobal => file.bytecode.globals[operand].to_string(),
+                Opcode::GetLocal | Opcode::SetLocal => function.locals[operand].to_string(),
+                Opcode::GetFree => function.free[operand].0.to_string(),
+                _ => String::new(),
+            };
+            let operands: Vec<String> = operands.iter().map(|o| o.to_string()).collect();
+            let instruction = format!("{} {}", op.name(), operands.join(" "));
+            let instruction = instruction.trim_end();
+            if comment.is_empty() {
+                out += &format!("  {:04} {:>7}  {}\n", at, line, instruction);
+            } else {
+                out += &format!(
+                    "  {:04} {:>7}  {:<20} ; {}\n",
+                    at, line, instruction, comment
+                );
+            }
+            at = next;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler, parser, vm};
+
+    fn compile(input: &str) -> File {
+        let (program, errors) = parser::parse(input);
+        assert_eq!(errors, vec![]);
+        File {
+            source: "test.mk".to_string(),
+            source_hash: hash_source(input),
+            lines: LineIndex::new(input),
+            bytecode: compiler::new().compile(&program).unwrap(),
+        }
+    }
+
+    const PROGRAM: &str = "let add = fn(a) { fn(b) { a + b } };\nputs(add(1)(2));\n";
+
+    #[test]
+    fn round_trip() {
+        let file = compile(PROGRAM);
+        let bytes = write(&file);
+        let loaded = read(&bytes).unwrap();
+        assert_eq!(loaded.source, file.source);
+        assert!(loaded.matches(PROGRAM));
+        assert!(!loaded.matches("puts(1);\n"));
+        assert_eq!(loaded.bytecode, file.bytecode);
+        assert_eq!(write(&loaded), bytes);
+        let mut machine = vm::new(vec![]);
+        machine.run(&loaded.bytecode).unwrap();
+        assert_eq!(machine.into_output(), b"3\n");
+    }
+
+    /// 外側の束縛へのたどり先も書き出して読み戻す
+    #[test]
+    fn fallbacks() {
+        let file = compile("let x = 1;\nlet f = fn() { let g = fn() { x }; let y = g(); let x = 2; y + g() };\nf()");
+        let bytes = write(&file);
+        let loaded = read(&bytes).unwrap();
+        assert_eq!(loaded.bytecode, file.bytecode);
+        assert!(disassemble(&loaded).contains("  fallbacks: local 2 -> global 0\n"));
+        assert!(disassemble(&loaded).contains("  fallbacks: free 0 -> global 0\n"));
+        let mut machine = vm::new(vec![]);
+        let value = machine.run(&loaded.bytecode).unwrap();
+        assert_eq!(value.to_string(), "3");
+    }
+
+    #[test]
+    fn rejects_bad_files() {
+        let bytes = write(&compile(PROGRAM));
+        assert_eq!(read(b"MK").err(), Some(LoadError::BadMagic));
+        let mut version = bytes.clone();
+        version[5] = 9;
+        assert_eq!(read(&version).err(), Some(LoadError::UnsupportedVersion(9)));
+        for len in 4..bytes.len() {
+            assert!(read(&bytes[..len]).is_err(), "{}", len);
+        }
+        // どこを書き換えても、読めるか検証で弾かれるかのどちらかで、実行してもパニックしない
+        for at in 6..bytes.len() {
+            for value in [0, 1, 0x17, 0xff] {
+                let mut bytes = bytes.clone();
+                bytes[at] = value;
+                if let Ok(file) = read(&bytes) {
+                    let _ = vm::new(vec![]).run(&file.bytecode);
+                }
+            }
+        }
+    }
+
+    /// `Null` を返し、`constants` を持つだけの関数
+    fn function(constants: Vec<Constant>) -> Rc<CompiledFunction> {
+        Rc::new(CompiledFunction {
+            parameters: vec![],
+            instructions: [
+                code::make(Opcode::Null, &[]),
+                code::make(Opcode::ReturnValue, &[]),
+            ]
+            .concat(),
+            constants,
+            locals: vec![],
+            cells: vec![],
+            free: vec![],
+            local_fallbacks: vec![],
+            free_fallbacks: vec![],
+            spans: vec![],
+        })
+    }
+
+    fn with_main(main: Rc<CompiledFunction>) -> File {
+        File {
+            source: "test.mk".to_string(),
+            source_hash: hash_source(""),
+            lines: LineIndex::new(""),
+            bytecode: Bytecode {
+                main,
+                globals: vec![],
+            },
+        }
+    }
+
+    /// 同じ関数を何度も指すファイルや深すぎる入れ子は、たどる前に弾く
+    #[test]
+    fn rejects_shared_and_deep_functions() {
+        let mut shared = function(vec![]);
+        for _ in 0..40 {
+            shared = function(vec![
+                Constant::Function(shared.clone()),
+                Constant::Function(shared),
+            ]);
+        }
+        let error = read(&write(&with_main(shared))).err().unwrap();
+        assert_eq!(
+            error,
+            LoadError::Invalid("function 0 is referenced more than once".to_string())
+        );
+
+        let chain = |depth: usize| {
+            let mut main = function(vec![]);
+            for _ in 0..depth {
+                main = function(vec![Constant::Function(main)]);
+            }
+            write(&with_main(main))
+        };
+        assert!(read(&chain(DEFAULT_MAX_NESTING)).is_ok());
+        let error = read(&chain(DEFAULT_MAX_NESTING + 1)).err().unwrap();
+        assert_eq!(
+            error,
+            LoadError::Invalid(format!(
+                "functions nested more than {} deep",
+                DEFAULT_MAX_NESTING
+            ))
+        );
+    }
+
+    #[test]
+    fn disassembly() {
+        let text = disassemble(&compile(PROGRAM));
+        assert_eq!(
+            text,
+            "\
+main (test.mk)
+  0000    1:11  Closure 0            ; function 1
+  0003     1:1  SetGlobal 0          ; add
+  0006     2:1  GetGlobal 1          ; puts
+  0009     2:6  GetGlobal 0          ; add
+  0012    2:10  Constant 1           ; 1
+  0015     2:6  Call 1
+  0018    2:13  Constant 2           ; 2
+  0021     2:6  Call 1
+  0024     2:1  Call 1
+  0027     1:1  ReturnValue
+
+function 1 fn(a)
+  0000    1:19  Closure 0            ; function 0
+  0003    1:17  ReturnValue
+
+function 0 fn(b)
+  free: a (local 0)
+  0000    1:27  GetFree 0            ; a
+  0003    1:31  GetLocal 0           ; b
+  0006    1:27  Add
+  0007    1:25  ReturnValue
+"
+        );
+    }
+}
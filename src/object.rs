@@ -0,0 +1,296 @@
+//! 評価した値と、名前から値を引く環境。
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use crate::ast::Block;
+use crate::code::CompiledFunction;
+use crate::symbol::Symbol;
+
+#[derive(Debug, Clone)]
+pub enum Value {
+    Integer(i64),
+    Boolean(bool),
+    Null,
+    Function(Rc<Function>),
+    /// 仮想機械で関数リテラルを評価したもの
+    Closure(Rc<Closure>),
+    Builtin(Builtin),
+    /// 埋め込み側が `Interpreter::register_fn` で登録した関数
+    Host(Rc<HostFunction>),
+}
+
+/// 関数リテラルを評価したもの。定義したときの環境を閉じ込める。
+pub struct Function {
+    pub parameters: Vec<Symbol>,
+    /// 関数リテラルの本体と共有する
+    pub body: Rc<Block>,
+    pub env: Env,
+}
+
+/// 閉じ込めた変数は、外側の関数と同じセルを共有する
+pub struct Closure {
+    pub function: Rc<CompiledFunction>,
+    pub free: Vec<Cell>,
+}
+
+/// クロージャが閉じ込める変数。値を代入する前は `None`。
+pub type Cell = Rc<RefCell<Option<Value>>>;
+
+/// 組み込み関数
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Builtin {
+    /// 引数を一行にひとつずつ出力して null を返す
+    Puts,
+}
+
+/// Rust の関数。引数の数や型の確認は `function` の中で済ませる。
+pub struct HostFunction {
+    pub name: String,
+    pub function: Box<dyn Fn(Vec<Value>) -> Result<Value, HostError>>,
+}
+
+/// 埋め込み側の関数の呼び出しに失敗した理由
+#[derive(Debug, PartialEq, Clone)]
+pub enum HostError {
+    Arity {
+        expected: usize,
+        found: usize,
+    },
+    /// `index` は 0 始まり
+    Argument {
+        index: usize,
+        expected: &'static str,
+        found: &'static str,
+    },
+    /// 関数自身が返したエラー
+    Failed(String),
+}
+
+impl Builtin {
+    pub const ALL: [Builtin; 1] = [Builtin::Puts];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Builtin::Puts => "puts",
+        }
+    }
+
+    pub fn lookup(name: &str) -> Option<Builtin> {
+        Builtin::ALL.into_iter().find(|b| b.name() == name)
+    }
+}
+
+impl Value {
+    /// エラーメッセージに使う型の名前
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Integer(_) => "int",
+            Value::Boolean(_) => "bool",
+            Value::Null => "null",
+            Value::Function(_) | Value::Closure(_) => "fn",
+            Value::Builtin(_) | Value::Host(_) => "builtin",
+        }
+    }
+
+    /// `false` と `null` だけが偽
+    pub fn is_truthy(&self) -> bool {
+        !matches!(self, Value::Boolean(false) | Value::Null)
+    }
+}
+
+/// 関数は同じ関数値のときだけ等しい
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self, other) {
+            (Value::Integer(a), Value::Integer(b)) => a == b,
+            (Value::Boolean(a), Value::Boolean(b)) => a == b,
+            (Value::Null, Value::Null) => true,
+            (Value::Function(a), Value::Function(b)) => Rc::ptr_eq(a, b),
+            (Value::Closure(a), Value::Closure(b)) => Rc::ptr_eq(a, b),
+            (Value::Builtin(a), Value::Builtin(b)) => a == b,
+            (Value::Host(a), Value::Host(b)) => Rc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Integer(value) => write!(f, "{}", value),
+            Value::Boolean(value) => write!(f, "{}", value),
+            Value::Null => write!(f, "null"),
+            Value::Function(function) => write!(f, "{:?}", function),
+            Value::Closure(closure) => write!(f, "{:?}", closure),
+            Value::Builtin(builtin) => write!(f, "builtin {}", builtin.name()),
+            Value::Host(host) => write!(f, "builtin {}", host.name),
+        }
+    }
+}
+
+/// 環境は関数自身を含むことがあるので、中身は表示しない
+impl fmt::Debug for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let parameters: Vec<&str> = self.parameters.iter().map(|p| p.as_str()).collect();
+        write!(f, "fn({}) {{ ... }}", parameters.join(", "))
+    }
+}
+
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let parameters: Vec<&str> = self
+            .function
+            .parameters
+            .iter()
+            .map(|p| p.as_str())
+            .collect();
+        write!(f, "fn({}) {{ ... }}", parameters.join(", "))
+    }
+}
+
+impl fmt::Debug for HostFunction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HostFunction({:?})", self.name)
+    }
+}
+
+/// Monkey の値から Rust の値への変換。型が合わなければ `None`。
+pub trait FromValue: Sized {
+    /// 型が合わないときのメッセージに使う名前
+    const TYPE_NAME: &'static str;
+
+    fn from_value(value: Value) -> Option<Self>;
+}
+
+/// Rust の値から Monkey の値への変換
+pub trait IntoValue {
+    fn into_value(self) -> Value;
+}
+
+impl FromValue for Value {
+    const TYPE_NAME: &'static str = "any";
+
+    fn from_value(value: Value) -> Option<Value> {
+        Some(value)
+    }
+}
+
+impl FromValue for i64 {
+    const TYPE_NAME: &'static str = "int";
+
+    fn from_value(value: Value) -> Option<i64> {
+        match value {
+            Value::Integer(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for bool {
+    const TYPE_NAME: &'static str = "bool";
+
+    fn from_value(value: Value) -> Option<bool> {
+        match value {
+            Value::Boolean(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+/// null は `None`
+impl<T: FromValue> FromValue for Option<T> {
+    const TYPE_NAME: &'static str = T::TYPE_NAME;
+
+    fn from_value(value: Value) -> Option<Option<T>> {
+        match value {
+            Value::Null => Some(None),
+            value => T::from_value(value).map(Some),
+        }
+    }
+}
+
+impl IntoValue for Value {
+    fn into_value(self) -> Value {
+        self
+    }
+}
+
+impl IntoValue for i64 {
+    fn into_value(self) -> Value {
+        Value::Integer(self)
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self) -> Value {
+        Value::Boolean(self)
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self) -> Value {
+        Value::Null
+    }
+}
+
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self) -> Value {
+        self.map_or(Value::Null, T::into_value)
+    }
+}
+
+pub type Env = Rc<RefCell<Environment>>;
+
+#[derive(Default)]
+pub struct Environment {
+    store: HashMap<Symbol, Value>,
+    outer: Option<Env>,
+}
+
+impl Environment {
+    pub fn new() -> Env {
+        Rc::new(RefCell::new(Environment::default()))
+    }
+
+    /// `outer` の内側に新しいスコープを作る
+    pub fn enclosed(outer: Env) -> Env {
+        Rc::new(RefCell::new(Environment {
+            store: HashMap::new(),
+            outer: Some(outer),
+        }))
+    }
+
+    /// 内側のスコープから順に探す
+    pub fn get(&self, name: &Symbol) -> Option<Value> {
+        match self.store.get(name) {
+            Some(value) => Some(value.clone()),
+            None => self.outer.as_ref()?.borrow().get(name),
+        }
+    }
+
+    pub fn set(&mut self, name: Symbol, value: Value) {
+        self.store.insert(name, value);
+    }
+
+    // ごみ集めが使う
+
+    pub(crate) fn values(&self) -> impl Iterator<Item = &Value> {
+        self.store.values()
+    }
+
+    pub(crate) fn outer(&self) -> Option<&Env> {
+        self.outer.as_ref()
+    }
+
+    pub(crate) fn capacity(&self) -> usize {
+        self.store.capacity()
+    }
+
+    /// 束縛と外側の環境を捨てる
+    pub(crate) fn clear(&mut self) {
+        *self = Environment::default();
+    }
+}
@@ -0,0 +1,290 @@
+//! 実行前に構文木を簡単にする。
+//!
+//! - 整数と真偽値のリテラルだけでできた演算を畳み込む。0 除算や桁あふれのように
+//!   実行時エラーになるものは、同じ位置で同じエラーになるように残す。
+//! - 条件がリテラルの `if` は、選ばれない方の枝を捨てる。`if` はスコープを作らないので、
+//!   文として置かれたものは選ばれた枝の文をそのまま外側に並べる。
+//! - `return` より後の文を捨てる。
+//!
+//! 残した式の範囲は変えないので、診断の位置は最適化しないときと同じになる。
+//! 入れ子が `limits::DEFAULT_MAX_NESTING` より深い式はそのまま残し、コンパイラや評価器にエラーにさせる。
+
+use std::rc::Rc;
+
+use crate::ast::*;
+use crate::evaluator::{infix, prefix};
+use crate::limits::{self, DEFAULT_MAX_NESTING};
+use crate::object::Value;
+use crate::span::Span;
+
+pub fn optimize(program: Program) -> Program {
+    Program {
+        statements: statements(program.statements, 0),
+    }
+}
+
+/// `depth` は文の並びを囲む式の入れ子の深さ
+fn statements(statements: Vec<Statement>, depth: usize) -> Vec<Statement> {
+    let count = statements.len();
+    let mut out = Vec::with_capacity(count);
+    for (i, statement) in statements.into_iter().enumerate() {
+        let last = i + 1 == count;
+        let span = statement.span;
+        match statement.kind {
+            StatementKind::Let { name, value } => out.push(Statement {
+                kind: StatementKind::Let {
+                    name,
+                    value: expression(value, depth),
+                },
+                span,
+            }),
+            StatementKind::Return(value) => {
+                out.push(Statement {
+                    kind: StatementKind::Return(expression(value, depth)),
+                    span,
+                });
+                break;
+            }
+            StatementKind::Expression(value) => match expression(value, depth) {
+                // 選ばれた枝の値がそのまま文の並びの値になる。枝が空で最後の文なら値は null
+                // なので、そのまま残す。
+                Expression {
+                    kind:
+                        ExpressionKind::If {
+                            condition,
+                            consequence,
+                            alternative: None,
+                        },
+                    ..
+                } if matches!(condition.kind, ExpressionKind::Boolean(_))
+                    && !(last && consequence.statements.is_empty()) =>
+                {
+                    if condition.kind == ExpressionKind::Boolean(true) {
+                        out.extend(consequence.statements);
+                    }
+                }
+                value => out.push(Statement {
+                    kind: StatementKind::Expression(value),
+                    span,
+                }),
+            },
+        }
+        if matches!(
+            out.last(),
+            Some(Statement {
+                kind: StatementKind::Return(_),
+                ..
+            })
+        ) {
+            break;
+        }
+    }
+    out
+}
+
+fn block(block: Block, depth: usize) -> Block {
+    Block {
+        statements: statements(block.statements, depth),
+        span: block.span,
+    }
+}
+
+/// リテラルなら値を返す
+fn literal(expression: &Expression) -> Option<Value> {
+    match expression.kind {
+        ExpressionKind::Integer(value) => Some(Value::Integer(value)),
+        ExpressionKind::Boolean(value) => Some(Value::Boolean(value)),
+        _ => None,
+    }
+}
+
+/// `depth` はこの式を囲む入れ子の深さ。左結合の連なりの左側は同じ深さでたどる
+fn expression(expression: Expression, depth: usize) -> Expression {
+    if depth >= DEFAULT_MAX_NESTING {
+        return expression;
+    }
+    limits::grow_stack(|| fold(expression, depth))
+}
+
+fn fold(expression: Expression, outer: usize) -> Expression {
+    let depth = outer + 1;
+    let span = expression.span;
+    let kind = match expression.kind {
+        ExpressionKind::Prefix { operator, right } => {
+            let right = self::expression(*right, depth);
+            match literal(&right).map(|value| prefix(operator, value, span)) {
+                Some(Ok(value)) => return from_value(value, span),
+                _ => ExpressionKind::Prefix {
+                    operator,
+                    right: Box::new(right),
+                },
+            }
+        }
+        ExpressionKind::Infix {
+            operator,
+            left,
+            right,
+        } => {
+            let left = self::expression(*left, outer);
+            let right = self::expression(*right, depth);
+            let folded = match (literal(&left), literal(&right)) {
+                (Some(l), Some(r)) => infix(operator, l, r, span).ok(),
+                _ => None,
+            };
+            match folded {
+                Some(value) => return from_value(value, span),
+                None => ExpressionKind::Infix {
+                    operator,
+                    left: Box::new(left),
+                    right: Box::new(right),
+                },
+            }
+        }
+        ExpressionKind::If {
+            condition,
+            consequence,
+            alternative,
+        } => {
+            let condition = self::expression(*condition, depth);
+            match literal(&condition).map(|value| value.is_truthy()) {
+                // 選ばれる枝を `if (true) { ... }` の形にする
+                Some(truthy) => {
+                    let consequence_span = consequence.span;
+                    let chosen = if truthy {
+                        Some(consequence)
+                    } else {
+                        alternative
+                    };
+                    let condition_span = condition.span;
+                    let (value, consequence) = match chosen {
+                        Some(chosen) => (true, block(chosen, depth)),
+                        None => (
+                            false,
+                            Block {
+                                statements: vec![],
+                                span: consequence_span,
+                            },
+                        ),
+                    };
+                    ExpressionKind::If {
+                        condition: Box::new(Expression {
+                            kind: ExpressionKind::Boolean(value),
+                            span: condition_span,
+                        }),
+                        consequence,
+                        alternative: None,
+                    }
+                }
+                None => ExpressionKind::If {
+                    condition: Box::new(condition),
+                    consequence: block(consequence, depth),
+                    alternative: alternative.map(|alternative| block(alternative, depth)),
+                },
+            }
+        }
+        ExpressionKind::Function { parameters, body } => ExpressionKind::Function {
+            parameters,
+            body: Rc::new(block(Rc::unwrap_or_clone(body), depth)),
+        },
+        ExpressionKind::Call {
+            function,
+            arguments,
+        } => ExpressionKind::Call {
+            function: Box::new(self::expression(*function, outer)),
+            arguments: arguments
+                .into_iter()
+                .map(|argument| self::expression(argument, depth))
+                .collect(),
+        },
+        kind @ (ExpressionKind::Identifier(_)
+        | ExpressionKind::Integer(_)
+        | ExpressionKind::Boolean(_)) => kind,
+    };
+    Expression { kind, span }
+}
+
+fn from_value(value: Value, span: Span) -> Expression {
+    let kind = match value {
+        Value::Integer(value) => ExpressionKind::Integer(value),
+        Value::Boolean(value) => ExpressionKind::Boolean(value),
+        value => unreachable!("folded to a {}", value.type_name()),
+    };
+    Expression { kind, span }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::printer::{self, Mode};
+    use crate::{evaluator, parser};
+
+    fn optimize(input: &str) -> String {
+        let (program, errors) = parser::parse(input);
+        assert_eq!(errors, vec![]);
+        printer::print_program(&super::optimize(program), Mode::Normal)
+    }
+
+    #[test]
+    fn folding() {
+        let cases = [
+            ("let x = 2 * 3 + 4;", "let x = 10;\n"),
+            ("!(1 < 2) == false", "true;\n"),
+            ("-(-5)", "5;\n"),
+            (
+                "let f = fn(a) { a + 1 * 2 };",
+                "let f = fn(a) {\n    a + 2;\n};\n",
+            ),
+            // 実行時エラーになるものは残す
+            ("1 / 0", "1 / 0;\n"),
+            ("9223372036854775807 + 1", "9223372036854775807 + 1;\n"),
+            ("1 + true", "1 + true;\n"),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(optimize(input), expected, "{}", input);
+        }
+    }
+
+    #[test]
+    fn dead_code() {
+        let cases = [
+            ("if (true) { 1 } else { 2 }", "1;\n"),
+            ("if (1 > 2) { 1 } else { let y = 2; }", "let y = 2;\n"),
+            ("if (false) { 1 }; 3", "3;\n"),
+            ("if (false) { 1 }", "if (false) {};\n"),
+            (
+                "let x = if (0) { 1 } else { 2 };",
+                "let x = if (true) {\n    1;\n};\n",
+            ),
+            ("return 1; 2; 3", "return 1;\n"),
+            ("if (true) { return 1; } puts(2)", "return 1;\n"),
+            (
+                "fn() { if (x) { return 1; 2 } 3 }",
+                "fn() {\n    if (x) {\n        return 1;\n    };\n    3;\n};\n",
+            ),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(optimize(input), expected, "{}", input);
+        }
+    }
+
+    /// 最適化しても値と出力とエラーは変わらない
+    #[test]
+    fn same_results() {
+        let inputs = [
+            "let f = fn(n) { if (true) { let m = n * 2; } m + 1 }; f(3)",
+            "if (false) { 1 }",
+            "puts(1); if (true) { puts(2); return 3; puts(4) } puts(5)",
+            "let x = 1 / 0;",
+            "let f = fn() { if (false) { 1 } }; f()",
+            "let g = fn() { return 1 + 2 * 3; 4 }; g() == 7",
+        ];
+        for input in inputs {
+            let (program, _) = parser::parse(input);
+            let mut e = evaluator::new(vec![]);
+            let expected = e.eval_program(&program);
+            let mut optimized = evaluator::new(vec![]);
+            let actual = optimized.eval_program(&super::optimize(program));
+            assert_eq!(actual, expected, "{}", input);
+            assert_eq!(optimized.output(), e.output(), "{}", input);
+        }
+    }
+}
@@ -0,0 +1,721 @@
+use std::rc::Rc;
+
+use crate::ast::*;
+use crate::diagnostic::{self, Diagnostic};
+use crate::lexer::{self, Comment, Lexer};
+use crate::limits::{self, DEFAULT_MAX_NESTING, MAX_EXPRESSION_HEIGHT};
+use crate::span::Span;
+use crate::symbol::{Interner, Symbol};
+use crate::token::*;
+
+/// 期待したトークンではなかった
+pub const E_UNEXPECTED_TOKEN: &str = "E0100";
+/// 式が来るべき位置に式がない
+pub const E_EXPECTED_EXPRESSION: &str = "E0101";
+/// 整数リテラルが i64 に収まらない
+pub const E_INTEGER_OVERFLOW: &str = "E0102";
+/// 式の入れ子が上限より深い。上限は既定で `limits::DEFAULT_MAX_NESTING`
+pub const E_NESTING_TOO_DEEP: &str = "E0103";
+
+#[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+enum Precedence {
+    Lowest,
+    /// ==
+    Equals,
+    /// > or <
+    LessGreater,
+    /// +
+    Sum,
+    /// *
+    Product,
+    /// -X or !X
+    Prefix,
+    /// myFunction(X)
+    Call,
+}
+
+fn precedence(token_type: &TokenType) -> Precedence {
+    match token_type {
+        TokenType::Operator(Operator::Eq | Operator::NotEq) => Precedence::Equals,
+        TokenType::Operator(Operator::Lt | Operator::Gt) => Precedence::LessGreater,
+        TokenType::Operator(Operator::Plus | Operator::Minus) => Precedence::Sum,
+        TokenType::Operator(Operator::Mul | Operator::Div) => Precedence::Product,
+        TokenType::Paren(Paren::LParen) => Precedence::Call,
+        _ => Precedence::Lowest,
+    }
+}
+
+pub struct Parser<'src> {
+    lexer: Lexer<'src>,
+    cur: TokenRef<'src>,
+    cur_span: Span,
+    peek: TokenRef<'src>,
+    peek_span: Span,
+    errors: Vec<Diagnostic>,
+    /// 解析中の式の入れ子の深さ
+    depth: usize,
+    max_nesting: Option<usize>,
+    /// 解析し終えた式の木の高さ。中の式を読むたびに大きい方を残す
+    height: usize,
+}
+
+/// 識別子の名前は `lexer` に渡した intern 表でまとめる。表がなければ出てくるたびに確保する
+pub fn new(mut lexer: Lexer<'_>) -> Parser<'_> {
+    let (cur, cur_span) = lexer.next_token_with_span();
+    let (peek, peek_span) = lexer.next_token_with_span();
+    Parser {
+        lexer,
+        cur,
+        cur_span,
+        peek,
+        peek_span,
+        errors: vec![],
+        depth: 0,
+        max_nesting: Some(DEFAULT_MAX_NESTING),
+        height: 0,
+    }
+}
+
+/// `input` を構文解析し、プログラムと字句・構文のエラーを返す
+pub fn parse(input: &str) -> (Program, Vec<Diagnostic>) {
+    parse_with(input, &mut Interner::default(), Some(DEFAULT_MAX_NESTING))
+}
+
+/// `parse` と同じだが、識別子の名前を `interner` にまとめ、式の入れ子の上限を `max_nesting` にする。
+/// 続けて解析する入力どうしで名前を共有できる
+pub fn parse_with(
+    input: &str,
+    interner: &mut Interner,
+    max_nesting: Option<usize>,
+) -> (Program, Vec<Diagnostic>) {
+    let mut p = new(lexer::borrowed(input).with_interner(std::mem::take(interner)));
+    p.max_nesting = max_nesting;
+    let program = p.parse_program();
+    *interner = p.lexer.take_interner();
+    (program, p.errors())
+}
+
+impl<'src> Parser<'src> {
+    /// エラーがあっても文の区切りまで読み飛ばして解析を続け、解析できた文だけを返す
+    pub fn parse_program(&mut self) -> Program {
+        let mut statements = vec![];
+        while !self.cur_is(TokenType::Special(Special::EOF)) {
+            let start = self.cur_span.start;
+            match self.parse_statement() {
+                Some(stmt) => {
+                    statements.push(stmt);
+                    self.next_token();
+                }
+                None => self.synchronize(start),
+            }
+        }
+        Program { statements }
+    }
+
+    /// 字句解析と構文解析のエラーをソース上の位置順に返す
+    pub fn errors(&self) -> Vec<Diagnostic> {
+        let mut errors: Vec<Diagnostic> = self
+            .lexer
+            .errors()
+            .iter()
+            .chain(self.errors.iter())
+            .cloned()
+            .collect();
+        errors.sort_by_key(|d| d.span.start);
+        errors
+    }
+
+    /// 読み飛ばしたコメント
+    pub fn comments(&self) -> &[Comment] {
+        self.lexer.comments()
+    }
+
+    /// 今の識別子のトークンの名前
+    fn symbol(&self) -> Symbol {
+        match &self.cur.symbol {
+            Some(symbol) => symbol.clone(),
+            None => Symbol::from(self.cur.literal),
+        }
+    }
+
+    fn next_token(&mut self) {
+        let (peek, peek_span) = self.lexer.next_token_with_span();
+        self.cur = std::mem::replace(&mut self.peek, peek);
+        self.cur_span = std::mem::replace(&mut self.peek_span, peek_span);
+    }
+
+    fn cur_is(&self, token_type: TokenType) -> bool {
+        self.cur.token_type == token_type
+    }
+
+    fn peek_is(&self, token_type: TokenType) -> bool {
+        self.peek.token_type == token_type
+    }
+
+    /// 次のトークンが `token_type` なら読み進める。違えばエラーを記録する。
+    fn expect_peek(&mut self, token_type: TokenType, expected: &str) -> Option<()> {
+        if self.peek_is(token_type) {
+            self.next_token();
+            Some(())
+        } else {
+            self.unexpected_peek(expected);
+            None
+        }
+    }
+
+    fn unexpected_peek(&mut self, expected: &str) {
+        // 不正な文字は字句解析のエラーとして報告済み
+        if self.peek_is(TokenType::Special(Special::Illegal)) {
+            return;
+        }
+        self.errors.push(diagnostic::error(
+            E_UNEXPECTED_TOKEN,
+            format!("expected {}, found {}", expected, describe(&self.peek)),
+            self.peek_span,
+        ));
+    }
+
+    /// エラーの後、次の文の始まりまで読み飛ばす。
+    /// `;` の次、または `}` `let` `return` の位置で止まる。ただし `start` から一歩は必ず進める。
+    /// 途中で開いた `{ ... }` は対応する `}` まで丸ごと読み飛ばす。
+    fn synchronize(&mut self, start: usize) {
+        let mut depth = 0;
+        loop {
+            match self.cur.token_type {
+                TokenType::Special(Special::EOF) => return,
+                TokenType::Paren(Paren::LBrace) => depth += 1,
+                TokenType::Paren(Paren::RBrace) if depth > 0 => depth -= 1,
+                _ if depth > 0 => {}
+                TokenType::Delimiter(Delimiter::SemiColon) => {
+                    self.next_token();
+                    return;
+                }
+                TokenType::Paren(Paren::RBrace)
+                | TokenType::KeyWord(KeyWord::Let)
+                | TokenType::KeyWord(KeyWord::Return)
+                    if self.cur_span.start != start =>
+                {
+                    return
+                }
+                _ => {}
+            }
+            self.next_token();
+        }
+    }
+
+    fn parse_statement(&mut self) -> Option<Statement> {
+        match self.cur.token_type {
+            TokenType::KeyWord(KeyWord::Let) => self.parse_let_statement(),
+            TokenType::KeyWord(KeyWord::Return) => self.parse_return_statement(),
+            _ => self.parse_expression_statement(),
+        }
+    }
+
+    fn parse_let_statement(&mut self) -> Option<Statement> {
+        let start = self.cur_span.start;
+        self.expect_peek(TokenType::Value(Value::Ident), "identifier")?;
+        let name = Identifier {
+            name: self.symbol(),
+            span: self.cur_span,
+        };
+        self.expect_peek(TokenType::Operator(Operator::Assign), "`=`")?;
+        self.next_token();
+        let value = self.parse_expression(Precedence::Lowest)?;
+        self.skip_semicolon();
+        Some(Statement {
+            kind: StatementKind::Let { name, value },
+            span: self.span_from(start),
+        })
+    }
+
+    fn parse_return_statement(&mut self) -> Option<Statement> {
+        let start = self.cur_span.start;
+        self.next_token();
+        let value = self.parse_expression(Precedence::Lowest)?;
+        self.skip_semicolon();
+        Some(Statement {
+            kind: StatementKind::Return(value),
+            span: self.span_from(start),
+        })
+    }
+
+    fn parse_expression_statement(&mut self) -> Option<Statement> {
+        let start = self.cur_span.start;
+        let expression = self.parse_expression(Precedence::Lowest)?;
+        self.skip_semicolon();
+        Some(Statement {
+            kind: StatementKind::Expression(expression),
+            span: self.span_from(start),
+        })
+    }
+
+    /// 文末の `;` は省略できる
+    fn skip_semicolon(&mut self) {
+        if self.peek_is(TokenType::Delimiter(Delimiter::SemiColon)) {
+            self.next_token();
+        }
+    }
+
+    /// `start` から現在のトークンの終わりまで
+    fn span_from(&self, start: usize) -> Span {
+        Span {
+            start,
+            end: self.cur_span.end,
+        }
+    }
+
+    fn parse_block(&mut self) -> Option<Block> {
+        let start = self.cur_span.start;
+        self.next_token();
+        let mut statements = vec![];
+        while !self.cur_is(TokenType::Paren(Paren::RBrace)) {
+            if self.cur_is(TokenType::Special(Special::EOF)) {
+                self.errors.push(diagnostic::error(
+                    E_UNEXPECTED_TOKEN,
+                    format!("expected `}}`, found {}", describe(&self.cur)),
+                    self.cur_span,
+                ));
+                return None;
+            }
+            let statement_start = self.cur_span.start;
+            match self.parse_statement() {
+                Some(stmt) => {
+                    statements.push(stmt);
+                    self.next_token();
+                }
+                None => self.synchronize(statement_start),
+            }
+        }
+        Some(Block {
+            statements,
+            span: self.span_from(start),
+        })
+    }
+
+    /// 入れ子を一段深くして解析する。左結合の連なりは `parse_nested` のループで読むので数えない
+    fn parse_expression(&mut self, precedence: Precedence) -> Option<Expression> {
+        if let Some(max) = self.max_nesting.filter(|&max| self.depth >= max) {
+            self.errors.push(diagnostic::error(
+                E_NESTING_TOO_DEEP,
+                format!("expressions are nested more than {} levels deep", max),
+                self.cur_span,
+            ));
+            return None;
+        }
+        self.depth += 1;
+        let outer = std::mem::take(&mut self.height);
+        let expression = limits::grow_stack(|| self.parse_nested(precedence));
+        self.height = self.height.max(outer);
+        self.depth -= 1;
+        expression
+    }
+
+    /// 読み終えたら `height` を読んだ式の木の高さにする
+    fn parse_nested(&mut self, precedence: Precedence) -> Option<Expression> {
+        let span = self.cur_span;
+        let mut left = self.parse_prefix()?;
+        let mut height = self.check_height(self.height + 1, span)?;
+        while !self.peek_is(TokenType::Delimiter(Delimiter::SemiColon))
+            && precedence < self::precedence(&self.peek.token_type)
+        {
+            self.next_token();
+            let span = self.cur_span;
+            self.height = 0;
+            left = match self.cur.token_type {
+                TokenType::Paren(Paren::LParen) => self.parse_call(left)?,
+                _ => self.parse_infix(left)?,
+            };
+            height = self.check_height(height.max(self.height) + 1, span)?;
+        }
+        self.height = height;
+        Some(left)
+    }
+
+    fn check_height(&mut self, height: usize, span: Span) -> Option<usize> {
+        if height > MAX_EXPRESSION_HEIGHT {
+            self.errors.push(diagnostic::error(
+                E_NESTING_TOO_DEEP,
+                format!(
+                    "expression is more than {} levels deep",
+                    MAX_EXPRESSION_HEIGHT
+                ),
+                span,
+            ));
+            return None;
+        }
+        Some(height)
+    }
+
+    fn parse_prefix(&mut self) -> Option<Expression> {
+        let span = self.cur_span;
+        match self.cur.token_type {
+            TokenType::Value(Value::Ident) => Some(Expression {
+                kind: ExpressionKind::Identifier(self.symbol()),
+                span,
+            }),
+            TokenType::Value(Value::Int) => match self.cur.literal.parse() {
+                Ok(value) => Some(Expression {
+                    kind: ExpressionKind::Integer(value),
+                    span,
+                }),
+                Err(_) => {
+                    self.errors.push(diagnostic::error(
+                        E_INTEGER_OVERFLOW,
+                        format!("integer literal `{}` is too large", self.cur.literal),
+                        span,
+                    ));
+                    None
+                }
+            },
+            TokenType::KeyWord(KeyWord::True) => Some(Expression {
+                kind: ExpressionKind::Boolean(true),
+                span,
+            }),
+            TokenType::KeyWord(KeyWord::False) => Some(Expression {
+                kind: ExpressionKind::Boolean(false),
+                span,
+            }),
+            TokenType::Operator(operator @ (Operator::Not | Operator::Minus)) => {
+                self.next_token();
+                let right = self.parse_expression(Precedence::Prefix)?;
+                Some(Expression {
+                    kind: ExpressionKind::Prefix {
+                        operator,
+                        right: Box::new(right),
+                    },
+                    span: self.span_from(span.start),
+                })
+            }
+            TokenType::Paren(Paren::LParen) => {
+                self.next_token();
+                let expression = self.parse_expression(Precedence::Lowest)?;
+                self.expect_peek(TokenType::Paren(Paren::RParen), "`)`")?;
+                Some(Expression {
+                    kind: expression.kind,
+                    span: self.span_from(span.start),
+                })
+            }
+            TokenType::KeyWord(KeyWord::If) => self.parse_if(),
+            TokenType::KeyWord(KeyWord::Function) => self.parse_function(),
+            TokenType::Special(Special::Illegal) => None,
+            _ => {
+                self.errors.push(diagnostic::error(
+                    E_EXPECTED_EXPRESSION,
+                    format!("expected expression, found {}", describe(&self.cur)),
+                    span,
+                ));
+                None
+            }
+        }
+    }
+
+    fn parse_infix(&mut self, left: Expression) -> Option<Expression> {
+        let operator = match self.cur.token_type {
+            TokenType::Operator(operator) => operator,
+            _ => unreachable!("infix operator expected"),
+        };
+        let precedence = precedence(&self.cur.token_type);
+        self.next_token();
+        let right = self.parse_expression(precedence)?;
+        let start = left.span.start;
+        Some(Expression {
+            kind: ExpressionKind::Infix {
+                operator,
+                left: Box::new(left),
+                right: Box::new(right),
+            },
+            span: self.span_from(start),
+        })
+    }
+
+    fn parse_if(&mut self) -> Option<Expression> {
+        let start = self.cur_span.start;
+        self.expect_peek(TokenType::Paren(Paren::LParen), "`(`")?;
+        self.next_token();
+        let condition = self.parse_expression(Precedence::Lowest)?;
+        self.expect_peek(TokenType::Paren(Paren::RParen), "`)`")?;
+        self.expect_peek(TokenType::Paren(Paren::LBrace), "`{`")?;
+        let consequence = self.parse_block()?;
+        let alternative = if self.peek_is(TokenType::KeyWord(KeyWord::Else)) {
+            self.next_token();
+            self.expect_peek(TokenType::Paren(Paren::LBrace), "`{`")?;
+            Some(self.parse_block()?)
+        } else {
+            None
+        };
+        Some(Expression {
+            kind: ExpressionKind::If {
+                condition: Box::new(condition),
+                consequence,
+                alternative,
+            },
+            span: self.span_from(start),
+        })
+    }
+
+    fn parse_function(&mut self) -> Option<Expression> {
+        let start = self.cur_span.start;
+        self.expect_peek(TokenType::Paren(Paren::LParen), "`(`")?;
+        let mut parameters = vec![];
+        if self.peek_is(TokenType::Paren(Paren::RParen)) {
+            self.next_token();
+        } else {
+            loop {
+                self.expect_peek(TokenType::Value(Value::Ident), "parameter name")?;
+                parameters.push(Identifier {
+                    name: self.symbol(),
+                    span: self.cur_span,
+                });
+                if self.peek_is(TokenType::Delimiter(Delimiter::Comma)) {
+                    self.next_token();
+                } else {
+                    self.expect_peek(TokenType::Paren(Paren::RParen), "`,` or `)`")?;
+                    break;
+                }
+            }
+        }
+        self.expect_peek(TokenType::Paren(Paren::LBrace), "`{`")?;
+        let body = self.parse_block()?;
+        Some(Expression {
+            kind: ExpressionKind::Function {
+                parameters,
+                body: Rc::new(body),
+            },
+            span: self.span_from(start),
+        })
+    }
+
+    fn parse_call(&mut self, function: Expression) -> Option<Expression> {
+        let mut arguments = vec![];
+        if self.peek_is(TokenType::Paren(Paren::RParen)) {
+            self.next_token();
+        } else {
+            loop {
+                self.next_token();
+                arguments.push(self.parse_expression(Precedence::Lowest)?);
+                if self.peek_is(TokenType::Delimiter(Delimiter::Comma)) {
+                    self.next_token();
+                } else {
+                    self.expect_peek(TokenType::Paren(Paren::RParen), "`,` or `)`")?;
+                    break;
+                }
+            }
+        }
+        let start = function.span.start;
+        Some(Expression {
+            kind: ExpressionKind::Call {
+                function: Box::new(function),
+                arguments,
+            },
+            span: self.span_from(start),
+        })
+    }
+}
+
+/// エラーメッセージ用のトークンの表記
+fn describe(tok: &TokenRef) -> String {
+    match tok.token_type {
+        TokenType::Special(Special::EOF) => "end of input".to_string(),
+        _ => format!("`{}`", tok.literal),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, parse_with, E_EXPECTED_EXPRESSION, E_NESTING_TOO_DEEP, E_UNEXPECTED_TOKEN};
+    use crate::ast::*;
+    use crate::lexer::E_ILLEGAL_CHARACTER;
+    use crate::limits::{DEFAULT_MAX_NESTING, MAX_EXPRESSION_HEIGHT};
+    use crate::span::{LineIndex, Position};
+    use crate::symbol::Interner;
+
+    #[test]
+    fn let_statement() {
+        let (program, errors) = parse("let x = 1 + 2 * 3;");
+        assert_eq!(errors, vec![]);
+        assert_eq!(program.statements.len(), 1);
+        let stmt = &program.statements[0];
+        assert_eq!(stmt.span.start, 0);
+        assert_eq!(stmt.span.end, 18);
+        match &stmt.kind {
+            StatementKind::Let { name, value } => {
+                assert_eq!(name.name, "x");
+                match &value.kind {
+                    ExpressionKind::Infix { right, .. } => {
+                        assert!(matches!(right.kind, ExpressionKind::Infix { .. }))
+                    }
+                    kind => panic!("unexpected expression {:?}", kind),
+                }
+            }
+            kind => panic!("unexpected statement {:?}", kind),
+        }
+    }
+
+    /// 括弧や左結合の連なりが深すぎればエラーにし、スタックを溢れさせない
+    #[test]
+    fn nesting_limit() {
+        let n = DEFAULT_MAX_NESTING / 2;
+        let shallow = [
+            format!("{}1{}", "(".repeat(n), ")".repeat(n)),
+            format!("{}1", "-".repeat(n)),
+        ];
+        for input in &shallow {
+            assert_eq!(parse(input).1, vec![], "{}", &input[..10]);
+        }
+        // 左結合の連なりは再帰しないので、入れ子に数えない
+        let n = MAX_EXPRESSION_HEIGHT / 4;
+        assert!(n > DEFAULT_MAX_NESTING);
+        let flat = [
+            format!("1{}", " + 1".repeat(n)),
+            format!("f{}", "()".repeat(n)),
+            format!("f{}", "(1) * 2 - x".repeat(n)),
+        ];
+        for input in &flat {
+            assert_eq!(parse(input).1, vec![], "{}", &input[..10]);
+        }
+        let n = DEFAULT_MAX_NESTING * 2;
+        let deep = [
+            format!("{}1{}; 2", "(".repeat(n), ")".repeat(n)),
+            format!("{}1{}; 2", "if (true) { ".repeat(n), " }".repeat(n)),
+            format!("{}; 2", "-".repeat(n)),
+            format!("1{}; 2", " + (1".repeat(n)),
+        ];
+        for input in &deep {
+            let (program, errors) = parse(input);
+            let codes: Vec<_> = errors.iter().map(|d| d.code).collect();
+            assert_eq!(codes, vec![E_NESTING_TOO_DEEP], "{}", &input[..10]);
+            // 文の区切りから解析を続ける
+            let last = &program.statements.last().unwrap().kind;
+            assert!(
+                matches!(
+                    last,
+                    StatementKind::Expression(Expression {
+                        kind: ExpressionKind::Integer(2),
+                        ..
+                    })
+                ),
+                "{}",
+                &input[..10]
+            );
+        }
+    }
+
+    /// 上限は呼び出し側で変えられる
+    #[test]
+    fn configurable_nesting_limit() {
+        let input = format!("{}1", "-".repeat(DEFAULT_MAX_NESTING * 2));
+        let mut interner = Interner::default();
+        let (_, errors) = parse_with(&input, &mut interner, None);
+        assert_eq!(errors, vec![]);
+        let (_, errors) = parse_with("--1", &mut interner, Some(2));
+        let codes: Vec<_> = errors.iter().map(|d| d.code).collect();
+        assert_eq!(codes, vec![E_NESTING_TOO_DEEP]);
+        assert_eq!(
+            errors[0].message,
+            "expressions are nested more than 2 levels deep"
+        );
+        assert_eq!(parse_with("-1 + 2 + 3", &mut interner, Some(2)).1, vec![]);
+    }
+
+    /// 木の高さは入れ子の上限によらず `MAX_EXPRESSION_HEIGHT` までで、連なりも数える
+    #[test]
+    fn expression_height_limit() {
+        let n = MAX_EXPRESSION_HEIGHT;
+        let tall = [
+            format!("1{}; 2", " + 1".repeat(n)),
+            format!("f{}; 2", "()".repeat(n)),
+            format!("{}1; 2", "-".repeat(n)),
+            format!("1{}{}; 2", " + (1".repeat(n / 2), ")".repeat(n / 2)),
+        ];
+        for input in &tall {
+            let (program, errors) = parse_with(input, &mut Interner::default(), None);
+            let codes: Vec<_> = errors.iter().map(|d| d.code).collect();
+            assert_eq!(codes, vec![E_NESTING_TOO_DEEP], "{}", &input[..10]);
+            assert_eq!(
+                errors[0].message,
+                format!("expression is more than {} levels deep", n)
+            );
+            assert_eq!(program.statements.len(), 1, "{}", &input[..10]);
+        }
+        let input = format!("1{}", " + 1".repeat(n - 1));
+        assert_eq!(parse(&input).1, vec![]);
+    }
+
+    #[test]
+    fn reports_every_error() {
+        let input = "
+let x 5;
+let = 10;
+let y = 3;
+let z = * 2;
+let add = fn(a, b) {
+    let c = ;
+    a + b;
+};
+let @ = 1;
+return y;
+";
+        let (program, errors) = parse(input);
+        let index = LineIndex::new(input);
+        let found: Vec<(&str, Position)> = errors
+            .iter()
+            .map(|e| (e.code, index.position(e.span.start)))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (E_UNEXPECTED_TOKEN, Position { line: 2, column: 7 }),
+                (E_UNEXPECTED_TOKEN, Position { line: 3, column: 5 }),
+                (E_EXPECTED_EXPRESSION, Position { line: 5, column: 9 }),
+                (
+                    E_EXPECTED_EXPRESSION,
+                    Position {
+                        line: 7,
+                        column: 13
+                    }
+                ),
+                (
+                    E_ILLEGAL_CHARACTER,
+                    Position {
+                        line: 10,
+                        column: 5
+                    }
+                ),
+            ]
+        );
+        // 壊れていない文はすべて解析できている
+        let kinds: Vec<&str> = program
+            .statements
+            .iter()
+            .map(|s| match &s.kind {
+                StatementKind::Let { name, .. } => name.name.as_str(),
+                StatementKind::Return(_) => "return",
+                StatementKind::Expression(_) => "expression",
+            })
+            .collect();
+        assert_eq!(kinds, vec!["y", "add", "return"]);
+    }
+
+    #[test]
+    fn stray_closing_brace() {
+        let (program, errors) = parse("} let a = 1; }");
+        assert_eq!(errors.len(), 2);
+        assert_eq!(program.statements.len(), 1);
+    }
+
+    #[test]
+    fn skips_nested_block() {
+        let (program, errors) = parse("let f = fn(a,) { let b = a; b };\nlet g = 1;");
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "expected parameter name, found `)`");
+        assert_eq!(program.statements.len(), 1);
+    }
+
+    #[test]
+    fn unclosed_block() {
+        let (program, errors) = parse("let f = fn() { 1");
+        assert_eq!(program.statements.len(), 0);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].message, "expected `}`, found end of input");
+    }
+}
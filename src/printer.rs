@@ -0,0 +1,544 @@
+//! 構文木を Monkey のソースに戻す。
+
+use crate::ast::*;
+use crate::lexer::Comment;
+use crate::limits;
+use crate::span::LineIndex;
+use crate::token::Operator;
+
+const INDENT: &str = "    ";
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Mode {
+    /// 優先順位の解釈に必要な括弧だけを付ける
+    Normal,
+    /// 前置・中置の式をすべて括弧で囲む。演算子の優先順位を確かめるため。
+    Parenthesized,
+}
+
+pub fn print_program(program: &Program, mode: Mode) -> String {
+    let mut p = Printer::new(mode, &[], None);
+    for statement in &program.statements {
+        p.statement(statement);
+    }
+    p.out
+}
+
+pub fn print_expression(expression: &Expression, mode: Mode) -> String {
+    let mut p = Printer::new(mode, &[], None);
+    p.expression(expression);
+    p.out
+}
+
+/// コメントを元の位置の近くに残して印字する。
+/// 文と同じ行に続くコメントはその文の後ろに、それ以外は次の文の前の行に置く。
+/// 文の間の空行は 1 行までに詰めて残す。
+pub fn print_program_with_comments(
+    program: &Program,
+    comments: &[Comment],
+    index: &LineIndex,
+) -> String {
+    let mut p = Printer::new(Mode::Normal, comments, Some(index));
+    for statement in &program.statements {
+        p.statement(statement);
+    }
+    p.comments_before(usize::MAX);
+    p.out
+}
+
+/// 式の結合の強さ。大きいほど強く結び付く。
+fn binding_power(expression: &Expression) -> u8 {
+    match &expression.kind {
+        ExpressionKind::Infix { operator, .. } => infix_binding_power(*operator),
+        ExpressionKind::Prefix { .. } => 5,
+        _ => 7,
+    }
+}
+
+fn infix_binding_power(operator: Operator) -> u8 {
+    match operator {
+        Operator::Eq | Operator::NotEq => 1,
+        Operator::Lt | Operator::Gt => 2,
+        Operator::Plus | Operator::Minus => 3,
+        Operator::Mul | Operator::Div => 4,
+        _ => unreachable!("not an infix operator: {:?}", operator),
+    }
+}
+
+struct Printer<'a> {
+    out: String,
+    indent: usize,
+    mode: Mode,
+    /// まだ印字していないコメント
+    comments: &'a [Comment],
+    index: Option<&'a LineIndex>,
+    /// 最後に印字した文かコメントの、元のソースでの最終行
+    last_line: Option<usize>,
+    /// 印字中のブロックの終わり。これより後のコメントはブロック内の文に付けない。
+    block_end: usize,
+}
+
+impl<'a> Printer<'a> {
+    fn new(mode: Mode, comments: &'a [Comment], index: Option<&'a LineIndex>) -> Printer<'a> {
+        Printer {
+            out: String::new(),
+            indent: 0,
+            mode,
+            comments,
+            index,
+            last_line: None,
+            block_end: usize::MAX,
+        }
+    }
+
+    fn line_start(&mut self) {
+        for _ in 0..self.indent {
+            self.out += INDENT;
+        }
+    }
+
+    fn line(&self, offset: usize) -> Option<usize> {
+        self.index.map(|index| index.position(offset).line)
+    }
+
+    /// 元のソースで空行を挟んでいたなら空行を 1 行入れる
+    fn blank_line(&mut self, start: usize) {
+        if let (Some(last), Some(line)) = (self.last_line, self.line(start)) {
+            if line > last + 1 {
+                self.out += "\n";
+            }
+        }
+    }
+
+    /// `offset` より前にあるコメントを 1 行ずつ印字する
+    fn comments_before(&mut self, offset: usize) {
+        while let Some((comment, rest)) = self.comments.split_first() {
+            if comment.span.start >= offset {
+                return;
+            }
+            self.comments = rest;
+            self.blank_line(comment.span.start);
+            self.line_start();
+            self.out += &comment.text;
+            self.out += "\n";
+            self.last_line = self.line(comment.span.start);
+        }
+    }
+
+    /// `end` で終わる文と同じ行に続くコメントを印字する
+    fn trailing_comment(&mut self, end: usize) {
+        if let Some((comment, rest)) = self.comments.split_first() {
+            if comment.span.start >= end
+                && comment.span.start < self.block_end
+                && self.line(comment.span.start) == self.line(end.saturating_sub(1))
+            {
+                self.comments = rest;
+                self.out += " ";
+                self.out += &comment.text;
+            }
+        }
+    }
+
+    fn statement(&mut self, statement: &Statement) {
+        self.comments_before(statement.span.start);
+        self.blank_line(statement.span.start);
+        self.line_start();
+        match &statement.kind {
+            StatementKind::Let { name, value } => {
+                self.out += "let ";
+                self.out += name.name.as_str();
+                self.out += " = ";
+                self.expression(value);
+            }
+            StatementKind::Return(value) => {
+                self.out += "return ";
+                self.expression(value);
+            }
+            StatementKind::Expression(expression) => self.expression(expression),
+        }
+        self.out += ";";
+        self.trailing_comment(statement.span.end);
+        self.out += "\n";
+        self.last_line = self.line(statement.span.end.saturating_sub(1));
+    }
+
+    fn block(&mut self, block: &Block) {
+        let has_comments = self
+            .comments
+            .first()
+            .is_some_and(|c| c.span.start < block.span.end);
+        if block.statements.is_empty() && !has_comments {
+            self.out += "{}";
+            return;
+        }
+        self.out += "{\n";
+        self.indent += 1;
+        // ブロックの先頭には空行を入れない
+        self.last_line = None;
+        let block_end = std::mem::replace(&mut self.block_end, block.span.end);
+        for statement in &block.statements {
+            self.statement(statement);
+        }
+        self.comments_before(block.span.end);
+        self.block_end = block_end;
+        self.indent -= 1;
+        self.line_start();
+        self.out += "}";
+    }
+
+    /// `min` より弱く結び付く式は括弧で囲む
+    fn operand(&mut self, expression: &Expression, min: u8) {
+        if self.mode == Mode::Normal && binding_power(expression) < min {
+            self.out += "(";
+            self.expression(expression);
+            self.out += ")";
+        } else {
+            self.expression(expression);
+        }
+    }
+
+    fn expression(&mut self, expression: &Expression) {
+        limits::grow_stack(|| {
+            match &expression.kind {
+                ExpressionKind::Identifier(name) => self.out += name.as_str(),
+                ExpressionKind::Integer(value) => self.out += &value.to_string(),
+                ExpressionKind::Boolean(value) => self.out += &value.to_string(),
+                ExpressionKind::Prefix { operator, right } => {
+                    self.open();
+                    self.out += operator.as_str();
+                    self.operand(right, 5);
+                    self.close();
+                }
+                ExpressionKind::Infix {
+                    operator,
+                    left,
+                    right,
+                } => {
+                    let power = infix_binding_power(*operator);
+                    self.open();
+                    // 左結合なので右側は同じ強さでも括弧が要る
+                    self.operand(left, power);
+                    self.out += " ";
+                    self.out += operator.as_str();
+                    self.out += " ";
+                    self.operand(right, power + 1);
+                    self.close();
+                }
+                ExpressionKind::If {
+                    condition,
+                    consequence,
+                    alternative,
+                } => {
+                    self.out += "if (";
+                    self.expression(condition);
+                    self.out += ") ";
+                    self.block(consequence);
+                    if let Some(alternative) = alternative {
+                        self.out += " else ";
+                        self.block(alternative);
+                    }
+                }
+                ExpressionKind::Function { parameters, body } => {
+                    self.out += "fn(";
+                    let names: Vec<&str> = parameters.iter().map(|p| p.name.as_str()).collect();
+                    self.out += &names.join(", ");
+                    self.out += ") ";
+                    self.block(body);
+                }
+                ExpressionKind::Call {
+                    function,
+                    arguments,
+                } => {
+                    self.operand(function, 6);
+                    self.out += "(";
+                    for (i, argument) in arguments.iter().enumerate() {
+                        if i > 0 {
+                            self.out += ", ";
+                        }
+                        self.expression(argument);
+                    }
+                    self.out += ")";
+                }
+            }
+        })
+    }
+
+    fn open(&mut self) {
+        if self.mode == Mode::Parenthesized {
+            self.out += "(";
+        }
+    }
+
+    fn close(&mut self) {
+        if self.mode == Mode::Parenthesized {
+            self.out += ")";
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use proptest::prelude::*;
+
+    use super::{print_program, Mode};
+    use crate::ast::*;
+    use crate::parser;
+    use crate::span::Span;
+    use crate::symbol::Symbol;
+    use crate::token::Operator;
+
+    fn parse(input: &str) -> Program {
+        let (program, errors) = parser::parse(input);
+        assert_eq!(errors, vec![], "{}", input);
+        program
+    }
+
+    #[test]
+    fn normal() {
+        let input = "let add = fn(x, y) { return x + y; }; if ((1 + 2) * -f(3) == 9) { add(1, 2) } else { }";
+        assert_eq!(
+            print_program(&parse(input), Mode::Normal),
+            "\
+let add = fn(x, y) {
+    return x + y;
+};
+if ((1 + 2) * -f(3) == 9) {
+    add(1, 2);
+} else {};
+"
+        );
+    }
+
+    #[test]
+    fn parenthesized() {
+        let tests = [
+            ("-a * b", "((-a) * b);\n"),
+            ("!-a", "(!(-a));\n"),
+            ("a + b - c", "((a + b) - c);\n"),
+            (
+                "a + b * c + d / e - f",
+                "(((a + (b * c)) + (d / e)) - f);\n",
+            ),
+            ("5 > 4 == 3 < 4", "((5 > 4) == (3 < 4));\n"),
+            ("a + add(b * c) + d", "((a + add((b * c))) + d);\n"),
+            ("a - (b - c)", "(a - (b - c));\n"),
+        ];
+        for (input, expected) in tests {
+            assert_eq!(print_program(&parse(input), Mode::Parenthesized), expected);
+        }
+    }
+
+    // 位置情報は印字し直すと変わるので比較の前に消す
+    fn strip_statement(statement: &Statement) -> Statement {
+        let kind = match &statement.kind {
+            StatementKind::Let { name, value } => StatementKind::Let {
+                name: strip_identifier(name),
+                value: strip_expression(value),
+            },
+            StatementKind::Return(value) => StatementKind::Return(strip_expression(value)),
+            StatementKind::Expression(expression) => {
+                StatementKind::Expression(strip_expression(expression))
+            }
+        };
+        Statement {
+            kind,
+            span: Span { start: 0, end: 0 },
+        }
+    }
+
+    fn strip_identifier(identifier: &Identifier) -> Identifier {
+        Identifier {
+            name: identifier.name.clone(),
+            span: Span { start: 0, end: 0 },
+        }
+    }
+
+    fn strip_block(block: &Block) -> Block {
+        Block {
+            statements: block.statements.iter().map(strip_statement).collect(),
+            span: Span { start: 0, end: 0 },
+        }
+    }
+
+    fn strip_expression(expression: &Expression) -> Expression {
+        let boxed = |e: &Expression| Box::new(strip_expression(e));
+        let kind = match &expression.kind {
+            ExpressionKind::Prefix { operator, right } => ExpressionKind::Prefix {
+                operator: *operator,
+                right: boxed(right),
+            },
+            ExpressionKind::Infix {
+                operator,
+                left,
+                right,
+            } => ExpressionKind::Infix {
+                operator: *operator,
+                left: boxed(left),
+                right: boxed(right),
+            },
+            ExpressionKind::If {
+                condition,
+                consequence,
+                alternative,
+            } => ExpressionKind::If {
+                condition: boxed(condition),
+                consequence: strip_block(consequence),
+                alternative: alternative.as_ref().map(strip_block),
+            },
+            ExpressionKind::Function { parameters, body } => ExpressionKind::Function {
+                parameters: parameters.iter().map(strip_identifier).collect(),
+                body: Rc::new(strip_block(body)),
+            },
+            ExpressionKind::Call {
+                function,
+                arguments,
+            } => ExpressionKind::Call {
+                function: boxed(function),
+                arguments: arguments.iter().map(strip_expression).collect(),
+            },
+            kind => kind.clone(),
+        };
+        Expression {
+            kind,
+            span: Span { start: 0, end: 0 },
+        }
+    }
+
+    fn strip(program: &Program) -> Program {
+        Program {
+            statements: program.statements.iter().map(strip_statement).collect(),
+        }
+    }
+
+    fn node<T>(kind: T) -> (T, Span) {
+        (kind, Span { start: 0, end: 0 })
+    }
+
+    fn identifier() -> impl Strategy<Value = Symbol> {
+        "[a-z_][a-z0-9_]{0,5}"
+            .prop_filter("keyword", |s| {
+                !["fn", "let", "true", "false", "if", "else", "return"].contains(&s.as_str())
+            })
+            .prop_map(|s| Symbol::from(s.as_str()))
+    }
+
+    fn expression() -> BoxedStrategy<Expression> {
+        let leaf = prop_oneof![
+            identifier().prop_map(ExpressionKind::Identifier),
+            (0..=i64::MAX).prop_map(ExpressionKind::Integer),
+            any::<bool>().prop_map(ExpressionKind::Boolean),
+        ]
+        .prop_map(|kind| {
+            let (kind, span) = node(kind);
+            Expression { kind, span }
+        });
+        leaf.prop_recursive(4, 32, 4, |inner| {
+            let block = prop::collection::vec(statement(inner.clone()), 0..3)
+                .prop_map(|s| {
+                    let (statements, span) = node(s);
+                    Block { statements, span }
+                })
+                .boxed();
+            let infix = prop_oneof![
+                Just(Operator::Plus),
+                Just(Operator::Minus),
+                Just(Operator::Mul),
+                Just(Operator::Div),
+                Just(Operator::Lt),
+                Just(Operator::Gt),
+                Just(Operator::Eq),
+                Just(Operator::NotEq),
+            ];
+            prop_oneof![
+                (
+                    prop_oneof![Just(Operator::Not), Just(Operator::Minus)],
+                    inner.clone()
+                )
+                    .prop_map(|(operator, right)| ExpressionKind::Prefix {
+                        operator,
+                        right: Box::new(right),
+                    }),
+                (infix, inner.clone(), inner.clone()).prop_map(|(operator, left, right)| {
+                    ExpressionKind::Infix {
+                        operator,
+                        left: Box::new(left),
+                        right: Box::new(right),
+                    }
+                }),
+                (
+                    inner.clone(),
+                    block.clone(),
+                    prop::option::of(block.clone())
+                )
+                    .prop_map(|(condition, consequence, alternative)| {
+                        ExpressionKind::If {
+                            condition: Box::new(condition),
+                            consequence,
+                            alternative,
+                        }
+                    }),
+                (prop::collection::vec(identifier(), 0..3), block).prop_map(
+                    |(parameters, body)| ExpressionKind::Function {
+                        parameters: parameters
+                            .into_iter()
+                            .map(|name| {
+                                let (name, span) = node(name);
+                                Identifier { name, span }
+                            })
+                            .collect(),
+                        body: Rc::new(body),
+                    }
+                ),
+                (inner.clone(), prop::collection::vec(inner, 0..3)).prop_map(
+                    |(function, arguments)| ExpressionKind::Call {
+                        function: Box::new(function),
+                        arguments,
+                    }
+                ),
+            ]
+            .prop_map(|kind| {
+                let (kind, span) = node(kind);
+                Expression { kind, span }
+            })
+        })
+        .boxed()
+    }
+
+    fn statement(expression: BoxedStrategy<Expression>) -> BoxedStrategy<Statement> {
+        prop_oneof![
+            (identifier(), expression.clone()).prop_map(|(name, value)| StatementKind::Let {
+                name: {
+                    let (name, span) = node(name);
+                    Identifier { name, span }
+                },
+                value,
+            }),
+            expression.clone().prop_map(StatementKind::Return),
+            expression.prop_map(StatementKind::Expression),
+        ]
+        .prop_map(|kind| {
+            let (kind, span) = node(kind);
+            Statement { kind, span }
+        })
+        .boxed()
+    }
+
+    fn program() -> impl Strategy<Value = Program> {
+        prop::collection::vec(statement(expression()), 0..4)
+            .prop_map(|statements| Program { statements })
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(program in program()) {
+            for mode in [Mode::Normal, Mode::Parenthesized] {
+                let source = print_program(&program, mode);
+                let (reparsed, errors) = parser::parse(&source);
+                prop_assert_eq!(errors, vec![], "{}", source);
+                prop_assert_eq!(strip(&reparsed), program.clone(), "{}", source);
+            }
+        }
+    }
+}
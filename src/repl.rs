@@ -1,24 +1,23 @@
 use std::io::{self, Write};
+use std::process;
 
-use crate::{lexer, token};
+use learn_interpreter::{diagnostic, dump, lexer};
 
 pub fn start() -> ! {
     loop {
         let mut line = String::with_capacity(1000);
         print!(">> ");
         io::stdout().flush().unwrap();
-        io::stdin()
+        let read = io::stdin()
             .read_line(&mut line)
             .expect("Failed to read line.");
-        let mut l = lexer::new(line);
-
-        loop {
-            let tok = l.next_token();
-            if tok.token_type == token::TokenType::Special(token::Special::EOF) {
-                break;
-            } else {
-                println!("{:?}", tok)
-            }
+        if read == 0 {
+            // 入力の終わり (Ctrl-D)
+            println!();
+            process::exit(0);
         }
+        let (tokens, errors) = lexer::tokenize(&line);
+        print!("{}", dump::tokens_table(&line, &tokens));
+        eprint!("{}", diagnostic::render_all(&errors, &line, "<repl>"));
     }
 }
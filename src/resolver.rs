@@ -0,0 +1,255 @@
+//! 識別子がどの `let` や仮引数を指しているかを調べる。
+//!
+//! `if` のブロックはスコープを作らず、関数の本体だけが新しいスコープになる。
+//! 関数の本体は呼び出されたときに評価されるので、外側のスコープを最後まで見てから解決する。
+//! そのため本体からは、後で定義される外側の束縛も参照できる。
+
+use std::collections::HashMap;
+
+use crate::ast::*;
+use crate::limits;
+use crate::span::Span;
+use crate::symbol::Symbol;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BindingKind {
+    Let,
+    Parameter,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Binding {
+    pub name: Symbol,
+    pub kind: BindingKind,
+    /// 束縛する識別子の位置
+    pub span: Span,
+    /// この束縛を参照している識別子の位置
+    pub references: Vec<Span>,
+    /// この束縛が隠した、外側または同じスコープの束縛
+    pub shadows: Option<usize>,
+    /// `let` で関数リテラルを束縛しているか
+    pub function: bool,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Resolution {
+    pub bindings: Vec<Binding>,
+    /// どの束縛にも解決できなかった識別子
+    pub unresolved: Vec<(Symbol, Span)>,
+}
+
+impl Resolution {
+    /// `offset` にある識別子 (束縛する側でも参照する側でもよい) が指す束縛
+    pub fn binding_at(&self, offset: usize) -> Option<usize> {
+        let contains = |span: &Span| span.start <= offset && offset < span.end;
+        self.bindings
+            .iter()
+            .position(|b| contains(&b.span) || b.references.iter().any(contains))
+    }
+}
+
+pub fn resolve(program: &Program) -> Resolution {
+    let mut r = Resolver {
+        resolution: Resolution::default(),
+        scopes: vec![],
+    };
+    let global = r.new_scope(None);
+    r.scope(global, &program.statements, &[]);
+    r.resolution
+}
+
+struct Scope {
+    parent: Option<usize>,
+    names: HashMap<Symbol, usize>,
+}
+
+struct Resolver {
+    resolution: Resolution,
+    scopes: Vec<Scope>,
+}
+
+/// 本体の解決を後回しにしている関数リテラル
+struct Deferred<'a> {
+    scope: usize,
+    parameters: &'a [Identifier],
+    body: &'a Block,
+}
+
+impl Resolver {
+    fn new_scope(&mut self, parent: Option<usize>) -> usize {
+        self.scopes.push(Scope {
+            parent,
+            names: HashMap::new(),
+        });
+        self.scopes.len() - 1
+    }
+
+    fn lookup(&self, scope: usize, name: &Symbol) -> Option<usize> {
+        let mut scope = Some(scope);
+        while let Some(s) = scope {
+            if let Some(binding) = self.scopes[s].names.get(name) {
+                return Some(*binding);
+            }
+            scope = self.scopes[s].parent;
+        }
+        None
+    }
+
+    fn declare(
+        &mut self,
+        scope: usize,
+        identifier: &Identifier,
+        kind: BindingKind,
+        function: bool,
+    ) {
+        let shadows = self.lookup(scope, &identifier.name);
+        self.resolution.bindings.push(Binding {
+            name: identifier.name.clone(),
+            kind,
+            span: identifier.span,
+            references: vec![],
+            shadows,
+            function,
+        });
+        let id = self.resolution.bindings.len() - 1;
+        self.scopes[scope].names.insert(identifier.name.clone(), id);
+    }
+
+    /// スコープ内の文を解決し、最後に中で定義された関数の本体を解決する
+    fn scope(&mut self, scope: usize, statements: &[Statement], parameters: &[Identifier]) {
+        for parameter in parameters {
+            self.declare(scope, parameter, BindingKind::Parameter, false);
+        }
+        let mut deferred = vec![];
+        self.statements(scope, statements, &mut deferred);
+        for function in deferred {
+            let inner = self.new_scope(Some(function.scope));
+            self.scope(inner, &function.body.statements, function.parameters);
+        }
+    }
+
+    fn statements<'a>(
+        &mut self,
+        scope: usize,
+        statements: &'a [Statement],
+        deferred: &mut Vec<Deferred<'a>>,
+    ) {
+        for statement in statements {
+            match &statement.kind {
+                StatementKind::Let { name, value } => {
+                    self.expression(scope, value, deferred);
+                    let function = matches!(value.kind, ExpressionKind::Function { .. });
+                    self.declare(scope, name, BindingKind::Let, function);
+                }
+                StatementKind::Return(value) | StatementKind::Expression(value) => {
+                    self.expression(scope, value, deferred)
+                }
+            }
+        }
+    }
+
+    fn expression<'a>(
+        &mut self,
+        scope: usize,
+        expression: &'a Expression,
+        deferred: &mut Vec<Deferred<'a>>,
+    ) {
+        limits::grow_stack(|| match &expression.kind {
+            ExpressionKind::Identifier(name) => match self.lookup(scope, name) {
+                Some(binding) => self.resolution.bindings[binding]
+                    .references
+                    .push(expression.span),
+                None => self
+                    .resolution
+                    .unresolved
+                    .push((name.clone(), expression.span)),
+            },
+            ExpressionKind::Integer(_) | ExpressionKind::Boolean(_) => {}
+            ExpressionKind::Prefix { right, .. } => self.expression(scope, right, deferred),
+            ExpressionKind::Infix { left, right, .. } => {
+                self.expression(scope, left, deferred);
+                self.expression(scope, right, deferred);
+            }
+            ExpressionKind::If {
+                condition,
+                consequence,
+                alternative,
+            } => {
+                self.expression(scope, condition, deferred);
+                self.statements(scope, &consequence.statements, deferred);
+                if let Some(alternative) = alternative {
+                    self.statements(scope, &alternative.statements, deferred);
+                }
+            }
+            ExpressionKind::Function { parameters, body } => deferred.push(Deferred {
+                scope,
+                parameters,
+                body,
+            }),
+            ExpressionKind::Call {
+                function,
+                arguments,
+            } => {
+                self.expression(scope, function, deferred);
+                for argument in arguments {
+                    self.expression(scope, argument, deferred);
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{resolve, BindingKind};
+    use crate::parser;
+
+    #[test]
+    fn resolve_bindings() {
+        let input = "
+let f = fn(x) { g(x) };
+let g = fn(y) { let x = y; x };
+f(1);
+h;
+";
+        let (program, errors) = parser::parse(input);
+        assert_eq!(errors, vec![]);
+        let resolution = resolve(&program);
+        let summary: Vec<(&str, BindingKind, usize, Option<&str>)> = resolution
+            .bindings
+            .iter()
+            .map(|b| {
+                (
+                    b.name.as_str(),
+                    b.kind,
+                    b.references.len(),
+                    b.shadows.map(|s| resolution.bindings[s].name.as_str()),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                ("f", BindingKind::Let, 1, None),
+                ("g", BindingKind::Let, 1, None),
+                ("x", BindingKind::Parameter, 1, None),
+                ("y", BindingKind::Parameter, 1, None),
+                ("x", BindingKind::Let, 1, None),
+            ]
+        );
+        assert_eq!(resolution.unresolved.len(), 1);
+        assert_eq!(resolution.unresolved[0].0, "h");
+
+        let use_of_g = input.find("g(x)").unwrap();
+        assert_eq!(resolution.binding_at(use_of_g), Some(1));
+    }
+
+    #[test]
+    fn shadowing() {
+        let (program, _) = parser::parse("let x = 1; let f = fn(x) { x }; let x = 2;");
+        let resolution = resolve(&program);
+        let shadows: Vec<Option<usize>> = resolution.bindings.iter().map(|b| b.shadows).collect();
+        // 後から定義した x は関数より先に解決されるので、仮引数は二つ目の x を隠す
+        assert_eq!(shadows, vec![None, None, Some(0), Some(2)]);
+    }
+}
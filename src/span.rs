@@ -0,0 +1,66 @@
+/// ソース上の範囲。`start` と `end` は文字単位のオフセットで、`end` は含まない。
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// 1 始まりの行・列
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// オフセットから行・列を引くための、各行の先頭オフセットの一覧
+pub struct LineIndex {
+    line_starts: Vec<usize>,
+}
+
+impl LineIndex {
+    pub fn new(input: &str) -> LineIndex {
+        let mut line_starts = vec![0];
+        for (i, c) in input.chars().enumerate() {
+            if c == '\n' {
+                line_starts.push(i + 1);
+            }
+        }
+        LineIndex { line_starts }
+    }
+
+    /// 保存しておいた行頭の一覧から作る。0 から始まって増えていなければ `None`。
+    pub fn from_line_starts(line_starts: Vec<usize>) -> Option<LineIndex> {
+        let valid = line_starts.first() == Some(&0) && line_starts.windows(2).all(|w| w[0] < w[1]);
+        valid.then_some(LineIndex { line_starts })
+    }
+
+    pub fn line_starts(&self) -> &[usize] {
+        &self.line_starts
+    }
+
+    pub fn position(&self, offset: usize) -> Position {
+        let line = match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(next) => next - 1,
+        };
+        Position {
+            line: line + 1,
+            column: offset - self.line_starts[line] + 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{LineIndex, Position};
+
+    #[test]
+    fn position() {
+        let index = LineIndex::new("let a = 1;\nlet b = 2;\n");
+        assert_eq!(index.position(0), Position { line: 1, column: 1 });
+        assert_eq!(index.position(4), Position { line: 1, column: 5 });
+        assert_eq!(index.position(11), Position { line: 2, column: 1 });
+        assert_eq!(index.position(15), Position { line: 2, column: 5 });
+        assert_eq!(index.position(22), Position { line: 3, column: 1 });
+    }
+}
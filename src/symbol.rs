@@ -0,0 +1,139 @@
+//! 識別子の名前を表す `Symbol` と、同じ名前を一つの確保にまとめる intern 表。
+//!
+//! 表はプロセスで一つではなく、構文解析するプログラムや `Evaluator`、
+//! `.mkc` の読み込みごとに持つ。表とそこから作った `Symbol` がすべて消えれば名前も解放する。
+//! `Symbol` は作るときに名前のハッシュ値を一度だけ求めて持ち、環境を引くときは名前を読まない。
+//! 同じ表から作った `Symbol` はポインタの比較で済み、別の表から作ったものとは中身で比べる。
+
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::rc::Rc;
+use std::sync::OnceLock;
+
+#[derive(Clone)]
+pub struct Symbol {
+    name: Rc<str>,
+    /// `name` のハッシュ値。別の表から作っても、同じ名前なら同じ値になる
+    hash: u64,
+}
+
+#[derive(Default)]
+pub struct Interner {
+    names: HashMap<Rc<str>, Symbol>,
+}
+
+impl Interner {
+    pub fn intern(&mut self, name: &str) -> Symbol {
+        if let Some(symbol) = self.names.get(name) {
+            return symbol.clone();
+        }
+        let symbol = Symbol::from(name);
+        self.names.insert(Rc::clone(&symbol.name), symbol.clone());
+        symbol
+    }
+
+    /// 表に加えずに引く
+    pub fn get(&self, name: &str) -> Option<Symbol> {
+        self.names.get(name).cloned()
+    }
+}
+
+/// 名前のハッシュ値を求める。鍵はプロセスごとに選ぶので、外から衝突する名前を狙えない
+fn hash_name(name: &str) -> u64 {
+    static KEYS: OnceLock<RandomState> = OnceLock::new();
+    KEYS.get_or_init(RandomState::new).hash_one(name)
+}
+
+impl Symbol {
+    pub fn as_str(&self) -> &str {
+        &self.name
+    }
+}
+
+/// 表を通さずに作る。同じ名前でも確保は共有しない
+impl From<&str> for Symbol {
+    fn from(name: &str) -> Symbol {
+        Symbol {
+            name: Rc::from(name),
+            hash: hash_name(name),
+        }
+    }
+}
+
+impl PartialEq for Symbol {
+    fn eq(&self, other: &Symbol) -> bool {
+        Rc::ptr_eq(&self.name, &other.name) || (self.hash == other.hash && self.name == other.name)
+    }
+}
+
+impl Eq for Symbol {}
+
+impl Hash for Symbol {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash);
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+impl PartialEq<&str> for Symbol {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::rc::Rc;
+
+    use super::{Interner, Symbol};
+
+    #[test]
+    fn intern() {
+        let mut interner = Interner::default();
+        let a = interner.intern("a");
+        assert!(Rc::ptr_eq(&interner.intern("a").name, &a.name));
+        assert_ne!(interner.intern("b"), a);
+        assert_eq!(a.as_str(), "a");
+        assert_eq!(format!("{} {:?}", a, a), "a \"a\"");
+
+        // 別の表から作った名前とも中身で等しい
+        assert_eq!(Interner::default().intern("a"), a);
+        assert_eq!(Symbol::from("a"), a);
+
+        assert!(Rc::ptr_eq(&interner.get("a").unwrap().name, &a.name));
+        assert_eq!(interner.get("c"), None);
+    }
+
+    /// ハッシュ値は名前から決まるので、別の表から作った名前でも引ける
+    #[test]
+    fn hash_across_interners() {
+        let mut map = HashMap::new();
+        map.insert(Interner::default().intern("name"), 1);
+        assert_eq!(map.get(&Interner::default().intern("name")), Some(&1));
+        assert_eq!(map.get(&Symbol::from("name")), Some(&1));
+        assert_eq!(map.get(&Symbol::from("other")), None);
+    }
+
+    #[test]
+    fn dropped_with_interner() {
+        let mut interner = Interner::default();
+        let weak = Rc::downgrade(&interner.intern("name").name);
+        assert!(weak.upgrade().is_some());
+        drop(interner);
+        assert!(weak.upgrade().is_none());
+    }
+}
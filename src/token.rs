@@ -1,16 +1,23 @@
-#[derive(Debug, PartialEq)]
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, OnceLock};
+
+use crate::symbol::Symbol;
+
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Special {
     Illegal,
     EOF,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Value {
     Ident,
     Int,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Operator {
     Assign,
     Plus,
@@ -24,13 +31,14 @@ pub enum Operator {
     Not,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Delimiter {
     Comma,
     SemiColon,
 }
 
-#[derive(Debug, PartialEq)]
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Paren {
     /// (
     LParen,
@@ -42,7 +50,7 @@ pub enum Paren {
     RBrace,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum KeyWord {
     Function,
     Let,
@@ -53,7 +61,7 @@ pub enum KeyWord {
     Return,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum TokenType {
     Special(Special),
     Value(Value),
@@ -63,25 +71,197 @@ pub enum TokenType {
     KeyWord(KeyWord),
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Token {
     pub token_type: TokenType,
     pub literal: String,
 }
 
-pub fn get_token_type(literal: &String) -> TokenType {
-    let num: Result<usize, _> = literal.parse();
-    match literal.as_str() {
-        "fn" => TokenType::KeyWord(KeyWord::Function),
-        "let" => TokenType::KeyWord(KeyWord::Let),
-        "true" => TokenType::KeyWord(KeyWord::True),
-        "false" => TokenType::KeyWord(KeyWord::False),
-        "if" => TokenType::KeyWord(KeyWord::If),
-        "else" => TokenType::KeyWord(KeyWord::Else),
-        "return" => TokenType::KeyWord(KeyWord::Return),
-        _ => match num {
-            Ok(_) => TokenType::Value(Value::Int),
-            Err(_) => TokenType::Value(Value::Ident),
-        },
+/// リテラルをソースから借用するトークン。`lexer::Lexer` が返す。
+#[derive(Debug, PartialEq, Clone)]
+pub struct TokenRef<'src> {
+    pub token_type: TokenType,
+    pub literal: &'src str,
+    /// 字句解析器に intern 表を渡したとき、識別子の名前
+    pub symbol: Option<Symbol>,
+}
+
+impl TokenRef<'_> {
+    pub fn to_token(&self) -> Token {
+        Token {
+            token_type: self.token_type,
+            literal: self.literal.to_string(),
+        }
+    }
+}
+
+impl Operator {
+    /// ソース上での表記
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Operator::Assign => "=",
+            Operator::Plus => "+",
+            Operator::Minus => "-",
+            Operator::Mul => "*",
+            Operator::Div => "/",
+            Operator::Lt => "<",
+            Operator::Gt => ">",
+            Operator::Eq => "==",
+            Operator::NotEq => "!=",
+            Operator::Not => "!",
+        }
+    }
+}
+
+impl fmt::Display for TokenType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            TokenType::Special(Special::Illegal) => "ILLEGAL",
+            TokenType::Special(Special::EOF) => "EOF",
+            TokenType::Value(Value::Ident) => "IDENT",
+            TokenType::Value(Value::Int) => "INT",
+            TokenType::Operator(Operator::Assign) => "ASSIGN",
+            TokenType::Operator(Operator::Plus) => "PLUS",
+            TokenType::Operator(Operator::Minus) => "MINUS",
+            TokenType::Operator(Operator::Mul) => "MUL",
+            TokenType::Operator(Operator::Div) => "DIV",
+            TokenType::Operator(Operator::Lt) => "LT",
+            TokenType::Operator(Operator::Gt) => "GT",
+            TokenType::Operator(Operator::Eq) => "EQ",
+            TokenType::Operator(Operator::NotEq) => "NOT_EQ",
+            TokenType::Operator(Operator::Not) => "NOT",
+            TokenType::Delimiter(Delimiter::Comma) => "COMMA",
+            TokenType::Delimiter(Delimiter::SemiColon) => "SEMICOLON",
+            TokenType::Paren(Paren::LParen) => "LPAREN",
+            TokenType::Paren(Paren::RParen) => "RPAREN",
+            TokenType::Paren(Paren::LBrace) => "LBRACE",
+            TokenType::Paren(Paren::RBrace) => "RBRACE",
+            TokenType::KeyWord(KeyWord::Function) => "FUNCTION",
+            TokenType::KeyWord(KeyWord::Let) => "LET",
+            TokenType::KeyWord(KeyWord::True) => "TRUE",
+            TokenType::KeyWord(KeyWord::False) => "FALSE",
+            TokenType::KeyWord(KeyWord::If) => "IF",
+            TokenType::KeyWord(KeyWord::Else) => "ELSE",
+            TokenType::KeyWord(KeyWord::Return) => "RETURN",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// `LET "let"` のように種類とリテラルを並べて表示する
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {:?}", self.token_type, self.literal)
+    }
+}
+
+impl fmt::Display for TokenRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {:?}", self.token_type, self.literal)
+    }
+}
+
+/// 既定の予約語で識別子・整数・予約語を見分ける
+pub fn get_token_type(literal: &str) -> TokenType {
+    Keywords::shared_default().token_type(literal)
+}
+
+/// 予約語の表。字句解析器を作るときに差し替えて、別名を足したり予約語を無効にしたりできる。
+///
+/// ```text
+/// let mut keywords = Keywords::default();
+/// keywords.disable(KeyWord::Return);
+/// let l = lexer::new(input).with_keywords(keywords);
+/// ```
+#[derive(Debug, PartialEq, Clone)]
+pub struct Keywords {
+    words: HashMap<String, KeyWord>,
+}
+
+impl Default for Keywords {
+    /// `fn` `let` `true` `false` `if` `else` `return`
+    fn default() -> Keywords {
+        let mut keywords = Keywords::empty();
+        keywords
+            .insert("fn", KeyWord::Function)
+            .insert("let", KeyWord::Let)
+            .insert("true", KeyWord::True)
+            .insert("false", KeyWord::False)
+            .insert("if", KeyWord::If)
+            .insert("else", KeyWord::Else)
+            .insert("return", KeyWord::Return);
+        keywords
+    }
+}
+
+impl Keywords {
+    pub fn empty() -> Keywords {
+        Keywords {
+            words: HashMap::new(),
+        }
+    }
+
+    /// 既定の表に日本語の別名を足したもの
+    pub fn japanese() -> Keywords {
+        let mut keywords = Keywords::default();
+        keywords
+            .insert("関数", KeyWord::Function)
+            .insert("定義", KeyWord::Let)
+            .insert("真", KeyWord::True)
+            .insert("偽", KeyWord::False)
+            .insert("もし", KeyWord::If)
+            .insert("でなければ", KeyWord::Else)
+            .insert("返す", KeyWord::Return);
+        keywords
+    }
+
+    /// 作るたびに確保しないよう、既定の表はひとつを共有する
+    pub(crate) fn shared_default() -> Arc<Keywords> {
+        static DEFAULT: OnceLock<Arc<Keywords>> = OnceLock::new();
+        DEFAULT
+            .get_or_init(|| Arc::new(Keywords::default()))
+            .clone()
+    }
+
+    /// `word` を `keyword` として読むようにする
+    pub fn insert(&mut self, word: &str, keyword: KeyWord) -> &mut Keywords {
+        self.words.insert(word.to_string(), keyword);
+        self
+    }
+
+    /// `word` を識別子として読むようにする
+    pub fn remove(&mut self, word: &str) -> &mut Keywords {
+        self.words.remove(word);
+        self
+    }
+
+    /// `keyword` のすべての表記を識別子として読むようにする
+    pub fn disable(&mut self, keyword: KeyWord) -> &mut Keywords {
+        self.words.retain(|_, k| *k != keyword);
+        self
+    }
+
+    pub fn get(&self, word: &str) -> Option<KeyWord> {
+        self.words.get(word).copied()
+    }
+
+    /// ASCII 以外の文字を含む表記。識別子には ASCII しか使えないので字句解析器が別に探す。
+    pub(crate) fn non_ascii(&self) -> impl Iterator<Item = (&str, KeyWord)> {
+        self.words
+            .iter()
+            .filter(|(word, _)| !word.is_ascii())
+            .map(|(word, keyword)| (word.as_str(), *keyword))
+    }
+
+    pub fn token_type(&self, literal: &str) -> TokenType {
+        if let Some(keyword) = self.get(literal) {
+            return TokenType::KeyWord(keyword);
+        }
+        // 桁が多すぎても整数。範囲の確認は構文解析器でする
+        if !literal.is_empty() && literal.bytes().all(|b| b.is_ascii_digit()) {
+            TokenType::Value(Value::Int)
+        } else {
+            TokenType::Value(Value::Ident)
+        }
     }
 }
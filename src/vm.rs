@@ -0,0 +1,489 @@
+//! コンパイルした命令を実行するスタック機械。
+//!
+//! 演算や呼び出しのエラーは評価器と同じ関数で作るので、同じプログラムなら
+//! 同じ値、同じ出力、同じ診断になる。
+
+use std::io::Write;
+use std::rc::Rc;
+
+use crate::code::{self, Capture, Constant, Fallback, Opcode};
+use crate::compiler::Bytecode;
+use crate::diagnostic::Diagnostic;
+use crate::evaluator::{arity, call_host, infix, not_callable, prefix, puts, undefined};
+use crate::gc::{self, Heap};
+use crate::limits::{Budget, Limits};
+use crate::object::{Builtin, Cell, Closure, Value};
+use crate::span::Span;
+use crate::symbol::Symbol;
+use crate::token::Operator;
+
+/// 大域変数を持ち、続けて実行したバイトコードの間で値を引き継ぐ。
+/// スロットは同じ `Compiler` でコンパイルしたものどうしでだけ一致する。
+pub struct Vm<W> {
+    globals: Vec<Option<Value>>,
+    out: W,
+    limits: Limits,
+    budget: Budget,
+    heap: Heap,
+}
+
+/// `puts` の出力先を `out` にした仮想機械
+pub fn new<W: Write>(out: W) -> Vm<W> {
+    Vm {
+        globals: Vec::new(),
+        out,
+        limits: Limits::default(),
+        budget: Budget::new(Limits::default()),
+        heap: Heap::default(),
+    }
+}
+
+/// 局所変数のスロット。内側の関数に閉じ込められるものは最初からセルにしておく。
+enum Slot {
+    Empty,
+    Value(Value),
+    Cell(Cell),
+}
+
+struct Frame {
+    closure: Rc<Closure>,
+    ip: usize,
+    /// 呼び出した時点のスタックの高さ。戻るときにここまで捨てる。
+    base: usize,
+    locals: Vec<Slot>,
+}
+
+/// ひとつの `run` の間の状態
+struct Machine<'a> {
+    frame: Frame,
+    frames: Vec<Frame>,
+    stack: Vec<Value>,
+    /// 実行中の命令の位置。エラーの位置を引くのに使う。
+    start: usize,
+    names: &'a [Symbol],
+}
+
+/// エラーの位置は `run` が実行中の命令から埋める
+const NOWHERE: Span = Span { start: 0, end: 0 };
+
+impl<W: Write> Vm<W> {
+    /// プログラムの値を返す。上限は呼び出しごとに数え直す
+    pub fn run(&mut self, bytecode: &Bytecode) -> Result<Value, Diagnostic> {
+        self.budget = Budget::new(self.limits);
+        if self.globals.len() < bytecode.globals.len() {
+            self.globals.resize(bytecode.globals.len(), None);
+        }
+        let closure = Rc::new(Closure {
+            function: bytecode.main.clone(),
+            free: Vec::new(),
+        });
+        let mut machine = Machine {
+            frame: Frame {
+                locals: new_locals(&mut self.heap, &closure, Vec::new()),
+                closure,
+                ip: 0,
+                base: 0,
+            },
+            frames: Vec::new(),
+            stack: Vec::new(),
+            start: 0,
+            names: &bytecode.globals,
+        };
+        self.execute(&mut machine).map_err(|mut diagnostic| {
+            diagnostic.span = machine.frame.closure.function.span_at(machine.start);
+            diagnostic
+        })
+    }
+
+    /// 次の `run` から使う上限
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    pub fn gc_stats(&self) -> gc::Stats {
+        self.heap.stats()
+    }
+
+    /// 循環して残っているクロージャとセルを今すぐ解放する
+    pub fn collect_garbage(&mut self) {
+        self.heap.collect();
+    }
+
+    /// クロージャやセルを確保するたびにごみ集めをする
+    pub fn set_gc_stress(&mut self, stress: bool) {
+        self.heap.set_stress(stress);
+    }
+
+    pub fn output(&self) -> &W {
+        &self.out
+    }
+
+    pub fn into_output(self) -> W {
+        self.out
+    }
+
+    /// 値がなければ組み込み関数を探す
+    fn global(&self, m: &Machine, slot: usize) -> Result<Value, Diagnostic> {
+        match &self.globals[slot] {
+            Some(value) => Ok(value.clone()),
+            None => {
+                let name = &m.names[slot];
+                match Builtin::lookup(name.as_str()) {
+                    Some(builtin) => Ok(Value::Builtin(builtin)),
+                    None => Err(undefined(name, NOWHERE)),
+                }
+            }
+        }
+    }
+
+    /// 閉じ込めた変数に値がなければ、外側のスコープで同じ名前が指す変数を順にたどる
+    fn free(&self, m: &Machine, mut i: usize) -> Result<Value, Diagnostic> {
+        let closure = &m.frame.closure;
+        loop {
+            if let Some(value) = closure.free[i].borrow().clone() {
+                return Ok(value);
+            }
+            match closure.function.free_fallback(i) {
+                Some(Fallback::Free(next)) => i = next,
+                Some(Fallback::Global(slot)) => return self.global(m, slot),
+                None => return Err(undefined(&closure.function.free[i].0, NOWHERE)),
+            }
+        }
+    }
+
+    fn fallback(&self, m: &Machine, fallback: Fallback) -> Result<Value, Diagnostic> {
+        match fallback {
+            Fallback::Free(i) => self.free(m, i),
+            Fallback::Global(slot) => self.global(m, slot),
+        }
+    }
+
+    fn execute(&mut self, m: &mut Machine) -> Result<Value, Diagnostic> {
+        loop {
+            let ip = m.frame.ip;
+            m.start = ip;
+            let instructions = &m.frame.closure.function.instructions;
+            let op = Opcode::from_byte(instructions[ip]).expect("invalid opcode");
+            let mut operand = 0;
+            let mut next = ip + 1;
+            if let Some(&width) = op.operand_widths().first() {
+                operand = code::read_operand(instructions, next, width);
+                next += width;
+            }
+            m.frame.ip = next;
+            self.budget.step(NOWHERE)?;
+
+            match op {
+                Opcode::Constant => {
+                    let value = match &m.frame.closure.function.constants[operand] {
+                        Constant::Integer(value) => Value::Integer(*value),
+                        Constant::Function(_) => unreachable!("functions are loaded by Closure"),
+                    };
+                    m.stack.push(value);
+                }
+                Opcode::Null => m.stack.push(Value::Null),
+                Opcode::True => m.stack.push(Value::Boolean(true)),
+                Opcode::False => m.stack.push(Value::Boolean(false)),
+                Opcode::Pop => {
+                    m.pop();
+                }
+                Opcode::Add
+                | Opcode::Sub
+                | Opcode::Mul
+                | Opcode::Div
+                | Opcode::Equal
+                | Opcode::NotEqual
+                | Opcode::LessThan
+                | Opcode::GreaterThan => {
+                    let operator = match op {
+                        Opcode::Add => Operator::Plus,
+                        Opcode::Sub => Operator::Minus,
+                        Opcode::Mul => Operator::Mul,
+                        Opcode::Div => Operator::Div,
+                        Opcode::Equal => Operator::Eq,
+                        Opcode::NotEqual => Operator::NotEq,
+                        Opcode::LessThan => Operator::Lt,
+                        _ => Operator::Gt,
+                    };
+                    let right = m.pop();
+                    let left = m.pop();
+                    m.stack.push(infix(operator, left, right, NOWHERE)?);
+                }
+                Opcode::Minus | Opcode::Bang => {
+                    let operator = match op {
+                        Opcode::Minus => Operator::Minus,
+                        _ => Operator::Not,
+                    };
+                    let right = m.pop();
+                    m.stack.push(prefix(operator, right, NOWHERE)?);
+                }
+                Opcode::Jump => m.frame.ip = operand,
+                Opcode::JumpNotTruthy => {
+                    if !m.pop().is_truthy() {
+                        m.frame.ip = operand;
+                    }
+                }
+                Opcode::GetGlobal => {
+                    let value = self.global(m, operand)?;
+                    m.stack.push(value);
+                }
+                Opcode::SetGlobal => self.globals[operand] = Some(m.pop()),
+                Opcode::GetLocal => {
+                    let value = match &m.frame.locals[operand] {
+                        Slot::Empty => None,
+                        Slot::Value(value) => Some(value.clone()),
+                        Slot::Cell(cell) => cell.borrow().clone(),
+                    };
+                    let value = match value {
+                        Some(value) => value,
+                        None => match m.frame.closure.function.local_fallback(operand) {
+                            Some(fallback) => self.fallback(m, fallback)?,
+                            None => {
+                                let name = &m.frame.closure.function.locals[operand];
+                                return Err(undefined(name, NOWHERE));
+                            }
+                        },
+                    };
+                    m.stack.push(value);
+                }
+                Opcode::SetLocal => {
+                    let value = m.pop();
+                    match &mut m.frame.locals[operand] {
+                        Slot::Cell(cell) => *cell.borrow_mut() = Some(value),
+                        slot => *slot = Slot::Value(value),
+                    }
+                }
+                Opcode::GetFree => {
+                    let value = self.free(m, operand)?;
+                    m.stack.push(value);
+                }
+                Opcode::Closure => {
+                    let Constant::Function(function) = &m.frame.closure.function.constants[operand]
+                    else {
+                        unreachable!("Closure takes a function constant");
+                    };
+                    self.budget.allocate(&mut self.heap, NOWHERE)?;
+                    let free = function
+                        .free
+                        .iter()
+                        .map(|(_, capture)| match capture {
+                            Capture::Local(slot) => match &m.frame.locals[*slot] {
+                                Slot::Cell(cell) => cell.clone(),
+                                _ => unreachable!("captured locals are cells"),
+                            },
+                            Capture::Free(i) => m.frame.closure.free[*i].clone(),
+                        })
+                        .collect();
+                    let closure = self.heap.closure(Closure {
+                        function: function.clone(),
+                        free,
+                    });
+                    m.stack.push(Value::Closure(closure));
+                }
+                Opcode::Call => self.call(m, operand, false)?,
+                Opcode::TailCall => self.call(m, operand, true)?,
+                Opcode::ReturnValue => {
+                    let value = m.pop();
+                    match m.frames.pop() {
+                        Some(caller) => {
+                            m.stack.truncate(m.frame.base);
+                            m.frame = caller;
+                            self.budget.leave();
+                            m.stack.push(value);
+                        }
+                        None => return Ok(value),
+                    }
+                }
+            }
+        }
+    }
+
+    /// スタックには関数、引数の順に積んである。
+    /// `tail` のときは今のフレームを置き換えるので、呼び出しの深さは増えない
+    fn call(&mut self, m: &mut Machine, count: usize, tail: bool) -> Result<(), Diagnostic> {
+        let arguments = m.stack.split_off(m.stack.len() - count);
+        let function = m.pop();
+        match function {
+            Value::Closure(closure) => {
+                if count != closure.function.parameters.len() {
+                    return Err(arity(closure.function.parameters.len(), count, NOWHERE));
+                }
+                self.budget.allocate(&mut self.heap, NOWHERE)?;
+                let locals = new_locals(&mut self.heap, &closure, arguments);
+                if tail {
+                    m.stack.truncate(m.frame.base);
+                    m.frame = Frame {
+                        locals,
+                        closure,
+                        ip: 0,
+                        base: m.frame.base,
+                    };
+                } else {
+                    self.budget.enter(NOWHERE)?;
+                    let frame = Frame {
+                        locals,
+                        closure,
+                        ip: 0,
+                        base: m.stack.len(),
+                    };
+                    m.frames.push(std::mem::replace(&mut m.frame, frame));
+                }
+            }
+            Value::Builtin(Builtin::Puts) => {
+                let value = puts(&mut self.out, arguments, NOWHERE)?;
+                m.stack.push(value);
+            }
+            Value::Host(host) => {
+                let value = call_host(&host, arguments, NOWHERE)?;
+                m.stack.push(value);
+            }
+            value => return Err(not_callable(&value, NOWHERE)),
+        }
+        Ok(())
+    }
+}
+
+impl Machine<'_> {
+    fn pop(&mut self) -> Value {
+        self.stack.pop().expect("stack underflow")
+    }
+}
+
+/// 仮引数に引数を入れ、閉じ込められるスロットをセルにする
+fn new_locals(heap: &mut Heap, closure: &Closure, arguments: Vec<Value>) -> Vec<Slot> {
+    let function = &closure.function;
+    let mut locals: Vec<Slot> = arguments.into_iter().map(Slot::Value).collect();
+    locals.resize_with(function.locals.len(), || Slot::Empty);
+    for &slot in &function.cells {
+        let value = match std::mem::replace(&mut locals[slot], Slot::Empty) {
+            Slot::Value(value) => Some(value),
+            _ => None,
+        };
+        locals[slot] = Slot::Cell(heap.cell(value));
+    }
+    locals
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::object::Value;
+    use crate::{compiler, evaluator, parser};
+
+    /// 仮想機械と評価器で実行して、値と出力が同じことを確かめる
+    fn run(input: &str) -> Result<Value, &'static str> {
+        let (program, errors) = parser::parse(input);
+        assert_eq!(errors, vec![]);
+        let bytecode = compiler::new().compile(&program).unwrap();
+        let mut vm = super::new(vec![]);
+        let value = vm.run(&bytecode);
+        let mut e = evaluator::new(vec![]);
+        assert_eq!(value, e.eval_program(&program), "{}", input);
+        assert_eq!(vm.output(), e.output(), "{}", input);
+        value.map_err(|d| d.code)
+    }
+
+    #[test]
+    fn values() {
+        let cases = [
+            ("1 + 2 * 3 - 4 / 2", Value::Integer(5)),
+            ("-5 < 3 == !false", Value::Boolean(true)),
+            ("if (0) { 1 }", Value::Integer(1)),
+            ("if (1 > 2) { 1 }", Value::Null),
+            ("let x = 1; x", Value::Integer(1)),
+            ("return 2; 3", Value::Integer(2)),
+            ("if (true) { if (true) { return 1; } 2 }", Value::Integer(1)),
+            (
+                "let f = fn() { 1 + if (true) { return 2; } }; f()",
+                Value::Integer(2),
+            ),
+            (
+                "let add = fn(a) { fn(b) { a + b } }; add(2)(3)",
+                Value::Integer(5),
+            ),
+            (
+                "let f = fn(n) { if (n < 2) { return n; } f(n - 1) + f(n - 2) }; f(10)",
+                Value::Integer(55),
+            ),
+            // 閉じ込めた後の代入も見える
+            (
+                "let f = fn() { let x = 1; let g = fn() { x }; let x = 2; g() }; f()",
+                Value::Integer(2),
+            ),
+            (
+                "let f = fn() { let g = fn(n) { if (n == 0) { 0 } else { g(n - 1) } }; g(3) }; f()",
+                Value::Integer(0),
+            ),
+            (
+                "let f = fn() { g() }; let g = fn() { 3 }; f()",
+                Value::Integer(3),
+            ),
+            ("let f = fn(a, a) { a }; f(1, 2)", Value::Integer(2)),
+            (
+                "puts(1, true); puts",
+                Value::Builtin(crate::object::Builtin::Puts),
+            ),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(run(input), Ok(expected), "{}", input);
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let cases = [
+            ("x", evaluator::E_UNDEFINED),
+            (
+                "let f = fn() { let g = fn() { y }; g() }; f()",
+                evaluator::E_UNDEFINED,
+            ),
+            ("1 + true", evaluator::E_TYPE_MISMATCH),
+            ("-true", evaluator::E_TYPE_MISMATCH),
+            ("1(2)", evaluator::E_NOT_CALLABLE),
+            ("fn(a) { a }()", evaluator::E_ARITY),
+            ("1 / 0", evaluator::E_DIVISION_BY_ZERO),
+            ("9223372036854775807 + 1", evaluator::E_OVERFLOW),
+            ("let f = fn() { 1 + f() }; f()", crate::limits::E_CALL_DEPTH),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(run(input), Err(expected), "{}", input);
+        }
+    }
+
+    #[test]
+    fn tail_calls() {
+        let cases = [
+            (
+                "let loop = fn(n) { if (n == 0) { return 0; } loop(n - 1) }; loop(100000)",
+                Ok(Value::Integer(0)),
+            ),
+            (
+                "let even = fn(n) { if (n == 0) { true } else { odd(n - 1) } };
+                 let odd = fn(n) { if (n == 0) { false } else { even(n - 1) } };
+                 even(10001)",
+                Ok(Value::Boolean(false)),
+            ),
+            (
+                "let sum = fn(n, acc) { if (n == 0) { return acc; } return sum(n - 1, acc + n); }; sum(10000, 0)",
+                Ok(Value::Integer(50005000)),
+            ),
+            (
+                "let f = fn(n) { let g = fn() { n }; if (n == 0) { g } else { f(n - 1) } }; f(500)()",
+                Ok(Value::Integer(0)),
+            ),
+            ("let f = fn(n) { if (n == 0) { puts(n) } else { f(n - 1) } }; f(1000)", Ok(Value::Null)),
+            ("let f = fn(n) { g(n, n) }; let g = fn(n) { n }; f(1)", Err(evaluator::E_ARITY)),
+            // 末尾にない `if` や式の中の `return` も末尾呼び出しになる
+            (
+                "let loop = fn(n) { if (n > 0) { return loop(n - 1); } 0 }; loop(100000)",
+                Ok(Value::Integer(0)),
+            ),
+            (
+                "let loop = fn(n) { 1 + if (n > 0) { return loop(n - 1); } else { -1 } }; loop(100000)",
+                Ok(Value::Integer(0)),
+            ),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(run(input), expected, "{}", input);
+        }
+    }
+}
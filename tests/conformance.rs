@@ -0,0 +1,183 @@
+//! `tests/programs/*.mk` を字句解析・構文解析・評価し、結果を同じ名前の期待ファイルと比べる。
+//!
+//! | 拡張子 | 内容 |
+//! | --- | --- |
+//! | `.tokens` | トークン列の表 |
+//! | `.ast` | 括弧付きで印字した構文木 |
+//! | `.stdout` | `puts` の出力 |
+//! | `.value` | プログラムの値 |
+//! | `.diagnostics` | 字句・構文・実行時のエラー |
+//!
+//! 中身が空になるものは期待ファイルを置かない。
+//! 評価器と、コンパイルして仮想機械で実行したものの両方が、最適化してもしなくても、
+//! 確保のたびにごみ集めをしても期待ファイルに一致する必要がある。
+//! 確保のたびにごみ集めをすると深い再帰は遅いので、呼び出しの深さは既定より低い `MAX_CALL_DEPTH` で打ち切る。
+//! `cargo test --test conformance -- --bless` で実際の結果を期待ファイルに書き出す。
+
+use std::path::{Path, PathBuf};
+use std::{env, fs, io, process};
+
+use learn_interpreter::limits::Limits;
+use learn_interpreter::{
+    compiler, diagnostic, dump, evaluator, lexer, optimizer, parser, printer, vm,
+};
+
+const MAX_CALL_DEPTH: usize = 200;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Engine {
+    Evaluator,
+    Vm,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+struct Mode {
+    optimize: bool,
+    gc_stress: bool,
+}
+
+/// 最初のものの結果で期待ファイルを書き換える
+const MODES: [Mode; 3] = [
+    Mode {
+        optimize: false,
+        gc_stress: false,
+    },
+    Mode {
+        optimize: true,
+        gc_stress: false,
+    },
+    Mode {
+        optimize: false,
+        gc_stress: true,
+    },
+];
+
+/// 各段階の結果。拡張子と中身の組。
+fn run(path: &Path, engine: Engine, mode: Mode) -> Vec<(&'static str, String)> {
+    let input = fs::read_to_string(path).unwrap();
+    let name = path.file_name().unwrap().to_string_lossy();
+
+    let (tokens, _) = lexer::tokenize(&input);
+    let (program, mut diagnostics) = parser::parse(&input);
+    let mut stdout = vec![];
+    let mut value = String::new();
+    if !diagnostic::has_errors(&diagnostics) {
+        // 構文木の期待ファイルは最適化する前のもの
+        let program = if mode.optimize {
+            optimizer::optimize(program.clone())
+        } else {
+            program.clone()
+        };
+        let limits = Limits {
+            max_call_depth: Some(MAX_CALL_DEPTH),
+            ..Limits::default()
+        };
+        let result = match engine {
+            Engine::Evaluator => {
+                let mut evaluator = evaluator::new(&mut stdout);
+                evaluator.set_gc_stress(mode.gc_stress);
+                evaluator.set_limits(limits);
+                evaluator.eval_program(&program)
+            }
+            Engine::Vm => compiler::new().compile(&program).and_then(|bytecode| {
+                let mut vm = vm::new(&mut stdout);
+                vm.set_gc_stress(mode.gc_stress);
+                vm.set_limits(limits);
+                vm.run(&bytecode)
+            }),
+        };
+        match result {
+            Ok(v) => value = format!("{}\n", v),
+            Err(error) => diagnostics.push(error),
+        }
+    }
+    vec![
+        ("tokens", dump::tokens_table(&input, &tokens)),
+        (
+            "ast",
+            printer::print_program(&program, printer::Mode::Parenthesized),
+        ),
+        ("stdout", String::from_utf8(stdout).unwrap()),
+        ("value", value),
+        (
+            "diagnostics",
+            diagnostic::render_all(&diagnostics, &input, &name),
+        ),
+    ]
+}
+
+/// 期待ファイルと異なった段階の説明を返す。
+/// `bless` のときは最適化しない評価器の結果で期待ファイルを書き換える。
+fn check(path: &Path, engine: Engine, mode: Mode, bless: bool) -> Vec<String> {
+    let bless = bless && engine == Engine::Evaluator && mode == MODES[0];
+    let mut failures = vec![];
+    for (extension, actual) in run(path, engine, mode) {
+        let golden = path.with_extension(extension);
+        let expected = match fs::read_to_string(&golden) {
+            Ok(expected) => expected,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => panic!("{}: {}", golden.display(), e),
+        };
+        if expected == actual {
+            continue;
+        }
+        if bless {
+            if actual.is_empty() {
+                fs::remove_file(&golden).unwrap();
+            } else {
+                fs::write(&golden, &actual).unwrap();
+            }
+        } else {
+            failures.push(format!(
+                "{} differs ({:?}{}{})\n--- expected\n{}--- actual\n{}",
+                golden.display(),
+                engine,
+                if mode.optimize { ", optimized" } else { "" },
+                if mode.gc_stress { ", gc stress" } else { "" },
+                expected,
+                actual
+            ));
+        }
+    }
+    failures
+}
+
+fn programs() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|e| e == "mk"))
+        .collect();
+    paths.sort();
+    paths
+}
+
+/// `cargo test` から渡されるほかの引数は無視する
+fn main() {
+    let bless = env::args().any(|arg| arg == "--bless");
+    let paths = programs();
+    assert!(!paths.is_empty());
+    let mut failed = 0;
+    for path in &paths {
+        let mut failures = vec![];
+        for mode in MODES {
+            for engine in [Engine::Evaluator, Engine::Vm] {
+                failures.extend(check(path, engine, mode, bless));
+            }
+        }
+        if !failures.is_empty() {
+            failed += 1;
+            eprintln!("{}", failures.join("\n"));
+        }
+    }
+    println!(
+        "conformance: {} passed; {} failed",
+        paths.len() - failed,
+        failed
+    );
+    if failed > 0 {
+        eprintln!("run `cargo test --test conformance -- --bless` to update the expected files");
+        process::exit(1);
+    }
+}
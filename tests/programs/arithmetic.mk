@@ -0,0 +1,5 @@
+// 優先順位と単項演算子
+let a = 1 + 2 * 3;
+let b = (1 + 2) * 3;
+puts(a, b, -a + b, 10 / 3, 7 - -2);
+a < b == !false
@@ -0,0 +1,3 @@
+let pair = fn(a, b) { a };
+puts(pair(1, 2));
+pair(1)
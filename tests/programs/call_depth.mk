@@ -0,0 +1,4 @@
+// 止まらない再帰は呼び出しの深さの上限で打ち切る。末尾呼び出しは深さに数えないので、
+// 呼び出しの後に計算を残す
+let down = fn(n) { 1 + down(n + 1) };
+down(0)
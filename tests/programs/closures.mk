@@ -0,0 +1,7 @@
+let adder = fn(n) {
+    fn(x) { x + n }
+};
+let add2 = adder(2);
+let twice = fn(f, x) { f(f(x)) };
+puts(add2(40), twice(add2, 1));
+twice(adder(10), 0)
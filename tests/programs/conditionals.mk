@@ -0,0 +1,11 @@
+// 0 も真になる
+puts(if (0) { 1 } else { 2 });
+puts(if (false) { 1 });
+let sign = fn(x) {
+    if (x < 0) { return -1; }
+    if (x == 0) { return 0; }
+    1
+};
+puts(sign(-5), sign(0), sign(5));
+return sign(0) == 0;
+puts(999);
@@ -0,0 +1,21 @@
+// 最適化してもしなくても同じ結果になる
+let x = 2 * 3 + 4;
+puts(x);
+if (true) {
+    let y = x * 2;
+} else {
+    puts(0);
+}
+puts(y);
+let f = fn(n) {
+    if (1 > 2) {
+        return 0;
+    }
+    return n + 1;
+    puts(n);
+};
+puts(f(y));
+if (false) { puts(1) }
+let big = 9223372036854775807;
+puts(-big - 1);
+x / (5 - 5);
@@ -0,0 +1,14 @@
+let fib = fn(n) {
+    if (n < 2) {
+        return n;
+    }
+    fib(n - 1) + fib(n - 2)
+};
+let count = fn(n) {
+    if (n > 0) {
+        puts(n);
+        count(n - 1);
+    }
+};
+count(3);
+fib(15)
@@ -0,0 +1,3 @@
+let divide = fn(a, b) { a / b };
+puts(divide(10, 2));
+divide(1, 0)
@@ -0,0 +1,6 @@
+let x = 1;
+let f = fn(x) { x * 10 };
+let g = fn() { x };
+let x = 2;
+puts(f(3), g(), x);
+x
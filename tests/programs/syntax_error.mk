@@ -0,0 +1,3 @@
+let = 5;
+let x = 5abc;
+puts(1)
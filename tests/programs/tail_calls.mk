@@ -0,0 +1,23 @@
+// 末尾呼び出しは呼び出しの深さの上限を超えても続けられる
+let loop = fn(n) {
+    if (n == 0) {
+        return 0;
+    }
+    loop(n - 1)
+};
+puts(loop(10000));
+let sum = fn(n, acc) {
+    if (n == 0) { acc } else { sum(n - 1, acc + n) }
+};
+puts(sum(10000, 0));
+// 末尾にない `if` の中の `return` も末尾呼び出し
+let countdown = fn(n) {
+    if (n > 0) {
+        return countdown(n - 1);
+    }
+    n
+};
+puts(countdown(10000));
+let even = fn(n) { if (n == 0) { true } else { odd(n - 1) } };
+let odd = fn(n) { if (n == 0) { false } else { even(n - 1) } };
+even(5001)
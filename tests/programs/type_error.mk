@@ -0,0 +1,4 @@
+let x = 5;
+puts(x);
+let y = x + true;
+puts(y);
@@ -0,0 +1,14 @@
+// まだ実行していない `let` の変数を読むと外側の束縛が見える
+let x = 1;
+let f = fn() { let y = x; let x = 2; y };
+puts(f());
+
+let z = 1;
+let g = fn() { if (false) { let z = 2; } z };
+puts(g());
+
+// 閉じ込めた変数を通しても同じ
+let h = fn() { let w = fn() { z }; let z = 3; w() };
+puts(h());
+let k = fn() { let w = fn() { z }; if (false) { let z = 4; } w() };
+k()
@@ -0,0 +1,2 @@
+let f = fn(a) { a + b };
+f(1)